@@ -0,0 +1,363 @@
+use std::error::Error;
+use std::time::Instant;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AuthConfig, Pins};
+use crate::User;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Operator,
+    Manager,
+    Admin,
+}
+
+impl Role {
+    fn parse(role: &str) -> Option<Self> {
+        match role {
+            "Operator" => Some(Role::Operator),
+            "Manager" => Some(Role::Manager),
+            "Admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl From<Role> for User {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Operator => User::Operator,
+            Role::Manager => User::Manager,
+            Role::Admin => User::Admin,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Account {
+    pub name: String,
+    pub role: Role,
+}
+
+#[derive(Debug)]
+pub enum AuthEvent {
+    LogIn,
+    LogOut,
+    Failed,
+    LockedOut,
+    Created,
+    Updated,
+    Removed,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum AuthError {
+    InvalidPin,
+    LockedOut(u64),
+    //Deliberately vague so it doesn't say whether someone else has the PIN
+    PinNotAllowed,
+    NameTaken,
+    NoSuchUser,
+    //The kiosk always keeps one Admin
+    LastAdmin,
+    Database(String),
+}
+
+impl From<rusqlite::Error> for AuthError {
+    fn from(e: rusqlite::Error) -> Self {
+        AuthError::Database(e.to_string())
+    }
+}
+
+// PINs are unique per account so a PIN alone identifies who is logging in. Failed attempts
+// can't be tied to an account, so lockout applies to the whole kiosk.
+pub struct Accounts {
+    database: Connection,
+    config: AuthConfig,
+    failed_attempts: usize,
+    locked_until: Option<Instant>,
+}
+
+impl Accounts {
+    pub fn new(database: Connection, config: AuthConfig) -> Self {
+        Self {
+            database,
+            config,
+            failed_attempts: 0,
+            locked_until: None,
+        }
+    }
+
+    pub fn config(&self) -> &AuthConfig {
+        &self.config
+    }
+
+    pub fn connect(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.database.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+            name TEXT PRIMARY KEY,
+            pin_hash TEXT NOT NULL,
+            role TEXT NOT NULL
+        )",
+            [],
+        )?;
+        self.database.execute(
+            "CREATE TABLE IF NOT EXISTS auth_logs (
+            id INTEGER PRIMARY KEY,
+            timestamp TEXT NOT NULL,
+            user TEXT,
+            event TEXT NOT NULL
+        )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    //Seeds one account per role from the plaintext pins in the controls config on first run
+    pub fn migrate_pins(&self, pins: &Pins) -> Result<(), AuthError> {
+        if !self.list()?.is_empty() {
+            return Ok(());
+        }
+        log::info!("No user accounts found, creating them from config pins");
+        self.add("sudo", &pins.sudo.to_string(), Role::Admin)?;
+        self.add("manager", &pins.manager.to_string(), Role::Manager)?;
+        self.add("operator", &pins.operator.to_string(), Role::Operator)?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<Account>, AuthError> {
+        let mut statement = self
+            .database
+            .prepare("SELECT name, role FROM users ORDER BY name")?;
+        let accounts = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .filter_map(|row| row.ok())
+            .filter_map(|(name, role)| Role::parse(&role).map(|role| Account { name, role }))
+            .collect();
+        Ok(accounts)
+    }
+
+    //Copies the hashes out so PINs can be checked without holding the app lock
+    pub fn pin_records(&self) -> Result<PinRecords, AuthError> {
+        let mut statement = self
+            .database
+            .prepare("SELECT name, pin_hash, role FROM users")?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .filter_map(|row| row.ok())
+            .collect();
+        Ok(PinRecords(rows))
+    }
+
+    pub fn role_of(&self, name: &str) -> Result<Option<Role>, AuthError> {
+        let mut statement = self
+            .database
+            .prepare("SELECT role FROM users WHERE name = ?1")?;
+        let role = statement
+            .query_map(params![name], |row| row.get::<_, String>(0))?
+            .filter_map(|row| row.ok())
+            .find_map(|role| Role::parse(&role));
+        Ok(role)
+    }
+
+    pub fn add(&self, name: &str, pin: &str, role: Role) -> Result<(), AuthError> {
+        let pin_hash = self.pin_records()?.new_pin_hash(pin, None)?;
+        self.insert(name, &pin_hash, role)
+    }
+
+    //Takes a hash from PinRecords::new_pin_hash, never replaces an existing account
+    pub fn insert(&self, name: &str, pin_hash: &str, role: Role) -> Result<(), AuthError> {
+        if self.role_of(name)?.is_some() {
+            return Err(AuthError::NameTaken);
+        }
+        self.database.execute(
+            "INSERT INTO users (name, pin_hash, role) VALUES (?1, ?2, ?3)",
+            params![name, pin_hash, format!("{:?}", role)],
+        )?;
+        self.audit(Some(name), &AuthEvent::Created);
+        Ok(())
+    }
+
+    //Changes the role and, given a new hash, the PIN of an existing account
+    pub fn update(&self, name: &str, pin_hash: Option<&str>, role: Role) -> Result<(), AuthError> {
+        if role != Role::Admin && self.is_last_admin(name)? {
+            return Err(AuthError::LastAdmin);
+        }
+        let changed = match pin_hash {
+            Some(pin_hash) => self.database.execute(
+                "UPDATE users SET pin_hash = ?2, role = ?3 WHERE name = ?1",
+                params![name, pin_hash, format!("{:?}", role)],
+            )?,
+            None => self.database.execute(
+                "UPDATE users SET role = ?2 WHERE name = ?1",
+                params![name, format!("{:?}", role)],
+            )?,
+        };
+        if changed == 0 {
+            return Err(AuthError::NoSuchUser);
+        }
+        self.audit(Some(name), &AuthEvent::Updated);
+        Ok(())
+    }
+
+    fn is_last_admin(&self, name: &str) -> Result<bool, AuthError> {
+        let admins: Vec<Account> = self
+            .list()?
+            .into_iter()
+            .filter(|account| account.role == Role::Admin)
+            .collect();
+        Ok(admins.len() == 1 && admins[0].name == name)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), AuthError> {
+        if self.is_last_admin(name)? {
+            return Err(AuthError::LastAdmin);
+        }
+        self.database
+            .execute("DELETE FROM users WHERE name = ?1", params![name])?;
+        self.audit(Some(name), &AuthEvent::Removed);
+        Ok(())
+    }
+
+    pub fn authenticate(&mut self, pin: &str) -> Result<Account, AuthError> {
+        self.check_lockout()?;
+        let found = self.pin_records()?.find(pin);
+        self.record_attempt(found)
+    }
+
+    pub fn check_lockout(&mut self) -> Result<(), AuthError> {
+        if let Some(locked_until) = self.locked_until {
+            let now = Instant::now();
+            if now < locked_until {
+                return Err(AuthError::LockedOut((locked_until - now).as_secs() + 1));
+            }
+            self.locked_until = None;
+            self.failed_attempts = 0;
+        }
+        Ok(())
+    }
+
+    //Counts a log in attempt checked against PinRecords::find
+    pub fn record_attempt(&mut self, found: Option<Account>) -> Result<Account, AuthError> {
+        match found {
+            Some(account) => {
+                self.failed_attempts = 0;
+                self.audit(Some(&account.name), &AuthEvent::LogIn);
+                Ok(account)
+            }
+            None => {
+                self.failed_attempts += 1;
+                self.audit(None, &AuthEvent::Failed);
+                if self.failed_attempts >= self.config.max_failed_attempts {
+                    log::warn!("Too many failed log in attempts, locking out");
+                    self.locked_until = Some(Instant::now() + self.config.lockout);
+                    self.audit(None, &AuthEvent::LockedOut);
+                    return Err(AuthError::LockedOut(self.config.lockout.as_secs()));
+                }
+                Err(AuthError::InvalidPin)
+            }
+        }
+    }
+
+    pub fn audit(&self, name: Option<&str>, event: &AuthEvent) {
+        let curr_time = chrono::Utc::now().to_string();
+        let event = format!("{:?}", event);
+        if let Err(e) = self.database.execute(
+            "INSERT INTO auth_logs (timestamp, user, event) VALUES (?1, ?2, ?3)",
+            params![curr_time, name, event],
+        ) {
+            log::error!("Failed to write auth log: {}", e);
+        }
+    }
+}
+
+// Name, PIN hash and role of every account. Argon2 is slow on purpose, so this is checked
+// with the app lock released.
+pub struct PinRecords(Vec<(String, String, String)>);
+
+impl PinRecords {
+    pub fn find(&self, pin: &str) -> Option<Account> {
+        let argon2 = Argon2::default();
+        for (name, pin_hash, role) in &self.0 {
+            let Ok(hash) = PasswordHash::new(pin_hash) else {
+                log::warn!("Unreadable pin hash for {}", name);
+                continue;
+            };
+            if argon2.verify_password(pin.as_bytes(), &hash).is_ok() {
+                return Role::parse(role).map(|role| Account {
+                    name: name.clone(),
+                    role,
+                });
+            }
+        }
+        None
+    }
+
+    //Hashes a PIN for owner, refused if any other account already uses it
+    pub fn new_pin_hash(&self, pin: &str, owner: Option<&str>) -> Result<String, AuthError> {
+        if self
+            .find(pin)
+            .is_some_and(|account| Some(account.name.as_str()) != owner)
+        {
+            return Err(AuthError::PinNotAllowed);
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let pin_hash = Argon2::default()
+            .hash_password(pin.as_bytes(), &salt)
+            .map_err(|e| AuthError::Database(e.to_string()))?
+            .to_string();
+        Ok(pin_hash)
+    }
+}
+
+#[test]
+fn test_accounts_lockout() {
+    let accounts_config = AuthConfig {
+        max_failed_attempts: 2,
+        lockout: std::time::Duration::from_secs(60),
+        ..Default::default()
+    };
+    let mut accounts = Accounts::new(Connection::open_in_memory().unwrap(), accounts_config);
+    accounts.connect().unwrap();
+    accounts.add("jo", "1234", Role::Manager).unwrap();
+    assert_eq!(
+        accounts.add("sam", "1234", Role::Operator),
+        Err(AuthError::PinNotAllowed)
+    );
+    assert_eq!(
+        accounts.add("jo", "5678", Role::Admin),
+        Err(AuthError::NameTaken)
+    );
+    assert_eq!(accounts.role_of("jo").unwrap(), Some(Role::Manager));
+    accounts.add("ash", "9999", Role::Admin).unwrap();
+    assert_eq!(accounts.remove("ash"), Err(AuthError::LastAdmin));
+    assert_eq!(
+        accounts.update("ash", None, Role::Manager),
+        Err(AuthError::LastAdmin)
+    );
+
+    let account = accounts.authenticate("1234").unwrap();
+    assert_eq!(account.name, "jo");
+    assert_eq!(account.role, Role::Manager);
+
+    assert_eq!(
+        accounts.authenticate("0000").unwrap_err(),
+        AuthError::InvalidPin
+    );
+    assert!(matches!(
+        accounts.authenticate("0000"),
+        Err(AuthError::LockedOut(_))
+    ));
+    // Even the right pin is refused while locked out
+    assert!(matches!(
+        accounts.authenticate("1234"),
+        Err(AuthError::LockedOut(_))
+    ));
+}
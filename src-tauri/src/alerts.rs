@@ -0,0 +1,305 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveTime;
+use control_components::components::clear_core_io::HBridge;
+use control_components::controllers::clear_core::Controller;
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::accounts::Role;
+use crate::events::EventSink;
+use crate::config::{toml_item, AlertPattern, AlertsConfig, BuzzerConfig, Config, QuietHours};
+use crate::session::CommandError;
+use crate::state::AppData;
+
+pub const ALERT_EVENT: &str = "alert";
+const ALERT_PERIOD: Duration = Duration::from_millis(250);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AlertKind {
+    RunOut,
+    HatchFault,
+    ScaleFault,
+    BowlLeft,
+    StaleHold,
+    SanitationOverdue,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub acknowledged: bool,
+    #[serde(skip)]
+    last_sounded: Option<Instant>,
+}
+
+//Sent to the kiosk each time an alert sounds
+#[derive(Serialize, Clone, Debug)]
+pub struct AlertSound {
+    pub kind: AlertKind,
+    pub sound: Option<String>,
+}
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").ok()
+}
+
+pub fn is_quiet(quiet_hours: &QuietHours, time: NaiveTime) -> bool {
+    let (Some(start), Some(end)) = (parse_time(&quiet_hours.start), parse_time(&quiet_hours.end))
+    else {
+        return false;
+    };
+    if start <= end {
+        start <= time && time < end
+    } else {
+        time >= start || time < end
+    }
+}
+
+// Keeps one alert per active condition. Alerts sound when raised and then every repeat period
+// until acknowledged, and clear themselves once the condition goes away.
+pub struct AlertTracker {
+    config: AlertsConfig,
+    active: Vec<Alert>,
+}
+
+impl AlertTracker {
+    pub fn new(config: AlertsConfig) -> Self {
+        Self {
+            config,
+            active: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &AlertsConfig {
+        &self.config
+    }
+
+    pub fn active(&self) -> &[Alert] {
+        &self.active
+    }
+
+    pub fn pattern(&self, kind: AlertKind) -> &AlertPattern {
+        match kind {
+            AlertKind::RunOut => &self.config.run_out,
+            AlertKind::HatchFault => &self.config.hatch_fault,
+            AlertKind::ScaleFault => &self.config.scale_fault,
+            AlertKind::BowlLeft => &self.config.bowl_left,
+            AlertKind::StaleHold => &self.config.stale_hold,
+            AlertKind::SanitationOverdue => &self.config.sanitation_overdue,
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        for alert in self.active.iter_mut() {
+            alert.acknowledged = true;
+        }
+    }
+
+    pub fn set_quiet_hours(&mut self, quiet_hours: Option<QuietHours>) {
+        self.config.quiet_hours = quiet_hours;
+    }
+
+    //Returns the alerts that should sound now
+    pub fn update(
+        &mut self,
+        conditions: &[AlertKind],
+        now: Instant,
+        time_of_day: NaiveTime,
+    ) -> Vec<AlertKind> {
+        self.active.retain(|alert| conditions.contains(&alert.kind));
+        for kind in conditions {
+            if !self.active.iter().any(|alert| alert.kind == *kind) {
+                log::warn!("Alert raised: {:?}", kind);
+                self.active.push(Alert {
+                    kind: *kind,
+                    acknowledged: false,
+                    last_sounded: None,
+                });
+            }
+        }
+        let quiet = self
+            .config
+            .quiet_hours
+            .as_ref()
+            .is_some_and(|quiet_hours| is_quiet(quiet_hours, time_of_day));
+        if quiet {
+            return Vec::new();
+        }
+        let mut to_sound = Vec::new();
+        for i in 0..self.active.len() {
+            let alert = &self.active[i];
+            let repeat = self.pattern(alert.kind).repeat;
+            let due = match alert.last_sounded {
+                None => true,
+                Some(last) => !repeat.is_zero() && now.duration_since(last) >= repeat,
+            };
+            if due && !alert.acknowledged {
+                self.active[i].last_sounded = Some(now);
+                to_sound.push(self.active[i].kind);
+            }
+        }
+        to_sound
+    }
+}
+
+pub struct Buzzer {
+    channel: HBridge,
+    config: BuzzerConfig,
+}
+
+impl Buzzer {
+    pub fn new(controller: &Controller, config: &BuzzerConfig) -> Self {
+        Self {
+            channel: controller.get_h_bridge(config.channel),
+            config: config.clone(),
+        }
+    }
+
+    pub async fn sound(&self, pattern: &AlertPattern) {
+        for _ in 0..pattern.beeps {
+            self.channel.set_state(self.config.on.into()).await;
+            tokio::time::sleep(pattern.on).await;
+            self.channel.set_state(self.config.off.into()).await;
+            tokio::time::sleep(pattern.off).await;
+        }
+    }
+}
+
+pub async fn run_alerts(state: &Mutex<AppData>, events: EventSink, buzzer: Option<Buzzer>) {
+    let mut ticker = interval(ALERT_PERIOD);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let to_sound: Vec<(AlertKind, AlertPattern)> = {
+            let mut state_guard = state.lock().unwrap();
+            state_guard.update_sanitation();
+            let conditions = state_guard.alert_conditions();
+            let time_of_day = chrono::Local::now().time();
+            let alerts = &mut state_guard.alerts;
+            alerts
+                .update(&conditions, Instant::now(), time_of_day)
+                .into_iter()
+                .map(|kind| (kind, alerts.pattern(kind).clone()))
+                .collect()
+        };
+        for (kind, pattern) in to_sound {
+            let sound = AlertSound {
+                kind,
+                sound: pattern.sound.clone(),
+            };
+            events.emit(ALERT_EVENT, sound);
+            if let Some(buzzer) = &buzzer {
+                buzzer.sound(&pattern).await;
+            }
+        }
+    }
+}
+
+pub fn get_alerts(state: &Mutex<AppData>) -> Vec<Alert> {
+    state.lock().unwrap().alerts.active().to_vec()
+}
+
+pub fn acknowledge_alerts(state: &Mutex<AppData>) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    log::info!("Alerts acknowledged");
+    state_guard.alerts.acknowledge();
+    Ok(())
+}
+
+pub fn get_quiet_hours(state: &Mutex<AppData>) -> Option<QuietHours> {
+    state.lock().unwrap().alerts.config().quiet_hours.clone()
+}
+
+pub fn set_quiet_hours(
+    state: &Mutex<AppData>,
+    quiet_hours: Option<QuietHours>,
+) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    if let Some(quiet_hours) = &quiet_hours {
+        if parse_time(&quiet_hours.start).is_none() || parse_time(&quiet_hours.end).is_none() {
+            return Err(CommandError::Failed(
+                "Quiet hours must be HH:MM".to_string(),
+            ));
+        }
+    }
+    log::info!("Quiet hours set to {:?}", quiet_hours);
+    Config::update_section(&state_guard.config_dir, "alerts", |alerts| {
+        match &quiet_hours {
+            Some(quiet_hours) => {
+                alerts.insert("quiet_hours", toml_item(quiet_hours)?);
+            }
+            None => {
+                alerts.remove("quiet_hours");
+            }
+        }
+        Ok(())
+    })
+    .map_err(|e| CommandError::Failed(e.to_string()))?;
+    state_guard.alerts.set_quiet_hours(quiet_hours);
+    Ok(())
+}
+
+#[test]
+fn test_alerts_repeat_until_acknowledged() {
+    let mut tracker = AlertTracker::new(AlertsConfig::default());
+    let repeat = tracker.pattern(AlertKind::RunOut).repeat;
+    let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+    let start = Instant::now();
+
+    assert_eq!(
+        tracker.update(&[AlertKind::RunOut], start, noon),
+        vec![AlertKind::RunOut]
+    );
+    assert!(tracker.update(&[AlertKind::RunOut], start, noon).is_empty());
+    assert_eq!(
+        tracker.update(&[AlertKind::RunOut], start + repeat, noon),
+        vec![AlertKind::RunOut]
+    );
+    tracker.acknowledge();
+    assert!(tracker
+        .update(&[AlertKind::RunOut], start + repeat * 2, noon)
+        .is_empty());
+    // Clearing the condition clears the alert, so it sounds again next time
+    assert!(tracker.update(&[], start + repeat * 2, noon).is_empty());
+    assert!(tracker.active().is_empty());
+    assert_eq!(
+        tracker.update(&[AlertKind::RunOut], start + repeat * 3, noon),
+        vec![AlertKind::RunOut]
+    );
+}
+
+#[test]
+fn test_quiet_hours() {
+    let overnight = QuietHours {
+        start: "22:00".to_string(),
+        end: "06:30".to_string(),
+    };
+    assert!(is_quiet(
+        &overnight,
+        NaiveTime::from_hms_opt(23, 0, 0).unwrap()
+    ));
+    assert!(is_quiet(
+        &overnight,
+        NaiveTime::from_hms_opt(6, 0, 0).unwrap()
+    ));
+    assert!(!is_quiet(
+        &overnight,
+        NaiveTime::from_hms_opt(12, 0, 0).unwrap()
+    ));
+    let lunch = QuietHours {
+        start: "12:00".to_string(),
+        end: "13:00".to_string(),
+    };
+    assert!(is_quiet(
+        &lunch,
+        NaiveTime::from_hms_opt(12, 30, 0).unwrap()
+    ));
+    assert!(!is_quiet(
+        &lunch,
+        NaiveTime::from_hms_opt(13, 0, 0).unwrap()
+    ));
+}
@@ -0,0 +1,31 @@
+use std::sync::Mutex;
+
+use control_components::components::clear_core_io::DigitalInput;
+
+use crate::config::PhotoEyeConfig;
+use crate::input::{Edge, FilteredInput};
+use crate::io::PhotoEyeState;
+use crate::state::AppData;
+
+// Bowls are placed and removed on the filtered photo eye, so a hand passing through the beam
+// or a flickering sensor doesn't look like a bowl coming and going
+pub async fn run_bowl_detector(
+    state: &Mutex<AppData>,
+    photo_eye: DigitalInput,
+    config: PhotoEyeConfig,
+) {
+    let mut photo_eye = FilteredInput::spawn("Photo eye", photo_eye, &config.filter()).await;
+    if photo_eye.get_state() {
+        state
+            .lock()
+            .unwrap()
+            .update_pe_state(PhotoEyeState::Blocked);
+    }
+    loop {
+        let pe_state = match photo_eye.next_edge().await {
+            Edge::Rising => PhotoEyeState::Blocked,
+            Edge::Falling => PhotoEyeState::Unblocked,
+        };
+        state.lock().unwrap().update_pe_state(pe_state);
+    }
+}
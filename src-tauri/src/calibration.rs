@@ -0,0 +1,330 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use libra::scale::ConnectedScale;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::config::{toml_item, CalibrationRecord, Config};
+use crate::accounts::Role;
+use crate::data_logging::DataAction;
+use crate::session::CommandError;
+use crate::state::{AppData, IchibuState};
+
+// Samples are taken at the phidget data interval set in run()
+const SAMPLE_COUNT: usize = 25;
+const SAMPLE_PERIOD: Duration = Duration::from_millis(40);
+const CELL_COUNT: usize = 4;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CalibrationRequest {
+    Tare,
+    AddWeight(f64),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CalibrationSample {
+    pub weight: f64,
+    pub readings: [f64; CELL_COUNT],
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CalibrationFit {
+    pub coefficients: [f64; CELL_COUNT],
+    //Root mean square error over the reference weights, in the same units as the weights
+    pub residual: f64,
+    pub errors: Vec<f64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Calibration {
+    pub tare: Option<[f64; CELL_COUNT]>,
+    pub samples: Vec<CalibrationSample>,
+    pub pending: Option<CalibrationRequest>,
+    pub error: Option<String>,
+}
+
+impl Calibration {
+    pub fn fit(&self, current: [f64; CELL_COUNT]) -> Option<CalibrationFit> {
+        let tare = self.tare?;
+        let deltas: Vec<([f64; CELL_COUNT], f64)> = self
+            .samples
+            .iter()
+            .map(|sample| {
+                let mut delta = [0.; CELL_COUNT];
+                for (i, d) in delta.iter_mut().enumerate() {
+                    *d = sample.readings[i] - tare[i];
+                }
+                (delta, sample.weight)
+            })
+            .collect();
+        if deltas.is_empty() {
+            return None;
+        }
+        // With fewer than one reference per load cell we can only rescale the existing coefficients
+        let coefficients = if deltas.len() >= CELL_COUNT {
+            fit_per_cell(&deltas).or_else(|| fit_gain(&deltas, current))?
+        } else {
+            fit_gain(&deltas, current)?
+        };
+        let errors: Vec<f64> = deltas
+            .iter()
+            .map(|(delta, weight)| dot(&coefficients, delta) - weight)
+            .collect();
+        let residual = (errors.iter().map(|e| e * e).sum::<f64>() / errors.len() as f64).sqrt();
+        Some(CalibrationFit {
+            coefficients,
+            residual,
+            errors,
+        })
+    }
+}
+
+fn dot(a: &[f64; CELL_COUNT], b: &[f64; CELL_COUNT]) -> f64 {
+    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+}
+
+fn fit_gain(
+    deltas: &[([f64; CELL_COUNT], f64)],
+    current: [f64; CELL_COUNT],
+) -> Option<[f64; CELL_COUNT]> {
+    let (num, den) = deltas.iter().fold((0., 0.), |(num, den), (delta, weight)| {
+        let predicted = dot(&current, delta);
+        (num + weight * predicted, den + predicted * predicted)
+    });
+    if den.abs() < f64::EPSILON {
+        return None;
+    }
+    let gain = num / den;
+    Some(current.map(|c| c * gain))
+}
+
+// Least squares on the normal equations, solved with partial pivoting
+fn fit_per_cell(deltas: &[([f64; CELL_COUNT], f64)]) -> Option<[f64; CELL_COUNT]> {
+    let mut a = [[0.; CELL_COUNT + 1]; CELL_COUNT];
+    for (delta, weight) in deltas {
+        for i in 0..CELL_COUNT {
+            for j in 0..CELL_COUNT {
+                a[i][j] += delta[i] * delta[j];
+            }
+            a[i][CELL_COUNT] += delta[i] * weight;
+        }
+    }
+    for col in 0..CELL_COUNT {
+        let pivot = (col..CELL_COUNT)
+            .max_by(|x, y| a[*x][col].abs().total_cmp(&a[*y][col].abs()))
+            .unwrap();
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        let pivot_row = a[col];
+        for (i, row) in a.iter_mut().enumerate() {
+            if i != col {
+                let factor = row[col] / pivot_row[col];
+                for (x, p) in row.iter_mut().zip(pivot_row.iter()).skip(col) {
+                    *x -= factor * p;
+                }
+            }
+        }
+    }
+    let mut coefficients = [0.; CELL_COUNT];
+    for (i, c) in coefficients.iter_mut().enumerate() {
+        *c = a[i][CELL_COUNT] / a[i][i];
+    }
+    Some(coefficients)
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let len = values.len();
+    (values[(len - 1) / 2] + values[len / 2]) / 2.
+}
+
+async fn read_cells(scale: &mut ConnectedScale) -> Result<[f64; CELL_COUNT], String> {
+    let mut samples: [Vec<f64>; CELL_COUNT] = Default::default();
+    for _ in 0..SAMPLE_COUNT {
+        let readings = scale
+            .get_raw_readings()
+            .map_err(|e| format!("Scale read failed: {:?}", e))?;
+        if readings.len() != CELL_COUNT {
+            return Err(format!(
+                "Expected {} load cells, got {}",
+                CELL_COUNT,
+                readings.len()
+            ));
+        }
+        for (cell, reading) in samples.iter_mut().zip(readings) {
+            cell.push(reading);
+        }
+        sleep(SAMPLE_PERIOD).await;
+    }
+    Ok(samples.map(|mut cell| median(&mut cell)))
+}
+
+//Called from the cycle loop, which owns the scale, while in IchibuState::Calibrating
+pub async fn handle_calibration_request(
+    state: &Mutex<AppData>,
+    scale: &mut ConnectedScale,
+) {
+    let request = { state.lock().unwrap().calibration.pending.clone() };
+    let Some(request) = request else {
+        sleep(Duration::from_millis(250)).await;
+        return;
+    };
+    let readings = read_cells(scale).await;
+    let mut state_guard = state.lock().unwrap();
+    let calibration = &mut state_guard.calibration;
+    calibration.pending = None;
+    match readings {
+        Ok(readings) => {
+            calibration.error = None;
+            match request {
+                CalibrationRequest::Tare => {
+                    log::info!("Calibration tare: {:?}", readings);
+                    calibration.tare = Some(readings);
+                    calibration.samples.clear();
+                }
+                CalibrationRequest::AddWeight(weight) => {
+                    log::info!("Calibration sample {}: {:?}", weight, readings);
+                    calibration
+                        .samples
+                        .push(CalibrationSample { weight, readings });
+                }
+            }
+        }
+        Err(e) => {
+            log::error!("Calibration reading failed: {}", e);
+            calibration.error = Some(e);
+        }
+    }
+}
+
+pub fn start_calibration(state: &Mutex<AppData>) -> Result<bool, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    if !matches!(state_guard.get_state(), IchibuState::Ready) {
+        return Ok(false);
+    }
+    state_guard.calibration = Calibration::default();
+    state_guard.update_state(IchibuState::Calibrating);
+    Ok(true)
+}
+
+pub fn request_calibration_reading(
+    state: &Mutex<AppData>,
+    request: CalibrationRequest,
+) -> Result<bool, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    if !matches!(state_guard.get_state(), IchibuState::Calibrating) {
+        return Ok(false);
+    }
+    state_guard.calibration.pending = Some(request);
+    Ok(true)
+}
+
+pub fn get_calibration(state: &Mutex<AppData>) -> Result<Calibration, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    Ok(state_guard.calibration.clone())
+}
+
+pub fn get_calibration_fit(
+    state: &Mutex<AppData>,
+) -> Result<Option<CalibrationFit>, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    let current = Config::load_from(&state_guard.config_dir).phidget.coefficients;
+    Ok(state_guard.calibration.fit(current))
+}
+
+//New coefficients are picked up the next time the scale is connected, so after a restart
+pub fn save_calibration(
+    state: &Mutex<AppData>,
+) -> Result<CalibrationFit, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    let config = Config::load_from(&state_guard.config_dir);
+    let fit = state_guard
+        .calibration
+        .fit(config.phidget.coefficients)
+        .ok_or(CommandError::Failed(
+            "Calibration needs a tare and at least one reference weight".to_string(),
+        ))?;
+    let record = CalibrationRecord {
+        timestamp: chrono::Utc::now().to_string(),
+        reference_weights: state_guard
+            .calibration
+            .samples
+            .iter()
+            .map(|s| s.weight)
+            .collect(),
+        residual: fit.residual,
+    };
+    Config::update_section(&state_guard.config_dir, "phidget", |phidget| {
+        phidget.insert("coefficients", toml_item(&fit.coefficients)?);
+        phidget.insert("calibration", toml_item(&record)?);
+        Ok(())
+    })
+    .map_err(|e| CommandError::Failed(e.to_string()))?;
+    log::info!("Saved scale coefficients {:?}", fit.coefficients);
+    state_guard.log_action(&DataAction::Calibrated);
+    state_guard.calibration = Calibration::default();
+    state_guard.update_state(IchibuState::Ready);
+    Ok(fit)
+}
+
+pub fn cancel_calibration(state: &Mutex<AppData>) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    state_guard.calibration = Calibration::default();
+    if matches!(state_guard.get_state(), IchibuState::Calibrating) {
+        state_guard.update_state(IchibuState::Ready);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_calibration_fit_per_cell() {
+    let coefficients = [0.5, 0.25, 2., 1.];
+    let tare = [100., 200., 300., 400.];
+    let cells = [
+        [10., 0., 0., 0.],
+        [0., 20., 0., 0.],
+        [0., 0., 5., 0.],
+        [0., 0., 0., 50.],
+        [10., 20., 5., 50.],
+    ];
+    let calibration = Calibration {
+        tare: Some(tare),
+        samples: cells
+            .iter()
+            .map(|delta| CalibrationSample {
+                weight: dot(&coefficients, delta),
+                readings: [0, 1, 2, 3].map(|i| tare[i] + delta[i]),
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let fit = calibration.fit([1.; 4]).unwrap();
+    for (fitted, expected) in fit.coefficients.iter().zip(coefficients) {
+        assert!((fitted - expected).abs() < 1e-9);
+    }
+    assert!(fit.residual < 1e-9);
+}
+
+#[test]
+fn test_calibration_fit_single_weight_rescales() {
+    let calibration = Calibration {
+        tare: Some([0.; 4]),
+        samples: vec![CalibrationSample {
+            weight: 200.,
+            readings: [25.; 4],
+        }],
+        ..Default::default()
+    };
+    let fit = calibration.fit([1.; 4]).unwrap();
+    assert_eq!(fit.coefficients, [2.; 4]);
+    assert!(Calibration::default().fit([1.; 4]).is_none());
+}
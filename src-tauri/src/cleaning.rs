@@ -0,0 +1,243 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use control_components::components::clear_core_motor::ClearCoreMotor;
+use libra::scale::ConnectedScale;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::accounts::Role;
+use crate::config::{CleaningConfig, Config};
+use crate::data_logging::DataAction;
+use crate::scale_health::{ScaleFault, ScaleMonitor};
+use crate::session::CommandError;
+use crate::state::{AppData, IchibuState};
+
+const JOG_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CleaningRequest {
+    //Runs the conveyor a short way so the chute can be rinsed
+    Jog,
+    ZeroCheck,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CleaningStep {
+    pub name: String,
+    pub done: bool,
+}
+
+// A guided cleaning, the operator confirms each step from the config and the scale has to
+// read zero again before it counts as done
+#[derive(Clone, Debug, Serialize)]
+pub struct CleaningSession {
+    pub user: String,
+    pub started: String,
+    #[serde(skip)]
+    started_at: Instant,
+    pub steps: Vec<CleaningStep>,
+    pub pending: Option<CleaningRequest>,
+    pub zero_check: Option<Result<f64, ScaleFault>>,
+    #[serde(skip)]
+    jog_distance: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CleaningRecord {
+    pub user: String,
+    pub started: String,
+    pub duration_s: u64,
+}
+
+impl CleaningSession {
+    pub fn new(config: &CleaningConfig, user: String) -> Self {
+        Self {
+            user,
+            started: chrono::Utc::now().to_rfc3339(),
+            started_at: Instant::now(),
+            steps: config
+                .steps
+                .iter()
+                .map(|name| CleaningStep {
+                    name: name.clone(),
+                    done: false,
+                })
+                .collect(),
+            pending: None,
+            zero_check: None,
+            jog_distance: config.jog_distance,
+        }
+    }
+
+    pub fn steps_done(&self) -> bool {
+        self.steps.iter().all(|step| step.done)
+    }
+
+    //Steps are confirmed in order, returns false once they are all done
+    pub fn confirm_step(&mut self) -> bool {
+        let Some(step) = self.steps.iter_mut().find(|step| !step.done) else {
+            return false;
+        };
+        log::info!("Cleaning step done: {}", step.name);
+        step.done = true;
+        //Anything moved after a zero check means it has to be done again
+        self.zero_check = None;
+        true
+    }
+
+    pub fn finish(&self) -> Result<CleaningRecord, String> {
+        if !self.steps_done() {
+            return Err("Not every cleaning step is done".to_string());
+        }
+        match &self.zero_check {
+            Some(Ok(_)) => Ok(CleaningRecord {
+                user: self.user.clone(),
+                started: self.started.clone(),
+                duration_s: self.started_at.elapsed().as_secs(),
+            }),
+            Some(Err(fault)) => Err(format!("Scale zero check failed: {:?}", fault)),
+            None => Err("The scale zero check hasn't been done".to_string()),
+        }
+    }
+}
+
+//Called from the cycle loop, which owns the conveyor and scale, while in IchibuState::Cleaning
+pub async fn handle_cleaning_request(
+    state: &Mutex<AppData>,
+    conveyor: &ClearCoreMotor,
+    scale: &mut ConnectedScale,
+    monitor: &mut ScaleMonitor,
+) {
+    let request = {
+        let mut state_guard = state.lock().unwrap();
+        state_guard
+            .cleaning
+            .as_mut()
+            .and_then(|cleaning| cleaning.pending.clone().map(|r| (r, cleaning.jog_distance)))
+    };
+    let Some((request, jog_distance)) = request else {
+        conveyor.abrupt_stop().await;
+        conveyor.disable().await;
+        sleep(Duration::from_millis(250)).await;
+        return;
+    };
+    match request {
+        CleaningRequest::Jog => {
+            log::info!("Jogging the conveyor for cleaning");
+            if conveyor.enable().await.is_ok() && conveyor.relative_move(jog_distance).await.is_ok()
+            {
+                let _ = conveyor.wait_for_move(JOG_TIMEOUT).await;
+            }
+            conveyor.abrupt_stop().await;
+            conveyor.disable().await;
+        }
+        CleaningRequest::ZeroCheck => {
+            let result = monitor.zero_check(scale).await;
+            log::info!("Cleaning zero check: {:?}", result);
+            if let Some(cleaning) = &mut state.lock().unwrap().cleaning {
+                cleaning.zero_check = Some(result);
+            }
+        }
+    }
+    if let Some(cleaning) = &mut state.lock().unwrap().cleaning {
+        cleaning.pending = None;
+    }
+}
+
+pub fn start_cleaning(state: &Mutex<AppData>) -> Result<bool, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    if !matches!(state_guard.get_state(), IchibuState::Ready) {
+        return Ok(false);
+    }
+    let config = Config::load_from(&state_guard.config_dir).cleaning;
+    let user = state_guard.user_name().unwrap_or_default();
+    log::info!("{} started cleaning", user);
+    state_guard.cleaning = Some(CleaningSession::new(&config, user));
+    state_guard.log_action(&DataAction::Cleaning);
+    state_guard.update_state(IchibuState::Cleaning);
+    Ok(true)
+}
+
+pub fn get_cleaning(state: &Mutex<AppData>) -> Result<Option<CleaningSession>, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    Ok(state_guard.cleaning.clone())
+}
+
+pub fn confirm_cleaning_step(state: &Mutex<AppData>) -> Result<bool, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    Ok(state_guard
+        .cleaning
+        .as_mut()
+        .is_some_and(|cleaning| cleaning.confirm_step()))
+}
+
+pub fn request_cleaning_action(
+    state: &Mutex<AppData>,
+    request: CleaningRequest,
+) -> Result<bool, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    let Some(cleaning) = &mut state_guard.cleaning else {
+        return Ok(false);
+    };
+    //Only check the zero once everything is back in place
+    if matches!(request, CleaningRequest::ZeroCheck) && !cleaning.steps_done() {
+        return Ok(false);
+    }
+    cleaning.pending = Some(request);
+    Ok(true)
+}
+
+pub fn finish_cleaning(state: &Mutex<AppData>) -> Result<CleaningRecord, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    let record = state_guard
+        .cleaning
+        .as_ref()
+        .ok_or(CommandError::Failed("No cleaning in progress".to_string()))?
+        .finish()
+        .map_err(CommandError::Failed)?;
+    log::info!(
+        "{} finished cleaning in {}s",
+        record.user,
+        record.duration_s
+    );
+    state_guard.log_action(&DataAction::CleaningCompleted {
+        user: record.user.clone(),
+        duration_s: record.duration_s,
+    });
+    state_guard.record_cleaning(&record.user, record.duration_s);
+    state_guard.cleaning = None;
+    state_guard.update_state(IchibuState::Ready);
+    Ok(record)
+}
+
+pub fn cancel_cleaning(state: &Mutex<AppData>) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    if state_guard.cleaning.take().is_some() {
+        log::warn!("Cleaning cancelled");
+    }
+    if matches!(state_guard.get_state(), IchibuState::Cleaning) {
+        state_guard.update_state(IchibuState::Ready);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_cleaning_steps() {
+    let mut cleaning = CleaningSession::new(&CleaningConfig::default(), "sam".to_string());
+    assert!(cleaning.finish().is_err());
+    while cleaning.confirm_step() {}
+    assert!(cleaning.steps_done());
+    assert!(cleaning.finish().is_err());
+    cleaning.zero_check = Some(Err(ScaleFault::ZeroDrift(12.)));
+    assert!(cleaning.finish().unwrap_err().contains("ZeroDrift"));
+    cleaning.zero_check = Some(Ok(0.4));
+    let record = cleaning.finish().unwrap();
+    assert_eq!(record.user, "sam");
+}
@@ -0,0 +1,188 @@
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::alerts::{acknowledge_alerts, get_alerts, get_quiet_hours, set_quiet_hours};
+use crate::calibration::{
+    cancel_calibration, get_calibration, get_calibration_fit, request_calibration_reading,
+    save_calibration, start_calibration,
+};
+use crate::cleaning::{
+    cancel_cleaning, confirm_cleaning_step, finish_cleaning, get_cleaning,
+    request_cleaning_action, start_cleaning,
+};
+use crate::logging::get_recent_logs;
+use crate::sanitation::{get_sanitation_report, get_sanitation_status};
+use crate::scale_health::{clear_scale_fault, get_scale_fault};
+use crate::session::CommandError;
+use crate::shutdown::exit_kiosk;
+use crate::staging::get_staging_report;
+use crate::trace::{get_cycle_trace, list_cycle_traces};
+use crate::state::{
+    clear_dispenser_time_out, discard_resume_offer, dispenser_has_timed_out, dispenser_is_busy,
+    get_dispense_count, get_dispense_type, get_pe_blocked, get_portion_sizes, get_resume_offer,
+    resume_previous_state, set_dispense_type, update_current_ingredient, update_run_state,
+    update_ui_request, AppData,
+};
+use crate::{
+    add_user, escape, get_session, list_users, log_in, log_out, remove_user, update_user,
+};
+
+// Every command that works on the app state. The kiosk webview and remote clients of the
+// daemon both go through invoke(), so they see the same command set.
+pub const COMMANDS: &[&str] = &[
+    "log_in",
+    "log_out",
+    "get_session",
+    "list_users",
+    "add_user",
+    "update_user",
+    "remove_user",
+    "escape",
+    "exit_kiosk",
+    "update_current_ingredient",
+    "update_run_state",
+    "update_ui_request",
+    "get_portion_sizes",
+    "get_dispense_type",
+    "set_dispense_type",
+    "get_dispense_count",
+    "get_pe_blocked",
+    "dispenser_is_busy",
+    "dispenser_has_timed_out",
+    "clear_dispenser_time_out",
+    "get_resume_offer",
+    "resume_previous_state",
+    "discard_resume_offer",
+    "start_calibration",
+    "request_calibration_reading",
+    "get_calibration",
+    "get_calibration_fit",
+    "save_calibration",
+    "cancel_calibration",
+    "start_cleaning",
+    "get_cleaning",
+    "confirm_cleaning_step",
+    "request_cleaning_action",
+    "finish_cleaning",
+    "cancel_cleaning",
+    "get_sanitation_status",
+    "get_sanitation_report",
+    "get_scale_fault",
+    "clear_scale_fault",
+    "get_alerts",
+    "acknowledge_alerts",
+    "get_quiet_hours",
+    "set_quiet_hours",
+    "get_staging_report",
+    "get_recent_logs",
+    "get_cycle_trace",
+    "list_cycle_traces",
+];
+
+//Arguments come keyed the way the UI sends them to tauri, camelCase
+fn arg<T: DeserializeOwned>(args: &Value, name: &str) -> Result<T, CommandError> {
+    let value = args.get(name).cloned().unwrap_or(Value::Null);
+    serde_json::from_value(value)
+        .map_err(|e| CommandError::Failed(format!("Bad {} argument: {}", name, e)))
+}
+
+fn reply<T: Serialize>(value: T) -> Result<Value, CommandError> {
+    serde_json::to_value(value).map_err(|e| CommandError::Failed(e.to_string()))
+}
+
+pub fn invoke(state: &Mutex<AppData>, command: &str, args: Value) -> Result<Value, CommandError> {
+    match command {
+        "log_in" => reply(log_in(state, arg(&args, "pin")?)),
+        "log_out" => {
+            log_out(state);
+            Ok(Value::Null)
+        }
+        "get_session" => reply(get_session(state)),
+        "list_users" => list_users(state).and_then(reply),
+        "add_user" => add_user(
+            state,
+            arg(&args, "name")?,
+            arg(&args, "pin")?,
+            arg(&args, "role")?,
+        )
+        .and_then(reply),
+        "update_user" => update_user(
+            state,
+            arg(&args, "name")?,
+            arg(&args, "pin")?,
+            arg(&args, "role")?,
+        )
+        .and_then(reply),
+        "remove_user" => remove_user(state, arg(&args, "name")?).and_then(reply),
+        "escape" => escape(state).and_then(reply),
+        "exit_kiosk" => exit_kiosk(state).and_then(reply),
+        "update_current_ingredient" => {
+            update_current_ingredient(state, arg(&args, "snack")?).and_then(reply)
+        }
+        "update_run_state" => update_run_state(state, arg(&args, "newState")?).and_then(reply),
+        "update_ui_request" => {
+            update_ui_request(state, arg(&args, "uiRequest")?);
+            Ok(Value::Null)
+        }
+        "get_portion_sizes" => reply(get_portion_sizes(state)),
+        "get_dispense_type" => reply(get_dispense_type(state)),
+        "set_dispense_type" => {
+            set_dispense_type(state, arg(&args, "dispenseType")?).and_then(reply)
+        }
+        "get_dispense_count" => reply(get_dispense_count(state)),
+        "get_pe_blocked" => reply(get_pe_blocked(state)),
+        "dispenser_is_busy" => reply(dispenser_is_busy(state)),
+        "dispenser_has_timed_out" => reply(dispenser_has_timed_out(state)),
+        "clear_dispenser_time_out" => clear_dispenser_time_out(state).and_then(reply),
+        "get_resume_offer" => reply(get_resume_offer(state)),
+        "resume_previous_state" => resume_previous_state(state).and_then(reply),
+        "discard_resume_offer" => discard_resume_offer(state).and_then(reply),
+        "start_calibration" => start_calibration(state).and_then(reply),
+        "request_calibration_reading" => {
+            request_calibration_reading(state, arg(&args, "request")?).and_then(reply)
+        }
+        "get_calibration" => get_calibration(state).and_then(reply),
+        "get_calibration_fit" => get_calibration_fit(state).and_then(reply),
+        "save_calibration" => save_calibration(state).and_then(reply),
+        "cancel_calibration" => cancel_calibration(state).and_then(reply),
+        "start_cleaning" => start_cleaning(state).and_then(reply),
+        "get_cleaning" => get_cleaning(state).and_then(reply),
+        "confirm_cleaning_step" => confirm_cleaning_step(state).and_then(reply),
+        "request_cleaning_action" => {
+            request_cleaning_action(state, arg(&args, "request")?).and_then(reply)
+        }
+        "finish_cleaning" => finish_cleaning(state).and_then(reply),
+        "cancel_cleaning" => cancel_cleaning(state).and_then(reply),
+        "get_sanitation_status" => reply(get_sanitation_status(state)),
+        "get_sanitation_report" => {
+            get_sanitation_report(state, arg(&args, "limit")?).and_then(reply)
+        }
+        "get_scale_fault" => reply(get_scale_fault(state)),
+        "clear_scale_fault" => clear_scale_fault(state).and_then(reply),
+        "get_alerts" => reply(get_alerts(state)),
+        "acknowledge_alerts" => acknowledge_alerts(state).and_then(reply),
+        "get_quiet_hours" => reply(get_quiet_hours(state)),
+        "set_quiet_hours" => set_quiet_hours(state, arg(&args, "quietHours")?).and_then(reply),
+        "get_staging_report" => reply(get_staging_report(state)),
+        "get_cycle_trace" => get_cycle_trace(state, arg(&args, "id")?).and_then(reply),
+        "list_cycle_traces" => list_cycle_traces(state, arg(&args, "limit")?).and_then(reply),
+        "get_recent_logs" => get_recent_logs(state, arg(&args, "minutes")?).and_then(reply),
+        _ => Err(CommandError::Failed(format!("Unknown command {}", command))),
+    }
+}
+
+#[test]
+fn test_command_args() {
+    use crate::state::IchibuState;
+
+    let args = serde_json::json!({ "newState": "Cleaning", "snack": 3 });
+    let new_state: IchibuState = arg(&args, "newState").unwrap();
+    assert_eq!(new_state, IchibuState::Cleaning);
+    assert_eq!(arg::<usize>(&args, "snack"), Ok(3));
+    assert!(arg::<usize>(&args, "pin").is_err());
+    let quiet_hours: Option<String> = arg(&args, "quietHours").unwrap();
+    assert_eq!(quiet_hours, None);
+}
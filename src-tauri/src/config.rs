@@ -1,6 +1,13 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
 use std::time::Duration;
 use std::{env, fs};
+use toml_edit::ser::ValueSerializer;
+use toml_edit::{Document, Item, TableLike};
+
+use crate::lights::{ChannelDrive, LightColors, LightPattern};
+use crate::portion::DispenseType;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Addresses {
@@ -14,14 +21,71 @@ pub struct MotorConfig {
     pub id: usize,
     pub scale: usize,
     pub acceleration: f64,
+    //Conveyor speed outside of dispensing, warm-ups that run faster go back to it
+    #[serde(default = "default_conveyor_velocity")]
+    pub velocity: f64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct PhotoEyeConfig {
+fn default_conveyor_velocity() -> f64 {
+    1.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum FilterMode {
+    //The last sample_number samples all agree
+    #[default]
+    Consecutive,
+    //Most of the last sample_number samples agree
+    Majority,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputFilterConfig {
     #[serde(with = "duration_serde")]
     pub sample_period: Duration,
     pub sample_number: usize,
+    #[serde(default)]
+    pub mode: FilterMode,
+}
+
+impl Default for InputFilterConfig {
+    fn default() -> Self {
+        Self {
+            sample_period: Duration::from_millis(10),
+            sample_number: 3,
+            mode: FilterMode::Consecutive,
+        }
+    }
+}
+
+// Bowls come and go slowly, so the photo eye is sampled less often than the hatch switches
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PhotoEyeConfig {
     pub input_id: usize,
+    #[serde(with = "duration_serde", default = "default_photo_eye_period")]
+    pub sample_period: Duration,
+    #[serde(default = "default_photo_eye_samples")]
+    pub sample_number: usize,
+    #[serde(default)]
+    pub mode: FilterMode,
+}
+
+fn default_photo_eye_period() -> Duration {
+    Duration::from_millis(50)
+}
+
+fn default_photo_eye_samples() -> usize {
+    4
+}
+
+impl PhotoEyeConfig {
+    pub fn filter(&self) -> InputFilterConfig {
+        InputFilterConfig {
+            sample_period: self.sample_period,
+            sample_number: self.sample_number,
+            mode: self.mode,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,23 +96,102 @@ pub struct HatchConfig {
     pub velocity: f64,
     pub acceleration: f64,
     pub scale: usize,
+    //For both limit switches
+    #[serde(default)]
+    pub limit_filter: InputFilterConfig,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PhidgetConfig {
     pub sn: i32,
     pub coefficients: [f64; 4],
+    #[serde(default)]
+    pub calibration: Option<CalibrationRecord>,
+}
+
+//Written by the calibration wizard alongside the coefficients it produced
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CalibrationRecord {
+    pub timestamp: String,
+    pub reference_weights: Vec<f64>,
+    pub residual: f64,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScaleHealthConfig {
+    #[serde(with = "duration_serde")]
+    pub check_interval: Duration,
+    #[serde(with = "duration_serde")]
+    pub sample_period: Duration,
+    pub sample_number: usize,
+    pub max_zero_drift: f64,
+    pub max_noise: f64,
+    pub min_weight: f64,
+    pub max_weight: f64,
+    //A settled load cell can read the same value for a while, only a long freeze is a stall
+    #[serde(with = "duration_serde", default = "default_stall_timeout")]
+    pub stall_timeout: Duration,
+}
+
+fn default_stall_timeout() -> Duration {
+    Duration::from_secs(15 * 60)
 }
+
+impl Default for ScaleHealthConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(60),
+            sample_period: Duration::from_millis(40),
+            sample_number: 25,
+            max_zero_drift: 5.,
+            max_noise: 2.,
+            min_weight: -500.,
+            max_weight: 5000.,
+            stall_timeout: default_stall_timeout(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DispenseConfig {
     #[serde(with = "duration_serde")]
     pub timeout: Duration,
+    #[serde(default)]
+    pub policy: DispensePolicy,
+    //Let the hatch and scale settle after closing before the portion is dispensed
+    #[serde(with = "duration_serde", default = "default_settle")]
+    pub settle: Duration,
+    //Used for snacks that don't set their own
+    #[serde(default)]
+    pub default_type: DispenseType,
+}
+
+fn default_settle() -> Duration {
+    Duration::from_millis(2000)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DispensePolicy {
+    pub max_consecutive_timeouts: usize,
+    pub prime_on_retry: bool,
+    //Fraction of the target weight that is served as is when a dispense times out
+    pub acceptable_partial_weight: f64,
+}
+
+impl Default for DispensePolicy {
+    fn default() -> Self {
+        Self {
+            max_consecutive_timeouts: 3,
+            prime_on_retry: true,
+            acceptable_partial_weight: 0.9,
+        }
+    }
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SetpointConfig {
     pub empty: f64,
     pub filling_threshold: f64,
 }
+//Only read to seed the user accounts on first run, can be removed from the file afterwards
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Pins {
     pub sudo: usize,
@@ -56,6 +199,411 @@ pub struct Pins {
     pub operator: usize,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthConfig {
+    pub max_failed_attempts: usize,
+    #[serde(with = "duration_serde")]
+    pub lockout: Duration,
+    #[serde(with = "duration_serde")]
+    pub session_timeout: Duration,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            max_failed_attempts: 5,
+            lockout: Duration::from_secs(300),
+            session_timeout: Duration::from_secs(900),
+        }
+    }
+}
+
+//Drive for each light channel, in the same order as `channels`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ColorEncodings {
+    pub off: Vec<ChannelDrive>,
+    pub red: Vec<ChannelDrive>,
+    pub yellow: Vec<ChannelDrive>,
+    pub green: Vec<ChannelDrive>,
+}
+
+//Which pattern shows for each machine condition
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightPatterns {
+    pub idle: LightPattern,
+    pub running: LightPattern,
+    pub busy: LightPattern,
+    pub run_out: LightPattern,
+    pub fault: LightPattern,
+    pub cleaning: LightPattern,
+    pub maintenance_due: LightPattern,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightsConfig {
+    //H-bridge ids on the ClearCore
+    pub channels: Vec<usize>,
+    pub colors: ColorEncodings,
+    #[serde(with = "duration_serde")]
+    pub tick: Duration,
+    pub patterns: LightPatterns,
+}
+
+impl Default for LightsConfig {
+    fn default() -> Self {
+        use ChannelDrive::{Neg, Off, Pos};
+        let blink = Duration::from_millis(500);
+        Self {
+            channels: vec![4, 5],
+            colors: ColorEncodings {
+                off: vec![Pos, Pos],
+                red: vec![Neg, Off],
+                yellow: vec![Neg, Neg],
+                green: vec![Pos, Neg],
+            },
+            tick: Duration::from_millis(50),
+            patterns: LightPatterns {
+                idle: LightPattern::Off,
+                running: LightPattern::Solid {
+                    color: LightColors::Green,
+                },
+                busy: LightPattern::Solid {
+                    color: LightColors::Yellow,
+                },
+                run_out: LightPattern::Blink {
+                    color: LightColors::Red,
+                    period: blink,
+                },
+                fault: LightPattern::Alternating {
+                    first: LightColors::Red,
+                    second: LightColors::Yellow,
+                    period: blink,
+                },
+                cleaning: LightPattern::Pulse {
+                    color: LightColors::Yellow,
+                    on: Duration::from_millis(200),
+                    period: Duration::from_secs(2),
+                },
+                maintenance_due: LightPattern::Pulse {
+                    color: LightColors::Red,
+                    on: Duration::from_millis(200),
+                    period: Duration::from_secs(2),
+                },
+            },
+        }
+    }
+}
+
+//Buzzer wired to an H-bridge on the ClearCore
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuzzerConfig {
+    pub channel: usize,
+    pub on: ChannelDrive,
+    pub off: ChannelDrive,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertPattern {
+    pub beeps: usize,
+    #[serde(with = "duration_serde")]
+    pub on: Duration,
+    #[serde(with = "duration_serde")]
+    pub off: Duration,
+    //Sounds again this often until acknowledged, zero only sounds once
+    #[serde(with = "duration_serde")]
+    pub repeat: Duration,
+    //File in the sounds folder for the kiosk to play
+    #[serde(default)]
+    pub sound: Option<String>,
+}
+
+impl AlertPattern {
+    fn beeps(beeps: usize, repeat: Duration) -> Self {
+        Self {
+            beeps,
+            on: Duration::from_millis(300),
+            off: Duration::from_millis(200),
+            repeat,
+            sound: None,
+        }
+    }
+}
+
+//Local times as "HH:MM", can wrap past midnight
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertsConfig {
+    #[serde(default)]
+    pub buzzer: Option<BuzzerConfig>,
+    pub run_out: AlertPattern,
+    pub hatch_fault: AlertPattern,
+    pub scale_fault: AlertPattern,
+    pub bowl_left: AlertPattern,
+    #[serde(default = "default_stale_hold")]
+    pub stale_hold: AlertPattern,
+    #[serde(default = "default_sanitation_overdue")]
+    pub sanitation_overdue: AlertPattern,
+    //How long a served bowl can sit under the chute before alerting
+    #[serde(with = "duration_serde")]
+    pub bowl_left_after: Duration,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+fn default_stale_hold() -> AlertPattern {
+    AlertPattern::beeps(2, Duration::from_secs(120))
+}
+
+fn default_sanitation_overdue() -> AlertPattern {
+    AlertPattern::beeps(2, Duration::from_secs(10 * 60))
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            buzzer: None,
+            run_out: AlertPattern::beeps(3, Duration::from_secs(60)),
+            hatch_fault: AlertPattern::beeps(5, Duration::from_secs(30)),
+            scale_fault: AlertPattern::beeps(5, Duration::from_secs(30)),
+            bowl_left: AlertPattern::beeps(1, Duration::from_secs(20)),
+            stale_hold: default_stale_hold(),
+            sanitation_overdue: default_sanitation_overdue(),
+            bowl_left_after: Duration::from_secs(30),
+            quiet_hours: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum StagingStrategy {
+    //Nothing is dispensed until the customer asks
+    OnDemand,
+    //The base portion waits on the closed hatch for the next customer
+    #[default]
+    KeepStaged,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExpiredAction {
+    //Raise an alert and leave it for staff
+    #[default]
+    Flag,
+    //Drop it through the hatch, only for bays with a waste tray under the chute
+    Discard,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StagingConfig {
+    pub strategy: StagingStrategy,
+    //Food safety limit for a staged portion
+    #[serde(with = "duration_serde")]
+    pub hold_time: Duration,
+    pub expired_action: ExpiredAction,
+}
+
+impl Default for StagingConfig {
+    fn default() -> Self {
+        Self {
+            strategy: StagingStrategy::KeepStaged,
+            hold_time: Duration::from_secs(20 * 60),
+            expired_action: ExpiredAction::Flag,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CleaningConfig {
+    //Shown to the operator in order, each one confirmed before the next
+    pub steps: Vec<String>,
+    //How far one jog runs the conveyor while rinsing
+    pub jog_distance: f64,
+}
+
+impl Default for CleaningConfig {
+    fn default() -> Self {
+        Self {
+            steps: vec![
+                "Remove the hopper".to_string(),
+                "Wash the chute".to_string(),
+                "Reinstall the hopper".to_string(),
+            ],
+            jog_distance: 10.,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SanitationConfig {
+    //Counted from the last completed cleaning
+    #[serde(with = "duration_serde")]
+    pub interval: Duration,
+    //Also due after this many bowls, if set
+    pub max_bowls: Option<i64>,
+    //How long cleaning can be due before it counts as overdue
+    #[serde(with = "duration_serde")]
+    pub grace_period: Duration,
+    pub block_when_overdue: bool,
+}
+
+impl Default for SanitationConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(4 * 60 * 60),
+            max_bowls: None,
+            grace_period: Duration::from_secs(30 * 60),
+            block_when_overdue: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OrderApiConfig {
+    pub enabled: bool,
+    //Loopback by default, open it up only on a trusted store network
+    pub address: [u8; 4],
+    pub port: u16,
+}
+
+impl Default for OrderApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: [127, 0, 0, 1],
+            port: 8080,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoggingConfig {
+    //error, warn, info, debug or trace
+    pub level: String,
+    //Overrides for a module and everything under it, e.g. "ichibu_lib::hatch" = "debug"
+    pub modules: BTreeMap<String, String>,
+    pub max_file_size_kb: u64,
+    //Rotated files kept besides the current one
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            modules: BTreeMap::new(),
+            max_file_size_kb: 10 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn dir() -> String {
+        format!("{}/logs", config_dir())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub address: [u8; 4],
+    pub port: u16,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: [127, 0, 0, 1],
+            port: 9184,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TelemetryTopics {
+    pub state: String,
+    pub dispense: String,
+    pub run_out: String,
+    pub fault: String,
+    pub heartbeat: String,
+}
+
+impl Default for TelemetryTopics {
+    fn default() -> Self {
+        Self {
+            state: "state".to_string(),
+            dispense: "dispense".to_string(),
+            run_out: "run_out".to_string(),
+            fault: "fault".to_string(),
+            heartbeat: "heartbeat".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    //Published to <prefix>/<node>/<topic>
+    pub topic_prefix: String,
+    pub topics: TelemetryTopics,
+    //0 at most once, 1 at least once, 2 exactly once
+    pub qos: u8,
+    #[serde(with = "duration_serde")]
+    pub heartbeat: Duration,
+    //Kept on disk while the broker is unreachable, newer messages are dropped past this
+    pub max_buffered: usize,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            topic_prefix: "ichibu".to_string(),
+            topics: TelemetryTopics::default(),
+            qos: 1,
+            heartbeat: Duration::from_secs(30),
+            max_buffered: 10_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DaemonConfig {
+    //Where headless mode serves the command API, open it up for UIs on other nodes
+    pub address: [u8; 4],
+    pub port: u16,
+    //Required as `Authorization: Bearer <token>` on every request. The API won't serve
+    //anywhere but loopback without one. Every UI holding the token shares the node's one
+    //kiosk session, so a PIN logged in from any of them is logged in for all of them.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl DaemonConfig {
+    pub fn is_loopback(&self) -> bool {
+        self.address[0] == 127
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            address: [127, 0, 0, 1],
+            port: 8090,
+            token: None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub phidget: PhidgetConfig,
@@ -65,26 +613,126 @@ pub struct Config {
     pub addresses: Addresses,
     pub dispense: DispenseConfig,
     pub setpoint: SetpointConfig,
-    pub pins: Pins,
+    #[serde(default)]
+    pub pins: Option<Pins>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub scale_health: ScaleHealthConfig,
+    #[serde(default)]
+    pub lights: LightsConfig,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
+    #[serde(default)]
+    pub staging: StagingConfig,
+    #[serde(default)]
+    pub cleaning: CleaningConfig,
+    #[serde(default)]
+    pub sanitation: SanitationConfig,
+    #[serde(default)]
+    pub order_api: OrderApiConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
-impl Config {
+pub fn config_dir() -> String {
+    let home_dir = env::var_os("HOME")
+        .expect("Fatal, no home directory found")
+        .into_string()
+        .unwrap();
+    format!("{}/.config/ichibu", home_dir)
+}
+
+// A node keeps its controls config, saved state and data in its own directory. A single kiosk
+// is the one node in ~/.config/ichibu, fleet nodes each have a directory listed in fleet.toml.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeConfig {
+    pub name: String,
+    //Relative to ~/.config/ichibu
+    pub dir: String,
+}
+
+impl NodeConfig {
+    pub fn local() -> Self {
+        Self {
+            name: "ichibu".to_string(),
+            dir: ".".to_string(),
+        }
+    }
+
+    pub fn path(&self) -> String {
+        format!("{}/{}", config_dir(), self.dir)
+    }
+}
+
+// fleet.toml in ~/.config/ichibu, the nodes of a snack station run from one process
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FleetConfig {
+    pub nodes: Vec<NodeConfig>,
+    //The combined API, order routes included
+    #[serde(default)]
+    pub api: DaemonConfig,
+    //The nodes' own metrics and logging sections are ignored, these cover the whole process
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+impl FleetConfig {
     pub fn load() -> Self {
-        const PATH: &str = ".config/ichibu/controls_config.toml";
-        let home_dir = env::var_os("HOME")
-            .expect("Fatal, no home directory found")
-            .into_string()
-            .unwrap();
+        let path = format!("{}/fleet.toml", config_dir());
+        let fleet_text = fs::read_to_string(path).unwrap();
+        toml::from_str(&fleet_text).expect("No fleet file loaded")
+    }
+}
 
-        let path = format!("{}/{}", &*home_dir, PATH);
+impl Config {
+    fn path(dir: &str) -> String {
+        const FILE: &str = "controls_config.toml";
+        format!("{}/{}", dir, FILE)
+    }
 
-        let config_text = fs::read_to_string(path).unwrap();
+    pub fn load() -> Self {
+        Self::load_from(&NodeConfig::local().path())
+    }
+
+    pub fn load_from(dir: &str) -> Self {
+        let config_text = fs::read_to_string(Self::path(dir)).unwrap();
         let config: Config = toml::from_str(&config_text).expect("No config file loaded");
         config
     }
+
+    // Edits one section in place, the rest of the file and its comments stay as written
+    pub fn update_section(
+        dir: &str,
+        section: &str,
+        edit: impl FnOnce(&mut dyn TableLike) -> Result<(), toml_edit::ser::Error>,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = Self::path(dir);
+        let mut document: Document = fs::read_to_string(&path)?.parse()?;
+        let table = document
+            .entry(section)
+            .or_insert(toml_edit::table())
+            .as_table_like_mut()
+            .ok_or(format!("[{}] is not a table", section))?;
+        edit(table)?;
+        fs::write(path, document.to_string())?;
+        Ok(())
+    }
+}
+
+pub fn toml_item<T: Serialize>(value: &T) -> Result<Item, toml_edit::ser::Error> {
+    Ok(Item::Value(value.serialize(ValueSerializer::new())?))
 }
 
-mod duration_serde {
+pub(crate) mod duration_serde {
     use serde::{self, Deserialize, Deserializer, Serializer};
     use std::time::Duration;
 
@@ -103,3 +751,26 @@ mod duration_serde {
         Ok(Duration::from_millis(millis))
     }
 }
+
+#[test]
+fn test_update_section_keeps_the_rest_of_the_file() {
+    let dir = env::temp_dir().join("ichibu_config_edit_test");
+    fs::create_dir_all(&dir).unwrap();
+    let dir = dir.to_str().unwrap();
+    let original = "# tuned on site\n[phidget]\nsn = 1234\ncoefficients = [1.0, 1.0, 1.0, 1.0]\n";
+    fs::write(Config::path(dir), original).unwrap();
+
+    Config::update_section(dir, "phidget", |phidget| {
+        phidget.insert("coefficients", toml_item(&[0.5, 0.25, 2., 1.])?);
+        Ok(())
+    })
+    .unwrap();
+
+    let text = fs::read_to_string(Config::path(dir)).unwrap();
+    assert!(text.starts_with("# tuned on site\n[phidget]\nsn = 1234\n"));
+    let edited: toml::Value = toml::from_str(&text).unwrap();
+    assert_eq!(
+        edited["phidget"]["coefficients"],
+        toml::Value::try_from([0.5, 0.25, 2., 1.]).unwrap()
+    );
+}
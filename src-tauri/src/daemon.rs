@@ -0,0 +1,410 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use control_components::components::clear_core_io::DigitalInput;
+use futures_util::StreamExt;
+use libra::scale::{self, ConnectedScale};
+use serde_json::Value;
+use tauri::async_runtime::JoinHandle;
+use tauri::AppHandle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+use crate::alerts::{run_alerts, Buzzer};
+use crate::bowl::run_bowl_detector;
+use crate::commands::{self, COMMANDS};
+use crate::config::{Config, DaemonConfig, LoggingConfig, NodeConfig, PhidgetConfig};
+use crate::events::{EventSink, UiEvent};
+use crate::ichibu::ichibu_cycle;
+use crate::io::initialize_controller;
+use crate::lights::{run_lights, Lights};
+use crate::logging;
+use crate::metrics;
+use crate::order_api;
+use crate::progress::ProgressEmitter;
+use crate::session::CommandError;
+use crate::shutdown::{self, request_shutdown, ShutdownReason};
+use crate::telemetry;
+use crate::state::{persist_state, AppData, SharedState};
+
+// Everything the controls need from the node's hardware
+pub struct Hardware {
+    photo_eye: DigitalInput,
+    lights: Lights,
+    buzzer: Option<Buzzer>,
+    scale: ConnectedScale,
+}
+
+impl Hardware {
+    pub fn connect(config: &Config) -> Self {
+        let controller = initialize_controller(config);
+        let photo_eye = controller.get_digital_input(config.photo_eye.input_id);
+        let lights = Lights::new(controller.clone(), &config.lights);
+        let buzzer = config
+            .alerts
+            .buzzer
+            .as_ref()
+            .map(|buzzer| Buzzer::new(&controller, buzzer));
+        let scale = connect_scale(&config.phidget).expect("Couldn't connect scale!");
+        Self {
+            photo_eye,
+            lights,
+            buzzer,
+            scale,
+        }
+    }
+}
+
+// Also gets the scale back after a failed dispense dropped it
+pub fn connect_scale(config: &PhidgetConfig) -> Result<ConnectedScale, scale::Error> {
+    let mut scale = scale::DisconnectedScale::new(config.sn).connect(
+        0.,
+        config.coefficients,
+        Duration::from_secs(10),
+    )?;
+    scale.set_data_intervals(Duration::from_millis(40))?;
+    Ok(scale)
+}
+
+// Starts the tasks that drive the hardware, the same whether the kiosk app or the daemon owns
+// them. The returned task finishes once a shutdown was requested and the hardware is parked.
+pub fn spawn_controls(
+    config: &Config,
+    state: SharedState,
+    hardware: Hardware,
+    events: EventSink,
+) -> JoinHandle<()> {
+    let Hardware {
+        photo_eye,
+        lights,
+        buzzer,
+        scale,
+    } = hardware;
+
+    tauri::async_runtime::spawn({
+        let state = state.clone();
+        let lights = lights.clone();
+        let config = config.lights.clone();
+        async move { run_lights(&state, lights, config).await }
+    });
+    tauri::async_runtime::spawn({
+        let state = state.clone();
+        let events = events.clone();
+        async move { run_alerts(&state, events, buzzer).await }
+    });
+    telemetry::spawn(&state, &config.telemetry);
+    tauri::async_runtime::spawn({
+        let state = state.clone();
+        let config = config.photo_eye.clone();
+        async move { run_bowl_detector(&state, photo_eye, config).await }
+    });
+    tauri::async_runtime::spawn({
+        let state = state.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(500));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                persist_state(&state);
+                interval.tick().await;
+            }
+        }
+    });
+
+    let cycle = tauri::async_runtime::spawn({
+        let state = state.clone();
+        let timeout = config.dispense.timeout;
+        let phidget = config.phidget.clone();
+        async move {
+            let node = state.lock().unwrap().node.clone();
+            let progress = ProgressEmitter::new(events, node.clone(), timeout, phidget);
+            logging::with_context(node, ichibu_cycle(&state, scale, progress)).await;
+            //Only returns once a shutdown was requested and the hardware is parked
+            let mut lights = lights;
+            lights.turn_off().await;
+            shutdown::finish(&state);
+        }
+    });
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = cycle.await {
+            log::error!("Controls stopped unexpectedly: {}", e);
+            state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .controls_stopped = true;
+        }
+    })
+}
+
+//A single node serves its own orders, a fleet serves them from the combined API
+pub fn spawn_order_api(config: &Config, state: &SharedState) {
+    if config.order_api.enabled {
+        let orders = state.lock().unwrap().orders.clone();
+        tauri::async_runtime::spawn(order_api::serve(config.order_api.clone(), orders));
+    }
+}
+
+// Runs the controls without a webview, for nodes without a display. UIs connect to the
+// daemon API instead.
+pub fn run_headless() {
+    let config = Config::load();
+    logging::init(&config.logging, &LoggingConfig::dir());
+    let state: SharedState = Arc::new(Mutex::new(AppData::new(&config, &NodeConfig::local())));
+    let events = EventSink::channel();
+    tauri::async_runtime::block_on(async move {
+        let hardware = Hardware::connect(&config);
+        let controls = spawn_controls(&config, state.clone(), hardware, events.clone());
+        spawn_order_api(&config, &state);
+        metrics::spawn(&config.metrics);
+        tauri::async_runtime::spawn(serve(config.daemon.clone(), state.clone(), events));
+        tauri::async_runtime::spawn({
+            let state = state.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    request_shutdown(&mut state.lock().unwrap(), ShutdownReason::Signal);
+                }
+            }
+        });
+        if let Err(e) = controls.await {
+            log::error!("Controls stopped unexpectedly: {}", e);
+        }
+    });
+}
+
+#[derive(Clone)]
+struct DaemonApi {
+    state: SharedState,
+    events: EventSink,
+}
+
+pub fn router(state: SharedState, events: EventSink, token: Option<String>) -> Router {
+    let router = Router::new()
+        .route("/commands/:command", post(run_command))
+        .route("/events", get(event_stream))
+        .with_state(DaemonApi { state, events });
+    require_token(router, token)
+}
+
+// Rejects requests without the configured bearer token, a router without a token is left open
+pub(crate) fn require_token(router: Router, token: Option<String>) -> Router {
+    match token {
+        Some(token) => router.layer(middleware::from_fn_with_state(token, check_token)),
+        None => router,
+    }
+}
+
+async fn check_token(State(token): State<String>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented.is_some_and(|presented| tokens_match(presented, &token)) {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+// Compares every byte so the time taken doesn't tell how much of the token was right
+fn tokens_match(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+//The command API can log in and run the hardware, so it is only opened up with a token
+pub(crate) fn refuses_to_serve(config: &DaemonConfig) -> bool {
+    if config.is_loopback() || config.token.is_some() {
+        return false;
+    }
+    log::error!(
+        "Not serving the command API on {:?} without a token, set [daemon].token",
+        config.address
+    );
+    true
+}
+
+pub async fn serve(config: DaemonConfig, state: SharedState, events: EventSink) {
+    if refuses_to_serve(&config) {
+        return;
+    }
+    let address = SocketAddr::from((config.address, config.port));
+    let listener = match tokio::net::TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Daemon API couldn't bind {}: {}", address, e);
+            return;
+        }
+    };
+    log::info!("Daemon API listening on {}", address);
+    if let Err(e) = axum::serve(listener, router(state, events, config.token)).await {
+        log::error!("Daemon API stopped: {}", e);
+    }
+}
+
+//Same commands and arguments the webview invokes, the body is the invoke args
+async fn run_command(
+    State(api): State<DaemonApi>,
+    Path(command): Path<String>,
+    Json(args): Json<Value>,
+) -> Response {
+    command_response(&api.state, &command, args)
+}
+
+pub(crate) fn command_response(state: &Mutex<AppData>, command: &str, args: Value) -> Response {
+    if !COMMANDS.contains(&command) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match commands::invoke(state, command, args) {
+        Ok(value) => Json(value).into_response(),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, Json(e)).into_response(),
+    }
+}
+
+async fn event_stream(State(api): State<DaemonApi>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| stream_events(socket, api.events))
+}
+
+pub(crate) async fn stream_events(mut socket: WebSocket, events: EventSink) {
+    let Some(mut events) = events.subscribe() else {
+        return;
+    };
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("Event websocket missed {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Ok(text) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+}
+
+// How the UI talks to a daemon on another node
+#[derive(Clone)]
+pub struct DaemonClient {
+    url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl DaemonClient {
+    pub fn new(url: &str, token: Option<String>) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn invoke(&self, command: &str, args: Value) -> Result<Value, CommandError> {
+        let unreachable = |e: reqwest::Error| CommandError::Failed(format!("Daemon error: {}", e));
+        let mut request = self
+            .http
+            .post(format!("{}/commands/{}", self.url, command))
+            .json(&args);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(unreachable)?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(CommandError::Failed(
+                "Daemon rejected the token".to_string(),
+            ));
+        }
+        if response.status().is_success() {
+            return response.json().await.map_err(unreachable);
+        }
+        match response.json::<CommandError>().await {
+            Ok(e) => Err(e),
+            Err(e) => Err(unreachable(e)),
+        }
+    }
+
+    //Re-emits the daemon's UI events in this app's webview, reconnecting if the daemon restarts
+    pub async fn relay_events(self, app_handle: AppHandle) {
+        let events = EventSink::Tauri(app_handle);
+        let url = format!("{}/events", self.url.replacen("http", "ws", 1));
+        loop {
+            let mut request = url.as_str().into_client_request().expect("Bad daemon url");
+            if let Some(token) = &self.token {
+                let bearer = format!("Bearer {}", token)
+                    .parse()
+                    .expect("Bad daemon token");
+                request.headers_mut().insert(AUTHORIZATION, bearer);
+            }
+            match tokio_tungstenite::connect_async(request).await {
+                Ok((mut socket, _)) => {
+                    log::info!("Connected to daemon events at {}", url);
+                    while let Some(Ok(message)) = socket.next().await {
+                        let Ok(text) = message.to_text() else {
+                            continue;
+                        };
+                        match serde_json::from_str::<UiEvent>(text) {
+                            Ok(event) => events.emit(&event.event, event.payload),
+                            Err(e) => log::warn!("Unreadable daemon event: {}", e),
+                        }
+                    }
+                    log::warn!("Lost daemon events, reconnecting");
+                }
+                Err(e) => log::warn!("Couldn't reach daemon events: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_token_required() {
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    let app = require_token(
+        Router::new().route("/commands/get_state", post(|| async { "Ready" })),
+        Some("secret".to_string()),
+    );
+    let send = |authorization: Option<&str>| {
+        let mut request = Request::builder().method("POST").uri("/commands/get_state");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+    assert_eq!(send(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        send(Some("Bearer secre")).await.unwrap().status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        send(Some("Bearer secret")).await.unwrap().status(),
+        StatusCode::OK
+    );
+
+    let mut exposed = DaemonConfig {
+        address: [0, 0, 0, 0],
+        ..Default::default()
+    };
+    assert!(refuses_to_serve(&exposed));
+    exposed.token = Some("secret".to_string());
+    assert!(!refuses_to_serve(&exposed));
+    assert!(!refuses_to_serve(&DaemonConfig::default()));
+}
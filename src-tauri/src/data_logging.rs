@@ -1,14 +1,44 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
 use rusqlite::{params, Connection};
 use std::error::Error;
 
-#[derive(Debug)]
+use crate::sanitation::CleaningEvent;
+use crate::shutdown::ShutdownReason;
+use crate::trace::{CycleTrace, TraceSummary};
+
+const TRACE_RETENTION: i64 = 10_000;
+//Logged with Utc::now().to_string()
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(timestamp.trim_end_matches(" UTC"), "%Y-%m-%d %H:%M:%S%.f")
+        .ok()
+        .map(|timestamp| timestamp.and_utc())
+}
+
+//dispense_logs rows that served a bowl
+const SERVED: &str =
+    "(data IN ('Dispensed', 'DispensedSmall', 'DispensedRegular') OR data LIKE 'DispensedSize%')";
+
+#[derive(Debug, PartialEq)]
 pub enum DataAction {
     DispensedSmall,
     DispensedRegular,
+    DispensedSize(String),
     Cleaning,
     Emptying,
     RanOut,
     Refilled,
+    Calibrated,
+    ScaleFault,
+    Shutdown(ShutdownReason),
+    Interrupted,
+    HoldExpired,
+    Discarded,
+    BowlPlaced,
+    //Taken away without being served
+    BowlRemoved,
+    //From the portion dropping into the bowl to the bowl leaving
+    BowlPickedUp { waited_ms: u64 },
+    CleaningCompleted { user: String, duration_s: u64 },
 }
 
 pub struct Data {
@@ -31,6 +61,27 @@ impl Data {
             [],
         )?;
 
+        self.database.execute(
+            "CREATE TABLE IF NOT EXISTS cycle_traces (
+            id INTEGER PRIMARY KEY,
+            started TEXT NOT NULL,
+            ingredient TEXT,
+            points TEXT NOT NULL
+        )",
+            [],
+        )?;
+
+        self.database.execute(
+            "CREATE TABLE IF NOT EXISTS cleaning_logs (
+            id INTEGER PRIMARY KEY,
+            timestamp TEXT NOT NULL,
+            user TEXT NOT NULL,
+            duration_s INTEGER NOT NULL,
+            bowl_count INTEGER NOT NULL
+        )",
+            [],
+        )?;
+
         // Count the number of rows in the table
         let row_count: i64 =
             self.database
@@ -42,11 +93,120 @@ impl Data {
     pub fn get_bowl_count(&self) -> Result<i64, Box<dyn Error + Send + Sync>> {
         let row_count: i64 =
             self.database
-                .query_row("SELECT COUNT(*) FROM dispense_logs WHERE data IN ('Dispensed', 'DispensedSmall', 'DispensedRegular')", [], |row| row.get(0))?;
+                .query_row(&format!("SELECT COUNT(*) FROM dispense_logs WHERE {}", SERVED), [], |row| row.get(0))?;
 
         Ok(row_count)
     }
 
+    //When the nth bowl the node ever served was logged, counting from 1
+    pub fn bowl_time(&self, n: i64) -> Option<DateTime<Utc>> {
+        let timestamp: String = self
+            .database
+            .query_row(
+                &format!(
+                    "SELECT timestamp FROM dispense_logs WHERE {} ORDER BY id LIMIT 1 OFFSET ?1",
+                    SERVED
+                ),
+                params![n - 1],
+                |row| row.get(0),
+            )
+            .ok()?;
+        parse_timestamp(&timestamp)
+    }
+
+    //When the node logged anything for the first time
+    pub fn first_log_time(&self) -> Option<DateTime<Utc>> {
+        let timestamp: String = self
+            .database
+            .query_row(
+                "SELECT timestamp FROM dispense_logs ORDER BY id LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .ok()?;
+        parse_timestamp(&timestamp)
+    }
+
+    //Returns the trace's id, older traces past the retention are dropped
+    pub fn save_trace(&self, trace: &CycleTrace) -> Result<i64, Box<dyn Error + Send + Sync>> {
+        let points = serde_json::to_string(&trace.points)?;
+        self.database.execute(
+            "INSERT INTO cycle_traces (started, ingredient, points) VALUES (?1, ?2, ?3)",
+            params![trace.started, trace.ingredient, points],
+        )?;
+        let id = self.database.last_insert_rowid();
+        self.database.execute(
+            "DELETE FROM cycle_traces WHERE id <= ?1",
+            params![id - TRACE_RETENTION],
+        )?;
+        Ok(id)
+    }
+
+    pub fn load_trace(&self, id: i64) -> Option<CycleTrace> {
+        let trace = self.database.query_row(
+            "SELECT id, started, ingredient, points FROM cycle_traces WHERE id = ?1",
+            params![id],
+            |row| {
+                let points: String = row.get(3)?;
+                Ok(CycleTrace {
+                    id: row.get(0)?,
+                    started: row.get(1)?,
+                    ingredient: row.get(2)?,
+                    points: serde_json::from_str(&points).unwrap_or_default(),
+                })
+            },
+        );
+        trace.ok()
+    }
+
+    //Newest first
+    pub fn list_traces(&self, limit: u32) -> rusqlite::Result<Vec<TraceSummary>> {
+        let mut statement = self.database.prepare(
+            "SELECT id, started, ingredient FROM cycle_traces ORDER BY id DESC LIMIT ?1",
+        )?;
+        let traces = statement.query_map(params![limit], |row| {
+            Ok(TraceSummary {
+                id: row.get(0)?,
+                started: row.get(1)?,
+                ingredient: row.get(2)?,
+            })
+        })?;
+        traces.collect()
+    }
+
+    pub fn save_cleaning(&self, cleaning: &CleaningEvent) -> rusqlite::Result<()> {
+        self.database.execute(
+            "INSERT INTO cleaning_logs (timestamp, user, duration_s, bowl_count) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                cleaning.timestamp,
+                cleaning.user,
+                cleaning.duration_s,
+                cleaning.bowl_count
+            ],
+        )?;
+        Ok(())
+    }
+
+    //Newest first
+    pub fn list_cleanings(&self, limit: u32) -> rusqlite::Result<Vec<CleaningEvent>> {
+        let mut statement = self.database.prepare(
+            "SELECT timestamp, user, duration_s, bowl_count FROM cleaning_logs ORDER BY id DESC LIMIT ?1",
+        )?;
+        let cleanings = statement.query_map(params![limit], |row| {
+            Ok(CleaningEvent {
+                timestamp: row.get(0)?,
+                user: row.get(1)?,
+                duration_s: row.get(2)?,
+                bowl_count: row.get(3)?,
+            })
+        })?;
+        cleanings.collect()
+    }
+
+    pub fn flush(&self) -> rusqlite::Result<()> {
+        self.database.cache_flush()
+    }
+
     pub fn log(
         &self,
         action: &DataAction,
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::broadcast;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UiEvent {
+    pub event: String,
+    pub payload: serde_json::Value,
+}
+
+// Where the controls send UI events: straight to the webview when the kiosk app runs the
+// hardware, or out the daemon API's websocket when running headless
+#[derive(Clone)]
+pub enum EventSink {
+    Tauri(AppHandle),
+    Channel(broadcast::Sender<UiEvent>),
+}
+
+impl EventSink {
+    pub fn channel() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        EventSink::Channel(sender)
+    }
+
+    pub fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) {
+        match self {
+            EventSink::Tauri(app_handle) => {
+                if let Err(e) = app_handle.emit(event, payload) {
+                    log::warn!("Failed to emit {}: {:?}", event, e);
+                }
+            }
+            EventSink::Channel(sender) => match serde_json::to_value(payload) {
+                Ok(payload) => {
+                    //No clients connected is fine
+                    let _ = sender.send(UiEvent {
+                        event: event.to_string(),
+                        payload,
+                    });
+                }
+                Err(e) => log::warn!("Failed to serialize {}: {}", event, e),
+            },
+        }
+    }
+
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<UiEvent>> {
+        match self {
+            EventSink::Tauri(_) => None,
+            EventSink::Channel(sender) => Some(sender.subscribe()),
+        }
+    }
+}
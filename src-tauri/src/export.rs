@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::HOME_DIRECTORY;
+
+// Where support exports are written, the first USB drive the desktop mounted, else ~/Downloads
+fn export_dir() -> PathBuf {
+    let user = std::env::var("USER").unwrap_or_default();
+    let mounts = [
+        Path::new("/media").join(&user),
+        Path::new("/run/media").join(&user),
+    ];
+    let usb = mounts
+        .iter()
+        .filter_map(|mounts| fs::read_dir(mounts).ok())
+        .flat_map(|entries| entries.flatten())
+        .map(|entry| entry.path())
+        .find(|path| path.is_dir());
+    usb.unwrap_or_else(|| Path::new(HOME_DIRECTORY.as_str()).join("Downloads"))
+}
+
+fn write_export(dir: &Path, file_name: &str, contents: &str) -> Result<PathBuf, String> {
+    // Only plain file names, nothing outside the export directory
+    if file_name.is_empty() || file_name.contains(['/', '\\']) || file_name.contains("..") {
+        return Err(format!("Bad export file name {:?}", file_name));
+    }
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let path = dir.join(file_name);
+    fs::write(&path, contents).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+// The webview can't download files, so exports are saved here and the path shown to staff
+#[tauri::command]
+pub fn save_export(file_name: String, contents: String) -> Result<String, String> {
+    let path = write_export(&export_dir(), &file_name, &contents)?;
+    log::info!("Exported {}", path.display());
+    Ok(path.display().to_string())
+}
+
+#[test]
+fn test_write_export() {
+    let dir = std::env::temp_dir().join("ichibu_export_test");
+    let path = write_export(&dir, "logs.jsonl", "{}\n").unwrap();
+    assert_eq!(fs::read_to_string(path).unwrap(), "{}\n");
+    assert!(write_export(&dir, "../logs.jsonl", "").is_err());
+    assert!(write_export(&dir, "", "").is_err());
+}
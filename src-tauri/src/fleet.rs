@@ -0,0 +1,423 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+use serde_json::Value;
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Manager};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::accounts::Role;
+use crate::alerts::ALERT_EVENT;
+use crate::config::{Config, DaemonConfig, FleetConfig};
+use crate::daemon::{
+    command_response, refuses_to_serve, require_token, spawn_controls, stream_events, Hardware,
+};
+use crate::events::EventSink;
+use crate::order_api::{self, OrderApi};
+use crate::orders::OrderBook;
+use crate::session::CommandError;
+use crate::shutdown::{holds_exit, request_shutdown, ShutdownReason};
+use crate::state::{AppData, IchibuState, SharedState};
+
+// One dispenser of the fleet, with its own controls, state and events
+#[derive(Clone)]
+pub struct Node {
+    pub name: String,
+    pub state: SharedState,
+    pub events: EventSink,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NodeStatus {
+    pub name: String,
+    pub snack: Option<String>,
+    pub run_state: IchibuState,
+    pub busy: bool,
+    pub timed_out: bool,
+    pub alerts: usize,
+}
+
+impl NodeStatus {
+    fn new(name: &str, state: &AppData) -> Self {
+        Self {
+            name: name.to_string(),
+            snack: state.get_snack().map(|snack| snack.name.clone()),
+            run_state: state.get_state(),
+            busy: state.dispenser_is_busy(),
+            timed_out: state.dispenser_has_timed_out,
+            alerts: state.alerts.active().len(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FleetView {
+    pub nodes: Vec<NodeStatus>,
+    //The node the UI's commands go to
+    pub selected: String,
+}
+
+// Several nodes run from one process. They share one order queue, each order goes to whichever
+// node has its snack loaded.
+#[derive(Clone)]
+pub struct Fleet {
+    nodes: Arc<Vec<Node>>,
+    pub orders: OrderBook,
+    selected: Arc<Mutex<usize>>,
+}
+
+impl Fleet {
+    //Returns the fleet and each node's controls, which finish once that node is parked or died
+    pub fn start(config: &FleetConfig) -> (Self, Vec<JoinHandle<()>>) {
+        assert!(!config.nodes.is_empty(), "Fleet has no nodes");
+        let orders = OrderBook::default();
+        let mut nodes = Vec::new();
+        let mut controls = Vec::new();
+        for node in &config.nodes {
+            let node_config = Config::load_from(&node.path());
+            let mut app_data = AppData::new(&node_config, node);
+            app_data.share_orders(orders.clone());
+            let state: SharedState = Arc::new(Mutex::new(app_data));
+            let events = EventSink::channel();
+            let hardware = Hardware::connect(&node_config);
+            log::info!("Starting fleet node {}", node.name);
+            controls.push(spawn_controls(
+                &node_config,
+                state.clone(),
+                hardware,
+                events.clone(),
+            ));
+            nodes.push(Node {
+                name: node.name.clone(),
+                state,
+                events,
+            });
+        }
+        let fleet = Self::new(nodes, orders);
+        tauri::async_runtime::spawn(fleet.clone().share_shutdown());
+        (fleet, controls)
+    }
+
+    //The nodes' states should already share the orders
+    fn new(nodes: Vec<Node>, orders: OrderBook) -> Self {
+        Self {
+            nodes: Arc::new(nodes),
+            orders,
+            selected: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.name == name)
+    }
+
+    pub fn selected(&self) -> &Node {
+        &self.nodes[*self.selected.lock().unwrap()]
+    }
+
+    pub fn select(&self, name: &str) -> bool {
+        let Some(index) = self.nodes.iter().position(|node| node.name == name) else {
+            return false;
+        };
+        *self.selected.lock().unwrap() = index;
+        log::info!("UI switched to node {}", name);
+        true
+    }
+
+    pub fn view(&self) -> FleetView {
+        FleetView {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|node| NodeStatus::new(&node.name, &node.state.lock().unwrap()))
+                .collect(),
+            selected: self.selected().name.clone(),
+        }
+    }
+
+    //Exiting from any node's UI or a ctrl-c parks the whole fleet
+    async fn share_shutdown(self) {
+        let mut interval = tokio::time::interval(Duration::from_millis(500));
+        loop {
+            interval.tick().await;
+            let reason = self
+                .nodes
+                .iter()
+                .find_map(|node| node.state.lock().unwrap().shutdown_request.clone());
+            if let Some(reason) = reason {
+                self.request_shutdown(reason);
+                return;
+            }
+        }
+    }
+
+    pub fn request_shutdown(&self, reason: ShutdownReason) {
+        for node in self.nodes.iter() {
+            request_shutdown(&mut node.state.lock().unwrap(), reason.clone());
+        }
+    }
+
+    pub fn holds_exit(&self) -> bool {
+        self.nodes
+            .iter()
+            .any(|node| holds_exit(&node.state.lock().unwrap_or_else(PoisonError::into_inner)))
+    }
+
+    // The webview shows the selected node, but alerts from every node still sound
+    pub fn relay_events(&self, app_handle: AppHandle) {
+        let ui = EventSink::Tauri(app_handle);
+        for (index, node) in self.nodes.iter().enumerate() {
+            let Some(mut events) = node.events.subscribe() else {
+                continue;
+            };
+            let ui = ui.clone();
+            let selected = self.selected.clone();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    let event = match events.recv().await {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return,
+                    };
+                    if event.event == ALERT_EVENT || *selected.lock().unwrap() == index {
+                        ui.emit(&event.event, event.payload);
+                    }
+                }
+            });
+        }
+    }
+}
+
+pub fn router(fleet: Fleet, token: Option<String>) -> Router {
+    let orders = OrderApi::load(fleet.orders.clone());
+    routes(fleet, orders, token)
+}
+
+//The token guards the node routes, the order routes stay as the POS expects them
+fn routes(fleet: Fleet, orders: OrderApi, token: Option<String>) -> Router {
+    let orders = order_api::router(orders);
+    let nodes = Router::new()
+        .route("/nodes", get(list_nodes))
+        .route("/nodes/:node/commands/:command", post(run_command))
+        .route("/nodes/:node/events", get(event_stream))
+        .with_state(fleet);
+    require_token(nodes, token).merge(orders)
+}
+
+// The combined API, the daemon API per node plus the shared order routes
+pub async fn serve(config: DaemonConfig, fleet: Fleet) {
+    if refuses_to_serve(&config) {
+        return;
+    }
+    let address = SocketAddr::from((config.address, config.port));
+    let listener = match tokio::net::TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Fleet API couldn't bind {}: {}", address, e);
+            return;
+        }
+    };
+    log::info!("Fleet API listening on {}", address);
+    if let Err(e) = axum::serve(listener, router(fleet, config.token)).await {
+        log::error!("Fleet API stopped: {}", e);
+    }
+}
+
+async fn list_nodes(State(fleet): State<Fleet>) -> Json<FleetView> {
+    Json(fleet.view())
+}
+
+async fn run_command(
+    State(fleet): State<Fleet>,
+    Path((node, command)): Path<(String, String)>,
+    Json(args): Json<Value>,
+) -> Response {
+    match fleet.node(&node) {
+        Some(node) => command_response(&node.state, &command, args),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn event_stream(
+    State(fleet): State<Fleet>,
+    Path(node): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    match fleet.node(&node) {
+        Some(node) => {
+            let events = node.events.clone();
+            ws.on_upgrade(move |socket| stream_events(socket, events))
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[tauri::command]
+pub fn get_fleet(app: AppHandle) -> Option<FleetView> {
+    app.try_state::<Fleet>().map(|fleet| fleet.view())
+}
+
+//Switching nodes takes whoever is logged in at the node on screen
+#[tauri::command]
+pub fn select_node(app: AppHandle, name: String) -> Result<bool, CommandError> {
+    let Some(fleet) = app.try_state::<Fleet>() else {
+        return Ok(false);
+    };
+    fleet
+        .selected()
+        .state
+        .lock()
+        .unwrap()
+        .authorize(Role::Operator)?;
+    Ok(fleet.select(&name))
+}
+
+#[tokio::test]
+async fn test_fleet_routes_orders_to_the_loaded_node() {
+    use crate::ingredients::Ingredient;
+    use crate::io::PhotoEyeState;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    async fn send(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(body.map_or(Body::empty(), |body| Body::from(body.to_string())))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    let config: Config = toml::from_str(
+        r#"
+        [phidget]
+        sn = 1
+        coefficients = [1.0, 1.0, 1.0, 1.0]
+        [hatch]
+        motor_id = 1
+        open_input = 1
+        close_input = 2
+        velocity = 1.0
+        acceleration = 1.0
+        scale = 1
+        [photo_eye]
+        input_id = 3
+        [motor]
+        id = 0
+        scale = 1
+        acceleration = 1.0
+        [addresses]
+        clear_core = "127.0.0.1:8888"
+        addr = [127, 0, 0, 1]
+        port = 8888
+        [dispense]
+        timeout = 10000
+        [setpoint]
+        empty = 0.0
+        filling_threshold = 0.0
+        "#,
+    )
+    .unwrap();
+    let snacks = [
+        Ingredient {
+            id: 1,
+            name: "Edamame".to_string(),
+            ..Default::default()
+        },
+        Ingredient {
+            id: 2,
+            name: "Pretzels".to_string(),
+            ..Default::default()
+        },
+    ];
+    let orders = OrderBook::default();
+    let nodes: Vec<Node> = ["left", "right"]
+        .iter()
+        .zip(snacks.iter())
+        .map(|(name, snack)| {
+            let dir = std::env::temp_dir().join(format!("ichibu_fleet_test_{}", name));
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut app_data = AppData::open(&config, name, dir.to_str().unwrap().to_string());
+            app_data.update_current_snack(snack.clone());
+            app_data.share_orders(orders.clone());
+            Node {
+                name: name.to_string(),
+                state: Arc::new(Mutex::new(app_data)),
+                events: EventSink::channel(),
+            }
+        })
+        .collect();
+    let fleet = Fleet::new(nodes, orders.clone());
+    let app = routes(fleet.clone(), OrderApi::new(orders, snacks.to_vec()), None);
+
+    let (_, view) = send(&app, "GET", "/nodes", None).await;
+    assert_eq!(view["nodes"][0]["snack"], "Edamame");
+    assert_eq!(view["nodes"][1]["snack"], "Pretzels");
+
+    let order = json!({"ingredient_id": 2, "size": "Regular"});
+    let (status, created) = send(&app, "POST", "/orders", Some(order)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let uri = format!("/orders/{}", created["id"]);
+
+    // Both nodes have a bowl waiting, only the one with pretzels loaded takes the order
+    for node in ["left", "right"] {
+        let mut state = fleet.node(node).unwrap().state.lock().unwrap();
+        state.update_pe_state(PhotoEyeState::Blocked);
+    }
+    assert!(!fleet
+        .node("left")
+        .unwrap()
+        .state
+        .lock()
+        .unwrap()
+        .take_next_order());
+    assert!(fleet
+        .node("right")
+        .unwrap()
+        .state
+        .lock()
+        .unwrap()
+        .take_next_order());
+    let (_, dispensing) = send(&app, "GET", &uri, None).await;
+    assert_eq!(dispensing["status"], "Dispensing");
+
+    let (status, busy) = send(
+        &app,
+        "POST",
+        "/nodes/right/commands/dispenser_is_busy",
+        Some(json!({})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(busy, json!(false));
+    let (status, _) = send(
+        &app,
+        "POST",
+        "/nodes/middle/commands/dispenser_is_busy",
+        Some(json!({})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
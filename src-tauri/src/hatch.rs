@@ -1,27 +1,34 @@
 use crate::config::HatchConfig;
-use control_components::components::clear_core_io::DigitalInput;
+use crate::input::FilteredInput;
 use control_components::components::clear_core_motor::ClearCoreMotor;
 use std::time::Duration;
-use tokio::time::{interval, Instant};
+use tokio::time::{timeout_at, Instant};
 
 // TODO: maybe put these in config as well?
 pub const HATCH_TIMEOUT: Duration = Duration::from_secs(6);
 pub const HATCH_STROKE: f64 = 100_000.;
+//After a limit blip the switch is right there, so the hatch only inches on to find it again
+const HATCH_CREEP: f64 = 2_000.;
+const MAX_CREEPS: usize = 5;
 #[derive(Debug)]
 pub enum HatchError {
     Timeout,
 }
 pub struct Hatch {
     motor: ClearCoreMotor,
-    open_input: DigitalInput,
-    close_input: DigitalInput,
+    //Limit switches, the motor stops on the first reading and the filter confirms it
+    open_input: FilteredInput,
+    close_input: FilteredInput,
+    //How long the last open or close took, None if the hatch was already there or stuck
+    pub last_move: Option<Duration>,
 }
 impl Hatch {
-    pub fn new(motor: ClearCoreMotor, open_input: DigitalInput, close_input: DigitalInput) -> Self {
+    pub fn new(motor: ClearCoreMotor, open_input: FilteredInput, close_input: FilteredInput) -> Self {
         Self {
             motor,
             open_input,
             close_input,
+            last_move: None,
         }
     }
     pub async fn setup(&mut self, config: &HatchConfig) {
@@ -31,40 +38,70 @@ impl Hatch {
         self.motor.set_acceleration(config.acceleration).await;
         //self.motor.set_deceleration(config.acceleration).await;
     }
+    //Closed and de-energised, for shutting down
+    pub async fn park(&mut self) -> Result<(), HatchError> {
+        let closed = self.close().await;
+        self.motor.abrupt_stop().await;
+        self.motor.disable().await;
+        closed
+    }
+    pub async fn is_open(&self) -> bool {
+        self.open_input.get_state()
+    }
     pub async fn open(&mut self) -> Result<(), HatchError> {
-        if self.open_input.get_state().await {
+        self.last_move = None;
+        if self.open_input.get_state() {
             return Ok(());
         }
 
         let start_time = Instant::now();
-        let mut interval = interval(Duration::from_millis(100));
-        self.motor.relative_move(-HATCH_STROKE).await.unwrap();
-        while !self.open_input.get_state().await {
-            if Instant::now() - start_time > HATCH_TIMEOUT {
-                self.motor.abrupt_stop().await;
-                return Err(HatchError::Timeout);
-            }
-            interval.tick().await;
-        }
-        self.motor.abrupt_stop().await;
+        move_to_limit(&self.motor, &mut self.open_input, -HATCH_STROKE).await?;
+        self.last_move = Some(start_time.elapsed());
         Ok(())
     }
     pub async fn close(&mut self) -> Result<(), HatchError> {
-        if self.close_input.get_state().await {
+        self.last_move = None;
+        if self.close_input.get_state() {
             return Ok(());
         }
         let start_time = Instant::now();
-        let mut interval = interval(Duration::from_millis(100));
-        let _ = self.motor.relative_move(HATCH_STROKE).await;
-        while !self.close_input.get_state().await {
-            if Instant::now() - start_time > HATCH_TIMEOUT {
-                self.motor.abrupt_stop().await;
-                self.open().await?;
-                return Err(HatchError::Timeout);
-            }
-            interval.tick().await;
+        if let Err(e) = move_to_limit(&self.motor, &mut self.close_input, HATCH_STROKE).await {
+            self.open().await?;
+            self.last_move = None;
+            return Err(e);
         }
-        self.motor.abrupt_stop().await;
+        self.last_move = Some(start_time.elapsed());
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// Stops as soon as the switch reads, so the hatch doesn't overrun it while the filter catches
+// up. A bounce the filter doesn't confirm sends the hatch on a short creep at a time, a switch
+// that still won't hold after a few is reported like a timeout.
+async fn move_to_limit(
+    motor: &ClearCoreMotor,
+    limit: &mut FilteredInput,
+    stroke: f64,
+) -> Result<(), HatchError> {
+    let deadline = Instant::now() + HATCH_TIMEOUT;
+    let creep = HATCH_CREEP.copysign(stroke);
+    let mut distance = stroke;
+    for _ in 0..=MAX_CREEPS {
+        let _ = motor.relative_move(distance).await;
+        let reached = timeout_at(deadline, limit.wait_for_raw(true)).await;
+        motor.abrupt_stop().await;
+        if reached.is_err() {
+            return Err(HatchError::Timeout);
+        }
+        if limit.confirm(true).await {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(HatchError::Timeout);
+        }
+        log::warn!("Hatch limit switch blipped without holding, creeping on");
+        distance = creep;
+    }
+    log::error!("Hatch limit switch never held");
+    Err(HatchError::Timeout)
+}
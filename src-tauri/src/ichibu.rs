@@ -1,21 +1,38 @@
-use control_components::components::clear_core_motor::ClearCoreMotor;
+use control_components::components::clear_core_motor::{ClearCoreMotor, Status};
 use libra::scale::ConnectedScale;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::time::sleep;
 
+use crate::calibration::handle_calibration_request;
+use crate::cleaning::handle_cleaning_request;
 use crate::config::Config;
 use crate::data_logging::DataAction;
-use crate::hatch::Hatch;
-use crate::ingredients::Ingredient;
+use crate::hatch::{Hatch, HatchError};
+use crate::ingredients::{Ingredient, WarmUp};
 use crate::io::{initialize_controller, initialize_hatch, setup_conveyor_motor, PhotoEyeState};
+use crate::portion::{top_up_settings, DispenseType};
+use crate::logging;
+use crate::metrics::METRICS;
+use crate::progress::ProgressEmitter;
+use crate::run_out::{DispenseDecision, RunOutTracker};
+use crate::config::{ExpiredAction, StagingStrategy};
+use crate::scale_health::{ScaleFault, ScaleMonitor};
 use crate::state::{AppData, IchibuState};
-use crate::{UiRequest};
-use node_diagnostics::dispenser;
-use node_diagnostics::dispenser::{DispenseOutcome};
+use crate::telemetry::TelemetryEvent;
+use crate::trace::TraceEvent;
+use crate::UiRequest;
 
-pub async fn ichibu_cycle(state: tauri::State<'_, Mutex<AppData>>, scale: ConnectedScale) {
-    let config = Config::load();
+const MOVE_POLL: Duration = Duration::from_millis(20);
+const READ_ATTEMPTS: usize = 3;
+
+pub async fn ichibu_cycle(
+    state: &Mutex<AppData>,
+    scale: ConnectedScale,
+    mut progress: ProgressEmitter,
+) {
+    let config_dir = state.lock().unwrap().config_dir.clone();
+    let config = Config::load_from(&config_dir);
 
     let cc_handle = initialize_controller(&config);
     let motor = setup_conveyor_motor(&config, &cc_handle).await;
@@ -24,59 +41,119 @@ pub async fn ichibu_cycle(state: tauri::State<'_, Mutex<AppData>>, scale: Connec
 
     let _ = hatch.close().await;
 
-    run_cycle_loop(state, scale, &motor, &mut hatch).await;
+    let settle = config.dispense.settle;
+    state.lock().unwrap().run_out = RunOutTracker::new(config.dispense.policy);
+    let mut monitor = ScaleMonitor::new(config.scale_health);
+    run_cycle_loop(state, scale, &motor, &mut hatch, &mut monitor, &mut progress, settle).await;
 }
 
-async fn wait_for_pe(state: tauri::State<'_, Mutex<AppData>>) {
+async fn wait_for_pe(state: &Mutex<AppData>) {
+    let mut logged = false;
     while matches!(
         state.lock().unwrap().get_pe_state(),
         PhotoEyeState::Unblocked
-    ) {
-        log::info!("Waiting for photoeye input");
+    ) && !state.lock().unwrap().is_shutting_down()
+    {
+        if !logged {
+            log::info!("Waiting for a bowl");
+            logged = true;
+        }
         sleep(Duration::from_millis(250)).await;
     }
 }
 
 async fn run_cycle_loop(
-    state: tauri::State<'_, Mutex<AppData>>,
+    state: &Mutex<AppData>,
     mut scale: ConnectedScale,
     conveyor: &ClearCoreMotor,
     hatch: &mut Hatch,
+    monitor: &mut ScaleMonitor,
+    progress: &mut ProgressEmitter,
+    settle: Duration,
 ) {
+    let node = state.lock().unwrap().node.clone();
     loop {
-        let state = state.clone();
-        let (ichibu_state, pe_state) = {
+        let iteration = Instant::now();
+        let (ichibu_state, pe_state, shutting_down) = {
             let state = state.lock().unwrap();
             let ichibu_state = state.get_state();
             let pe_state = state.get_pe_state();
-            (ichibu_state, pe_state)
+            let ingredient = state.get_snack().map(|snack| snack.name.clone());
+            logging::update_context(|context| {
+                context.state = Some(ichibu_state.clone());
+                context.ingredient = ingredient;
+            });
+            (ichibu_state, pe_state, state.is_shutting_down())
         };
+        if shutting_down {
+            park_hardware(conveyor, hatch).await;
+            return;
+        }
         match ichibu_state {
             IchibuState::Cleaning => {
-                if hatch.open().await.is_err() {
+                if !open_hatch(state, hatch).await {
                     log::error!("Hatch Failed To Open")
                 }
+                handle_cleaning_request(state, conveyor, &mut scale, monitor).await
+            }
+            IchibuState::Emptying => handle_emptying_state(conveyor, hatch, pe_state).await,
+            IchibuState::Ready => {
+                if hatch.is_open().await {
+                    monitor.check_if_due(state, &mut scale).await;
+                }
+                tokio::time::sleep(Duration::from_millis(1000)).await
+            }
+            IchibuState::Calibrating => {
+                // Reference weights sit on the closed hatch, same as a dispensed portion
+                if hatch.close().await.is_err() {
+                    log::error!("Hatch Failed to Close");
+                }
                 conveyor.abrupt_stop().await;
                 conveyor.disable().await;
-                tokio::time::sleep(Duration::from_millis(1000)).await
+                handle_calibration_request(state, &mut scale).await
             }
-            IchibuState::Emptying => handle_emptying_state(conveyor, hatch, pe_state).await,
-            IchibuState::Ready => tokio::time::sleep(Duration::from_millis(1000)).await,
-            IchibuState::RunningClassic | IchibuState::RunningSized => {
-                scale = handle_running_state(state, scale, conveyor, hatch).await
+            IchibuState::Running => {
+                state.lock().unwrap().start_trace();
+                scale = handle_running_state(state, scale, conveyor, hatch, monitor, progress, settle).await;
+                state.lock().unwrap().finish_trace();
             }
         }
+        METRICS
+            .cycle_iteration
+            .with_label_values(&[&node])
+            .observe(iteration.elapsed().as_secs_f64());
     }
 }
 
+// How handle_user_selection finished with the staged portion
+enum Selection {
+    Served,
+    Discard,
+    Stopped,
+    //The top-up ran out, nothing was served
+    RanOut,
+}
+
 async fn handle_running_state(
-    state: tauri::State<'_, Mutex<AppData>>,
+    state: &Mutex<AppData>,
     mut scale: ConnectedScale,
     conveyor: &ClearCoreMotor,
     hatch: &mut Hatch,
+    monitor: &mut ScaleMonitor,
+    progress: &mut ProgressEmitter,
+    settle: Duration,
 ) -> ConnectedScale {
-    let snack = { state.lock().unwrap().get_snack().unwrap().clone() };
-    if hatch.close().await.is_err() {
+    let (snack, dispense_type, needs_warm_up, strategy) = {
+        let state = state.lock().unwrap();
+        (
+            state.get_snack().unwrap().clone(),
+            state.dispense_type(),
+            state.needs_warm_up,
+            state.staging.config().strategy,
+        )
+    };
+    logging::update_context(|context| context.cycle += 1);
+    if !close_hatch(state, hatch).await {
         log::error!("Hatch Failed to Close");
         state.lock().unwrap().dispenser_has_timed_out = true;
         return scale
@@ -86,36 +163,59 @@ async fn handle_running_state(
         state.lock().unwrap().set_dispenser_busy(true);
     }
     
-    sleep(Duration::from_millis(2000)).await;
-    log::info!("Starting primary dispense");
+    sleep(settle).await;
+    if needs_warm_up {
+        conveyor.enable().await.expect("Conveyor enable failed");
+        trace(state, TraceEvent::ConveyorEnabled);
+        warm_up_conveyor(state, conveyor, &snack.warm_up).await;
+        state.lock().unwrap().needs_warm_up = false;
+    }
+    progress.start_portion(progress.read_weight(&scale));
+    trace_weights(state, progress);
+    // On demand skips the base portion and dispenses the whole request once it comes in
+    let target = match strategy {
+        StagingStrategy::OnDemand => 0.,
+        StagingStrategy::KeepStaged => dispense_type.base_target(&snack),
+    };
     // let dispense = dispenser.launch_dispense(setpoint, parameters).await;
     // TODO: need to get this from config later
     conveyor.enable().await.expect("Conveyor enable failed");
-    let dispense_settings = snack.dispense_settings.clone();
-    let dispense = dispenser::DispenseOutcome::dispense(conveyor, scale, dispense_settings)
-        .await
-        .expect("Dispense failed");
-    scale = {
-        let mut state_guard = state.lock().unwrap();
-        state_guard.set_dispenser_busy(false);
-
-        match dispense {
-            DispenseOutcome::Success(_, scale) => scale,
-            DispenseOutcome::Timeout(_, scale) => {
-                if state_guard.cycle_dispense_count > 2 {
-                    log::info!("oh fuck we timed out");
-                    state_guard.dispenser_has_timed_out = true;
-                    state_guard.update_state(IchibuState::Ready);
-                    let action = DataAction::RanOut;
-                    state_guard.log_action(&action);
-                    return scale;
-                } else {
-                    scale
-                }
-            }
+    trace(state, TraceEvent::ConveyorEnabled);
+    if target > 0. {
+        log::info!("Starting primary dispense");
+        let ran_out;
+        (scale, ran_out) =
+            dispense_with_policy(state, scale, conveyor, &snack, progress, target).await;
+        state.lock().unwrap().set_dispenser_busy(false);
+        if ran_out {
+            return scale;
         }
-    };
-    scale = handle_user_selection(state.clone(), scale, conveyor, &snack).await;
+        state.lock().unwrap().staging.stage(Instant::now());
+    } else {
+        state.lock().unwrap().set_dispenser_busy(false);
+    }
+    let selection;
+    (scale, selection) = handle_user_selection(
+        state,
+        scale,
+        conveyor,
+        &snack,
+        dispense_type,
+        progress,
+        target,
+    )
+    .await;
+    if matches!(selection, Selection::Stopped) {
+        state.lock().unwrap().fail_order("Dispensing stopped");
+    }
+    if matches!(selection, Selection::RanOut) {
+        // The short portion stays on the closed hatch, same as when the base portion runs out
+        state.lock().unwrap().reset_ui_request();
+        return scale;
+    }
+    if state.lock().unwrap().is_shutting_down() {
+        return scale;
+    }
 
     let ichibu_state = {
         let state = state.lock().unwrap();
@@ -129,6 +229,8 @@ async fn handle_running_state(
         state_guard.log_action(&cleaning);
         state_guard.dispenser_has_timed_out = false;
         state_guard.cycle_dispense_count = 0;
+        state_guard.run_out.reset();
+        state_guard.staging.clear();
         return scale;
     }
     if matches!(ichibu_state, IchibuState::Emptying) {
@@ -137,41 +239,63 @@ async fn handle_running_state(
         state_guard.log_action(&emptying);
         state_guard.dispenser_has_timed_out = false;
         state_guard.cycle_dispense_count = 0;
+        state_guard.run_out.reset();
+        state_guard.staging.clear();
+        return scale;
+    }
+    if matches!(selection, Selection::Discard) {
+        discard_staged(state, hatch).await;
         return scale;
     }
 
-    if hatch.open().await.is_err() {
+    if !open_hatch(state, hatch).await {
         log::error!("Hatch open timed out!");
     }
     sleep(Duration::from_millis(1000)).await;
+    // Hatch is open and the portion has dropped, so the platform should read zero
+    monitor.check_if_due(state, &mut scale).await;
     let mut state = state.lock().unwrap();
     state.cycle_dispense_count += 1;
     log::info!("Dispense count: {}", state.cycle_dispense_count);
+    if matches!(state.get_pe_state(), PhotoEyeState::Blocked) {
+        state.bowl_served = Some(Instant::now());
+    }
+    let wait = state.bowl_placed.map(|placed| placed.elapsed());
+    state.staging.serve(wait);
+    state.finish_order();
     state.reset_ui_request();
     scale
 }
 
 async fn handle_user_selection(
-    state: tauri::State<'_, Mutex<AppData>>,
+    state: &Mutex<AppData>,
     mut scale: ConnectedScale,
     conveyor: &ClearCoreMotor,
     snack: &Ingredient,
-) -> ConnectedScale {
+    dispense_type: DispenseType,
+    progress: &mut ProgressEmitter,
+    base_target: f64,
+) -> (ConnectedScale, Selection) {
     log::info!("Waiting for user input");
     let scale = loop {
-        let (request, ichibu_state) = {
+        let (request, ichibu_state, shutting_down) = {
             let state = state.lock().unwrap();
             let request = state.get_ui_request();
             let ichibu_state = state.get_state();
-            (request, ichibu_state)
+            (request, ichibu_state, state.is_shutting_down())
         };
+        if shutting_down {
+            return (scale, Selection::Stopped);
+        }
         if matches!(ichibu_state, IchibuState::Cleaning) {
             let cleaning = DataAction::Cleaning;
             let mut state_guard = state.lock().unwrap();
             state_guard.log_action(&cleaning);
             state_guard.dispenser_has_timed_out = false;
             state_guard.cycle_dispense_count = 0;
-            return scale;
+            state_guard.run_out.reset();
+            state_guard.staging.clear();
+            return (scale, Selection::Stopped);
         }
         if matches!(ichibu_state, IchibuState::Emptying) {
             let emptying = DataAction::Emptying;
@@ -179,80 +303,296 @@ async fn handle_user_selection(
             state_guard.log_action(&emptying);
             state_guard.dispenser_has_timed_out = false;
             state_guard.cycle_dispense_count = 0;
-            return scale;
+            state_guard.run_out.reset();
+            state_guard.staging.clear();
+            return (scale, Selection::Stopped);
         }
-        scale = match request {
-            UiRequest::None => {
-                sleep(Duration::from_millis(250)).await;
-                scale
+        if matches!(request, UiRequest::None) {
+            if state.lock().unwrap().take_next_order() {
+                continue;
             }
-            UiRequest::SmallDispense => {
-                let small_dispense = DataAction::DispensedSmall;
-                let mut state_guard = state.lock().unwrap();
-                if !state_guard.dispenser_has_timed_out {
-                    state_guard.log_action(&small_dispense);
-                } else {
-                    let action = DataAction::RanOut;
-                    state_guard.log_action(&action);
-                    state_guard.update_state(IchibuState::Ready);
-                }
-                break scale;
+            if staged_portion_expired(state) {
+                return (scale, Selection::Discard);
+            }
+            sleep(Duration::from_millis(250)).await;
+            continue;
+        }
+        let Some(target) = dispense_type.requested_target(snack, &request) else {
+            log::warn!("{} has no portion for {:?}", snack.name, request);
+            let mut state_guard = state.lock().unwrap();
+            state_guard.fail_order("No such portion size");
+            state_guard.reset_ui_request();
+            continue;
+        };
+        trace(state, TraceEvent::UserRequest(request.size_name().to_string()));
+        if target > base_target {
+            log::info!("Starting secondary dispense");
+            {
+                state.lock().unwrap().set_dispenser_busy(true);
+            }
+            let ran_out;
+            (scale, ran_out) =
+                dispense_with_policy(state, scale, conveyor, snack, progress, target).await;
+            state.lock().unwrap().set_dispenser_busy(false);
+            if ran_out {
+                state.lock().unwrap().fail_order("Ran out");
+                return (scale, Selection::RanOut);
             }
-            UiRequest::RegularDispense => {
-                if matches!(ichibu_state, IchibuState::RunningSized) {
-                    // TODO: figure out how to replace this:
-                    // let sp = snack.max_setpoint - snack.min_setpoint;
-                    log::info!("Starting secondary dispense");
-                    {
-                        state.lock().unwrap().set_dispenser_busy(true);
-                    }
+            log::info!("Secondary Dispense COMPLETE");
+        }
+        progress.finish(target, progress.read_weight(&scale));
+        trace_weights(state, progress);
+        let action = dispense_type.served_action(&request);
+        let mut state_guard = state.lock().unwrap();
+        state_guard.log_action(&action);
+        state_guard.trace.record(TraceEvent::Served);
+        let labels = [state_guard.node.as_str(), snack.name.as_str()];
+        METRICS
+            .bowls_dispensed
+            .with_label_values(&[labels[0], labels[1], request.size_name()])
+            .inc();
+        METRICS
+            .dispense_duration
+            .with_label_values(&labels)
+            .observe(progress.dispense_time().as_secs_f64());
+        if let Some(weight) = progress.portion_weight() {
+            METRICS
+                .weight_error
+                .with_label_values(&labels)
+                .observe(weight - target);
+        }
+        state_guard.telemetry.publish(TelemetryEvent::Dispensed {
+            ingredient: snack.name.clone(),
+            portion: request.size_name().to_string(),
+            target,
+            weight: progress.portion_weight(),
+            duration_ms: progress.dispense_time().as_millis() as u64,
+        });
+        break scale;
+    };
+    wait_for_pe(state).await;
+    (scale, Selection::Served)
+}
 
-                    let cycle_dispense_count = { state.lock().unwrap().cycle_dispense_count };
-                    if cycle_dispense_count == 0 {
-                        log::info!("Priming conveyor...");
-                        conveyor.relative_move(1.5).await.expect("Motor error");
-                        tokio::time::sleep(Duration::from_millis(500)).await;
-                        conveyor.wait_for_move(Duration::from_millis(20)).await.expect("Motor error");
-                        log::info!("Primed!");
-                    }
+//Flags a portion held past its hold time, true if it should be thrown out instead. A portion
+//is only thrown out with no bowl under the chute, until then it is held and flagged.
+fn staged_portion_expired(state: &Mutex<AppData>) -> bool {
+    let mut state_guard = state.lock().unwrap();
+    if !state_guard.staging.is_expired(Instant::now()) {
+        return false;
+    }
+    let no_bowl = matches!(state_guard.get_pe_state(), PhotoEyeState::Unblocked);
+    match state_guard.staging.config().expired_action {
+        ExpiredAction::Discard if no_bowl => true,
+        ExpiredAction::Discard | ExpiredAction::Flag => {
+            if state_guard.staging.flag() {
+                log::warn!("Staged portion held past its hold time");
+                state_guard.log_action(&DataAction::HoldExpired);
+            }
+            false
+        }
+    }
+}
+
+async fn discard_staged(state: &Mutex<AppData>, hatch: &mut Hatch) {
+    {
+        let mut state_guard = state.lock().unwrap();
+        // A bowl may have arrived since the portion was picked for discarding
+        if !matches!(state_guard.get_pe_state(), PhotoEyeState::Unblocked) {
+            log::info!("Bowl under the chute, holding the expired portion");
+            return;
+        }
+        log::warn!("Discarding staged portion held past its hold time");
+        state_guard.trace.record(TraceEvent::Discarded);
+    }
+    if !open_hatch(state, hatch).await {
+        log::error!("Hatch open timed out!");
+    }
+    sleep(Duration::from_millis(1000)).await;
+    let mut state_guard = state.lock().unwrap();
+    state_guard.staging.discard();
+    state_guard.log_action(&DataAction::Discarded);
+}
+
+async fn open_hatch(state: &Mutex<AppData>, hatch: &mut Hatch) -> bool {
+    trace(state, TraceEvent::HatchOpenStart);
+    let ok = record_hatch(state, hatch.open().await, hatch.last_move);
+    trace(state, TraceEvent::HatchOpenEnd { ok });
+    ok
+}
 
-                    let dispense_settings = snack.dispense_settings.clone();
-                    let dispense =
-                        DispenseOutcome::dispense(conveyor, scale, dispense_settings)
-                            .await
-                            .expect("Dispense failed :(");
+async fn close_hatch(state: &Mutex<AppData>, hatch: &mut Hatch) -> bool {
+    trace(state, TraceEvent::HatchCloseStart);
+    let ok = record_hatch(state, hatch.close().await, hatch.last_move);
+    trace(state, TraceEvent::HatchCloseEnd { ok });
+    ok
+}
+
+fn trace(state: &Mutex<AppData>, event: TraceEvent) {
+    state.lock().unwrap().trace.record(event);
+}
+
+// Every weight the progress emitter read, at the time it was read
+fn trace_weights(state: &Mutex<AppData>, progress: &mut ProgressEmitter) {
+    let mut state_guard = state.lock().unwrap();
+    for (at, weight) in progress.take_samples() {
+        state_guard
+            .trace
+            .record_at(at.into_std(), TraceEvent::Weight(weight));
+    }
+}
+
+//Keeps the hatch fault flag in step with the last hatch move
+fn record_hatch(
+    state: &Mutex<AppData>,
+    moved: Result<(), HatchError>,
+    move_time: Option<Duration>,
+) -> bool {
+    let mut state_guard = state.lock().unwrap();
+    if let Some(move_time) = move_time {
+        METRICS
+            .hatch_move
+            .with_label_values(&[&state_guard.node])
+            .observe(move_time.as_secs_f64());
+    }
+    if let Err(e) = &moved {
+        METRICS
+            .hatch_faults
+            .with_label_values(&[&state_guard.node])
+            .inc();
+        if !state_guard.hatch_fault {
+            state_guard.telemetry.publish(TelemetryEvent::Fault {
+                fault: format!("Hatch: {:?}", e),
+            });
+        }
+    }
+    state_guard.hatch_fault = moved.is_err();
+    moved.is_ok()
+}
 
-                    let mut state_guard = state.lock().unwrap();
-                    state_guard.set_dispenser_busy(false);
+async fn park_hardware(conveyor: &ClearCoreMotor, hatch: &mut Hatch) {
+    log::info!("Parking hardware for shutdown");
+    conveyor.abrupt_stop().await;
+    conveyor.disable().await;
+    if hatch.park().await.is_err() {
+        log::error!("Hatch failed to close for shutdown");
+    }
+}
 
-                    let scale = match dispense {
-                        DispenseOutcome::Success(_, scale) => scale,
-                        DispenseOutcome::Timeout(_, scale) => {
-                            if state_guard.cycle_dispense_count > 2 {
-                                log::info!("oh fuck we timed out");
-                                state_guard.dispenser_has_timed_out = true;
-                                state_guard.update_state(IchibuState::Ready);
-                                let action = DataAction::RanOut;
-                                state_guard.log_action(&action);
-                                return scale;
-                            } else {
-                                scale
-                            }
-                        }
-                    };
-                    log::info!("Secondary Dispense COMPLETE");
-                    scale
-                } else {
-                    let action = &DataAction::DispensedRegular;
-                    state.lock().unwrap().log_action(action);
-                    log::info!("Breaking out of handle_user_selection");
-                    break scale;
+async fn warm_up_conveyor(state: &Mutex<AppData>, conveyor: &ClearCoreMotor, warm_up: &WarmUp) {
+    log::info!("Priming conveyor...");
+    if let Some(velocity) = warm_up.velocity {
+        conveyor.set_velocity(velocity).await;
+    }
+    conveyor
+        .relative_move(warm_up.priming_distance)
+        .await
+        .expect("Motor error");
+    // The dwell also gives the move time to start before we wait on it
+    tokio::time::sleep(warm_up.dwell).await;
+    conveyor.wait_for_move(MOVE_POLL).await.expect("Motor error");
+    if warm_up.velocity.is_some() {
+        let velocity = state.lock().unwrap().conveyor_velocity;
+        conveyor.set_velocity(velocity).await;
+    }
+    log::info!("Primed!");
+}
+
+// The portion on the hatch, re-reading a scale that failed. None if it still can't be read.
+async fn read_portion(
+    scale: &ConnectedScale,
+    progress: &mut ProgressEmitter,
+    target: f64,
+) -> Option<f64> {
+    for _ in 0..READ_ATTEMPTS {
+        if let Some(weight) = progress.portion_weight() {
+            return Some(weight);
+        }
+        sleep(Duration::from_millis(100)).await;
+        progress.update(target, progress.read_weight(scale));
+    }
+    progress.portion_weight()
+}
+
+// Dispenses toward target until the run-out policy accepts the portion, or returns true once
+// we ran out or can't weigh the portion
+async fn dispense_with_policy(
+    state: &Mutex<AppData>,
+    mut scale: ConnectedScale,
+    conveyor: &ClearCoreMotor,
+    snack: &Ingredient,
+    progress: &mut ProgressEmitter,
+    target: f64,
+) -> (ConnectedScale, bool) {
+    loop {
+        // Retries only go for what is still missing after a partial dispense
+        let Some(portion) = read_portion(&scale, progress, target).await else {
+            // Without a weight we'd dispense blind, so stop until staff look at the scale
+            log::error!("Can't read the scale, stopping the portion");
+            let mut state_guard = state.lock().unwrap();
+            state_guard.scale_fault = Some(ScaleFault::Disconnected);
+            state_guard.log_action(&DataAction::ScaleFault);
+            state_guard.update_state(IchibuState::Ready);
+            return (scale, true);
+        };
+        let Some(dispense_settings) = top_up_settings(snack, target, portion) else {
+            log::info!("Portion already at {} of {}", portion, target);
+            return (scale, false);
+        };
+        trace(state, TraceEvent::DispenseStart { target });
+        let timed_out;
+        (scale, timed_out) = progress
+            .dispense(conveyor, scale, dispense_settings, target)
+            .await;
+        trace_weights(state, progress);
+        trace(state, TraceEvent::EndCondition { timed_out });
+        if matches!(conveyor.get_status().await, Status::Faulted) {
+            log::warn!("Conveyor motor faulted during dispense");
+            let node = state.lock().unwrap().node.clone();
+            METRICS
+                .motor_alerts
+                .with_label_values(&[&node, "conveyor"])
+                .inc();
+        }
+
+        let decision = {
+            let mut state_guard = state.lock().unwrap();
+            let decision = state_guard
+                .run_out
+                .record(timed_out, progress.portion_weight(), target);
+            let timeouts = state_guard.run_out.consecutive_timeouts();
+            match decision {
+                DispenseDecision::Accept => (),
+                DispenseDecision::Retry { .. } => {
+                    log::warn!("Dispense timed out ({} in a row), retrying", timeouts);
+                }
+                DispenseDecision::RunOut => {
+                    log::warn!("Dispenser ran out after {} consecutive timeouts", timeouts);
+                    state_guard.dispenser_has_timed_out = true;
+                    state_guard.update_state(IchibuState::Ready);
+                    state_guard.log_action(&DataAction::RanOut);
+                    state_guard.trace.record(TraceEvent::RanOut);
+                    METRICS
+                        .run_outs
+                        .with_label_values(&[&state_guard.node, &snack.name])
+                        .inc();
+                    state_guard.telemetry.publish(TelemetryEvent::RanOut {
+                        ingredient: snack.name.clone(),
+                    });
                 }
             }
+            decision
         };
-    };
-    wait_for_pe(state.clone()).await;
-    scale
+        match decision {
+            DispenseDecision::Accept => return (scale, false),
+            DispenseDecision::RunOut => return (scale, true),
+            DispenseDecision::Retry { prime } => {
+                if prime {
+                    warm_up_conveyor(state, conveyor, &snack.warm_up).await;
+                }
+            }
+        }
+    }
 }
 
 async fn handle_emptying_state(
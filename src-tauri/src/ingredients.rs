@@ -1,7 +1,12 @@
+use std::time::Duration;
+
 use node_diagnostics::dispenser::DispenseSettings;
 use serde::Serialize;
 use serde_derive::Deserialize;
 
+use crate::config::duration_serde;
+use crate::portion::DispenseType;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UiData {
     pub id: usize,
@@ -23,6 +28,35 @@ impl Default for UiData {
     }
 }
 
+//Run when a snack is first selected and after a refill, so the conveyor is full before dispensing
+#[derive(Deserialize, Debug, Clone)]
+pub struct WarmUp {
+    pub priming_distance: f64,
+    pub velocity: Option<f64>,
+    //Counted from the start of the priming move
+    #[serde(with = "duration_serde")]
+    pub dwell: Duration,
+}
+
+impl Default for WarmUp {
+    fn default() -> Self {
+        Self {
+            priming_distance: 1.5,
+            velocity: None,
+            dwell: Duration::from_millis(1000),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PortionSize {
+    pub name: String,
+    //Grams
+    pub target: f64,
+    #[serde(default)]
+    pub price: Option<f64>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Ingredient {
     pub name: String,
@@ -31,6 +65,43 @@ pub struct Ingredient {
     pub min_setpoint: usize,
     pub ui_data: UiData,
     pub dispense_settings: DispenseSettings,
+    #[serde(default)]
+    pub warm_up: WarmUp,
+    //Overrides the kiosk wide dispense type for this snack
+    #[serde(default)]
+    pub dispense_type: Option<DispenseType>,
+    #[serde(default)]
+    pub sizes: Vec<PortionSize>,
+}
+
+impl Ingredient {
+    //Smallest first. Snacks without sizes get small and regular from their setpoints
+    pub fn portion_sizes(&self) -> Vec<PortionSize> {
+        let mut sizes = if self.sizes.is_empty() {
+            vec![
+                PortionSize {
+                    name: "Small".to_string(),
+                    target: self.min_setpoint as f64,
+                    price: None,
+                },
+                PortionSize {
+                    name: "Regular".to_string(),
+                    target: self.max_setpoint as f64,
+                    price: None,
+                },
+            ]
+        } else {
+            self.sizes.clone()
+        };
+        sizes.sort_by(|a, b| a.target.total_cmp(&b.target));
+        sizes
+    }
+
+    pub fn portion_size(&self, name: &str) -> Option<PortionSize> {
+        self.portion_sizes()
+            .into_iter()
+            .find(|size| size.name == name)
+    }
 }
 
 impl Default for Ingredient {
@@ -42,6 +113,9 @@ impl Default for Ingredient {
             min_setpoint: 10,
             ui_data: Default::default(),
             dispense_settings: Default::default(),
+            warm_up: Default::default(),
+            dispense_type: None,
+            sizes: Vec::new(),
         }
     }
 }
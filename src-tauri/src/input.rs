@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use control_components::components::clear_core_io::DigitalInput;
+use tokio::sync::watch;
+
+use crate::config::{FilterMode, InputFilterConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+// Turns raw samples into a stable state, so a flickering sensor or a bouncing switch only
+// changes it once
+pub struct InputFilter {
+    stable: bool,
+    samples: VecDeque<bool>,
+    needed: usize,
+    mode: FilterMode,
+}
+
+impl InputFilter {
+    pub fn new(config: &InputFilterConfig, initial: bool) -> Self {
+        Self {
+            stable: initial,
+            samples: VecDeque::new(),
+            needed: config.sample_number.max(1),
+            mode: config.mode,
+        }
+    }
+
+    pub fn state(&self) -> bool {
+        self.stable
+    }
+
+    //The edge once the change has held, by the filter's mode
+    pub fn sample(&mut self, reading: bool) -> Option<Edge> {
+        if self.samples.len() == self.needed {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(reading);
+        if self.samples.len() < self.needed {
+            return None;
+        }
+        let changed = self.samples.iter().filter(|&&s| s != self.stable).count();
+        let flip = match self.mode {
+            FilterMode::Consecutive => changed == self.needed,
+            FilterMode::Majority => changed * 2 > self.needed,
+        };
+        if !flip {
+            return None;
+        }
+        self.stable = !self.stable;
+        self.samples.clear();
+        Some(if self.stable {
+            Edge::Rising
+        } else {
+            Edge::Falling
+        })
+    }
+}
+
+// A digital input sampled in the background, reading it gives the filtered state
+#[derive(Clone)]
+pub struct FilteredInput {
+    state: watch::Receiver<bool>,
+    raw: DigitalInput,
+    sample_period: Duration,
+    //Long enough for the filter to take up a change that holds
+    confirm: Duration,
+}
+
+impl FilteredInput {
+    //The first reading is taken as is, the sampling stops once every handle is dropped
+    pub async fn spawn(name: &str, input: DigitalInput, config: &InputFilterConfig) -> Self {
+        let raw = input.clone();
+        let mut filter = InputFilter::new(config, input.get_state().await);
+        let (sender, state) = watch::channel(filter.state());
+        let name = name.to_string();
+        let mut interval = tokio::time::interval(config.sample_period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        tauri::async_runtime::spawn(async move {
+            while !sender.is_closed() {
+                interval.tick().await;
+                if let Some(edge) = filter.sample(input.get_state().await) {
+                    log::debug!("{} {:?}", name, edge);
+                    sender.send_replace(filter.state());
+                }
+            }
+        });
+        Self {
+            state,
+            raw,
+            sample_period: config.sample_period,
+            confirm: config.sample_period * (config.sample_number as u32 + 1),
+        }
+    }
+
+    pub fn get_state(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    //Waits for the next stable change
+    pub async fn next_edge(&mut self) -> Edge {
+        self.state.borrow_and_update();
+        if self.state.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        match *self.state.borrow_and_update() {
+            true => Edge::Rising,
+            false => Edge::Falling,
+        }
+    }
+
+    pub async fn wait_for(&mut self, value: bool) {
+        let _ = self.state.wait_for(|state| *state == value).await;
+    }
+
+    //Returns on the first raw reading of value, for stopping a motor right at a switch
+    pub async fn wait_for_raw(&self, value: bool) {
+        while self.raw.get_state().await != value {
+            tokio::time::sleep(self.sample_period).await;
+        }
+    }
+
+    //Whether the filter takes up value in time, after wait_for_raw saw it
+    pub async fn confirm(&mut self, value: bool) -> bool {
+        let confirm = self.confirm;
+        tokio::time::timeout(confirm, self.wait_for(value)).await.is_ok()
+    }
+}
+
+#[test]
+fn test_input_filter() {
+    let config = |mode| InputFilterConfig {
+        sample_period: std::time::Duration::from_millis(10),
+        sample_number: 3,
+        mode,
+    };
+    let run = |filter: &mut InputFilter, samples: &[u8]| -> Vec<Option<Edge>> {
+        samples.iter().map(|&s| filter.sample(s == 1)).collect()
+    };
+
+    let mut consecutive = InputFilter::new(&config(FilterMode::Consecutive), false);
+    //A blip shorter than three samples is ignored
+    assert_eq!(run(&mut consecutive, &[1, 1, 0, 1, 1]), vec![None; 5]);
+    assert_eq!(consecutive.sample(true), Some(Edge::Rising));
+    assert_eq!(run(&mut consecutive, &[0, 0]), vec![None; 2]);
+    assert_eq!(consecutive.sample(false), Some(Edge::Falling));
+
+    let mut majority = InputFilter::new(&config(FilterMode::Majority), false);
+    assert_eq!(run(&mut majority, &[1, 0]), vec![None; 2]);
+    assert_eq!(majority.sample(true), Some(Edge::Rising));
+    assert_eq!(run(&mut majority, &[0, 1, 1, 0]), vec![None; 4]);
+    assert!(majority.state());
+}
+
+#[test]
+fn test_photo_eye_config() {
+    //Configs from before the filter mode existed still load
+    let config: crate::config::PhotoEyeConfig =
+        serde_json::from_str(r#"{"input_id": 2, "sample_period": 30, "sample_number": 5}"#)
+            .unwrap();
+    assert_eq!(config.sample_period.as_millis(), 30);
+    assert_eq!(config.sample_number, 5);
+    assert_eq!(config.mode, FilterMode::Consecutive);
+    //Without them the photo eye gets its own, slower defaults
+    let config: crate::config::PhotoEyeConfig =
+        serde_json::from_str(r#"{"input_id": 2}"#).unwrap();
+    assert_eq!(config.filter().sample_period.as_millis(), 50);
+    assert_eq!(config.filter().sample_number, 4);
+}
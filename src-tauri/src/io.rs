@@ -3,14 +3,14 @@ use control_components::components::clear_core_motor::ClearCoreMotor;
 use control_components::controllers::clear_core::{Controller, MotorBuilder};
 use rusqlite::Connection;
 
+use crate::accounts::Accounts;
 use crate::config::Config;
 use crate::data_logging::Data;
 use crate::hatch::Hatch;
-use crate::HOME_DIRECTORY;
+use crate::input::FilteredInput;
+const DB_PATH: &str = "data/";
 
-const DB_PATH: &str = ".config/ichibu/data/";
-
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 
 pub enum PhotoEyeState {
     Blocked,
@@ -32,18 +32,34 @@ pub async fn setup_conveyor_motor(config: &Config, controller: &Controller) -> C
     motor.clear_alerts().await;
     let _ = motor.enable().await;
     motor.set_acceleration(config.motor.acceleration).await;
+    motor.set_velocity(config.motor.velocity).await;
     //motor.set_deceleration(config.motor.acceleration).await;
     motor
 }
 
-pub fn initialize_database() -> (Data, i64) {
-    let database_path = format!("{}/{}", HOME_DIRECTORY.as_str(), DB_PATH);
-    let database_connection = Connection::open(database_path).unwrap();
-    let database = Data::new(database_connection);
+//dir is the node's config directory
+fn open_database(dir: &str) -> Connection {
+    let database_path = format!("{}/{}", dir, DB_PATH);
+    Connection::open(database_path).unwrap()
+}
+
+pub fn initialize_database(dir: &str) -> (Data, i64) {
+    let database = Data::new(open_database(dir));
     let bowl_count = database.connect().unwrap();
     (database, bowl_count)
 }
 
+pub fn initialize_accounts(config: &Config, dir: &str) -> Accounts {
+    let accounts = Accounts::new(open_database(dir), config.auth.clone());
+    accounts.connect().unwrap();
+    if let Some(pins) = &config.pins {
+        if let Err(e) = accounts.migrate_pins(pins) {
+            log::error!("Failed to create accounts from config pins: {:?}", e);
+        }
+    }
+    accounts
+}
+
 pub fn initialize_controller(config: &Config) -> Controller {
     let (controller, controller_client) = Controller::with_client(
         config.addresses.clear_core.clone(),
@@ -67,10 +83,13 @@ pub fn initialize_controller(config: &Config) -> Controller {
 }
 
 pub async fn initialize_hatch(cc_handle: &Controller, config: &Config) -> Hatch {
+    let filter = &config.hatch.limit_filter;
+    let open_input = cc_handle.get_digital_input(config.hatch.open_input);
+    let close_input = cc_handle.get_digital_input(config.hatch.close_input);
     let mut hatch = Hatch::new(
         cc_handle.get_motor(config.hatch.motor_id),
-        cc_handle.get_digital_input(config.hatch.open_input),
-        cc_handle.get_digital_input(config.hatch.close_input),
+        FilteredInput::spawn("Hatch open switch", open_input, filter).await,
+        FilteredInput::spawn("Hatch close switch", close_input, filter).await,
     );
     hatch.setup(&config.hatch).await;
     hatch
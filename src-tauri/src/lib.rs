@@ -1,36 +1,57 @@
-use config::Config;
-use ichibu::ichibu_cycle;
+use accounts::{Account, AuthError, Role};
+use session::CommandError;
+use shutdown::{request_shutdown, ShutdownReason};
+use config::{Config, FleetConfig, LoggingConfig, NodeConfig};
+use daemon::{spawn_controls, spawn_order_api, DaemonClient, Hardware};
+use events::EventSink;
+use fleet::{get_fleet, select_node, Fleet};
 use ingredients::{read_ingredient_config, UiData};
-use io::initialize_controller;
-use libra::scale;
 use log::info;
 use serde::{Deserialize, Serialize};
-use state::clear_dispenser_time_out;
-use state::dispenser_has_timed_out;
-use state::get_pe_blocked;
-use state::update_pe_state;
-use state::{
-    dispenser_is_busy, get_dispense_count, update_current_ingredient, update_run_state,
-    update_ui_request,
-};
+use serde_json::Value;
+use state::{AppData, SharedState};
 use std::env;
-use std::sync::{LazyLock, Mutex};
-use std::time::Duration;
+use std::sync::{Arc, LazyLock, Mutex};
+use tauri::ipc::{Invoke, InvokeBody, InvokeResolver};
 use tauri::AppHandle;
-use tauri::{ipc::Response, Manager};
-use crate::lights::Lights;
-use crate::state::update_lights_state;
+use tauri::{ipc::Response, Manager, RunEvent};
 
+pub mod accounts;
+pub mod alerts;
+pub mod bowl;
+pub mod calibration;
+pub mod cleaning;
+pub mod commands;
 pub mod config;
+pub mod daemon;
 pub mod data_logging;
 pub mod dispense;
+pub mod events;
+pub mod export;
+pub mod fleet;
 pub mod hatch;
 pub mod ichibu;
 pub mod ingredients;
+pub mod input;
 pub mod io;
+pub mod lights;
+pub mod logging;
+pub mod metrics;
+pub mod order_api;
+pub mod orders;
+pub mod persistence;
+pub mod portion;
+pub mod progress;
+pub mod run_out;
+pub mod sanitation;
+pub mod scale_health;
+pub mod session;
+pub mod staging;
+pub mod telemetry;
+pub mod trace;
+pub mod shutdown;
 
 pub mod state;
-mod lights;
 
 pub static HOME_DIRECTORY: LazyLock<String> = LazyLock::new(|| {
     env::var_os("HOME")
@@ -39,19 +60,26 @@ pub static HOME_DIRECTORY: LazyLock<String> = LazyLock::new(|| {
         .unwrap()
 });
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub enum DispenseType {
-    #[default]
-    Classic,
-    LargeSmall,
-}
-
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub enum UiRequest {
     #[default]
     None,
     SmallDispense,
     RegularDispense,
+    //One of the snack's named portion sizes
+    Size(String),
+}
+
+impl UiRequest {
+    //How the size is reported outside the app
+    pub fn size_name(&self) -> &str {
+        match self {
+            UiRequest::None => "None",
+            UiRequest::SmallDispense => "Small",
+            UiRequest::RegularDispense => "Regular",
+            UiRequest::Size(name) => name,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -82,117 +110,263 @@ fn get_image(filename: String) -> Response {
 }
 
 #[tauri::command]
-fn log_in(pin: String) -> User {
-    let pins = Config::load().pins;
-    if let Ok(pin_num) = pin.parse::<usize>() {
-        if pin_num == pins.sudo {
-            std::process::exit(0x0)
-        }
-        if pin_num == pins.manager {
-            println!("Manager, what are we going to dispense today?");
-            User::Manager
-        } else if pin_num == pins.operator {
-            println!("Operator, lets cook");
-            User::Operator
-        } else {
-            User::None
-        }
-    } else {
-        User::None
+fn get_sound(filename: String) -> Response {
+    const PATH: &str = ".config/ichibu/sounds/";
+    // Only plain file names, nothing outside the sounds directory
+    if filename.contains(['/', '\\']) || filename.contains("..") {
+        log::warn!("Refused sound file {:?}", filename);
+        return tauri::ipc::Response::new(Vec::new());
     }
+    let path = format!("{}/{}/{}", HOME_DIRECTORY.as_str(), PATH, filename);
+    let response = std::fs::read(path).unwrap_or_default();
+    tauri::ipc::Response::new(response)
+}
+
+fn log_in(state: &Mutex<AppData>, pin: String) -> User {
+    let records = {
+        let mut state_guard = state.lock().unwrap();
+        state_guard
+            .accounts
+            .check_lockout()
+            .and_then(|_| state_guard.accounts.pin_records())
+    };
+    // Checking the PIN is slow, so the controls can keep using the state meanwhile
+    let found = records.map(|records| records.find(&pin));
+    let mut state_guard = state.lock().unwrap();
+    let account = match found.and_then(|found| state_guard.accounts.record_attempt(found)) {
+        Ok(account) => account,
+        Err(e) => {
+            log::warn!("Log in failed: {:?}", e);
+            return User::None;
+        }
+    };
+    info!("{} logged in as {:?}", account.name, account.role);
+    let user = User::from(account.role);
+    state_guard.start_session(account);
+    user
+}
+
+fn log_out(state: &Mutex<AppData>) {
+    state.lock().unwrap().end_session();
+}
+
+fn get_session(state: &Mutex<AppData>) -> Option<Account> {
+    state.lock().unwrap().current_account()
+}
+
+fn list_users(state: &Mutex<AppData>) -> Result<Vec<Account>, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    Ok(state_guard.accounts.list()?)
+}
+
+fn add_user(
+    state: &Mutex<AppData>,
+    name: String,
+    pin: String,
+    role: Role,
+) -> Result<(), CommandError> {
+    let records = {
+        let mut state_guard = state.lock().unwrap();
+        // Nobody can hand out more access than they have themselves
+        state_guard.authorize(Role::Manager.max(role))?;
+        state_guard.accounts.pin_records()?
+    };
+    let pin_hash = records.new_pin_hash(&pin, None)?;
+    Ok(state.lock().unwrap().accounts.insert(&name, &pin_hash, role)?)
+}
+
+fn update_user(
+    state: &Mutex<AppData>,
+    name: String,
+    pin: Option<String>,
+    role: Role,
+) -> Result<(), CommandError> {
+    let records = {
+        let mut state_guard = state.lock().unwrap();
+        state_guard.authorize(Role::Manager)?;
+        let current = state_guard
+            .accounts
+            .role_of(&name)?
+            .ok_or(AuthError::NoSuchUser)?;
+        // Changing an account takes its access as well as the access being handed out
+        state_guard.authorize(current.max(role))?;
+        state_guard.accounts.pin_records()?
+    };
+    let pin_hash = match pin {
+        Some(pin) => Some(records.new_pin_hash(&pin, Some(&name))?),
+        None => None,
+    };
+    Ok(state
+        .lock()
+        .unwrap()
+        .accounts
+        .update(&name, pin_hash.as_deref(), role)?)
+}
+
+fn remove_user(state: &Mutex<AppData>, name: String) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    let target = state_guard
+        .accounts
+        .role_of(&name)?
+        .ok_or(AuthError::NoSuchUser)?;
+    // A Manager can't remove an Admin
+    state_guard.authorize(target.max(Role::Manager))?;
+    Ok(state_guard.accounts.remove(&name)?)
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let config = Config::load();
-    let controller = initialize_controller(&config);
-
-    let photo_eye = controller.get_digital_input(config.photo_eye.input_id);
-    let lights = Lights::new(controller.clone());
+    logging::init(&config.logging, &LoggingConfig::dir());
+    let state: SharedState = Arc::new(Mutex::new(AppData::new(&config, &NodeConfig::local())));
+    let hardware = Hardware::connect(&config);
 
     tauri::Builder::default()
-        .manage(Mutex::new(state::AppData::new()))
+        .manage(state.clone())
         .plugin(tauri_plugin_opener::init())
         .setup(move |app| {
-            let app_handle = app.app_handle();
-
-            //Let's spawn the scale
-
-            // let (scale_tx, scale_rx) = channel(10);
-            let scale = scale::DisconnectedScale::new(config.phidget.sn);
-            let mut scale = scale
-                .connect(0., config.phidget.coefficients, Duration::from_secs(10))
-                .expect("Couldn't connect scale!");
-            scale.set_data_intervals(Duration::from_millis(40)).expect("Couldn't set phidget data interval!");
-
-            // tauri::async_runtime::spawn({
-            //     async move {
-            //         let mut scale = Scale::new(config.phidget.sn);
-            //         scale = Scale::change_coefficients(scale, coefficients.to_vec());
-            //         if let Ok(scale) = scale.connect() {
-            //             if let Err(e) = actor(scale, scale_rx).await {
-            //                 log::error!("Scale runtime error: {}", e);
-            //             }
-            //         } else {
-            //             log::warn!("Launching in demo mode");
-            //         }
-            //     }
-            // });
-
-            // let empty_weight = config.setpoint.empty;
-            
-            // //Routine to update io members of state that we need for the UI
-            tauri::async_runtime::spawn({
-                let app_handle = app_handle.clone();
-                async move {
-                    let sleep = Duration::from_millis(500);
-                    let mut interval = tokio::time::interval(sleep);
-                    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
-                    loop {
-                        if let Some(state) = app_handle.try_state::<Mutex<state::AppData>>() {
-                            update_pe_state(state.clone(), photo_eye.clone()).await;
-                            update_lights_state(state.clone(), lights.clone(), sleep).await;
-                        }
-                        interval.tick().await;
-                    }
+            let app_handle = app.app_handle().clone();
+            spawn_order_api(&config, &state);
+            metrics::spawn(&config.metrics);
+            let controls = spawn_controls(
+                &config,
+                state,
+                hardware,
+                EventSink::Tauri(app_handle.clone()),
+            );
+            tauri::async_runtime::spawn(async move {
+                //Finishes once the hardware is parked, or the controls died
+                if let Err(e) = controls.await {
+                    log::error!("Controls stopped unexpectedly: {}", e);
                 }
+                app_handle.exit(0);
             });
+            Ok(())
+        })
+        .invoke_handler(invoke_handler(Backend::Local))
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let RunEvent::ExitRequested { api, .. } = event {
+                shutdown::on_exit_requested(app_handle, &api);
+            }
+        });
+}
+
+// Every node in fleet.toml run from this one process. The webview shows one node at a time,
+// orders and the combined API cover them all.
+pub fn run_fleet() {
+    let config = FleetConfig::load();
+    logging::init(&config.logging, &LoggingConfig::dir());
+    let (fleet, controls) = Fleet::start(&config);
 
-            tauri::async_runtime::spawn({
-                let app_handle = app_handle.clone();
-                // let scale_tx = scale_tx.clone();
-                async move {
-                    tokio::time::sleep(Duration::from_millis(100)).await;
-                    let state = loop {
-                        //wait for state to become available
-                        if let Some(state) = app_handle.try_state::<Mutex<state::AppData>>() {
-                            break state;
-                        } else {
-                            tokio::time::sleep(Duration::from_millis(50)).await;
-                        }
-                    };
-                    ichibu_cycle(state, scale).await;
+    tauri::Builder::default()
+        .manage(fleet.clone())
+        .plugin(tauri_plugin_opener::init())
+        .setup(move |app| {
+            let app_handle = app.app_handle().clone();
+            fleet.relay_events(app_handle.clone());
+            metrics::spawn(&config.metrics);
+            tauri::async_runtime::spawn(fleet::serve(config.api.clone(), fleet));
+            tauri::async_runtime::spawn(async move {
+                for node in controls {
+                    if let Err(e) = node.await {
+                        log::error!("Node controls stopped unexpectedly: {}", e);
+                    }
                 }
+                app_handle.exit(0);
             });
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![
-            get_ingredient_data,
-            get_image,
-            get_dispense_count,
-            get_pe_blocked,
-            update_current_ingredient,
-            update_run_state,
-            update_ui_request,
-            log_in,
-            set_fullscreen,
-            dispenser_is_busy,
-            dispenser_has_timed_out,
-            clear_dispenser_time_out,
-            escape
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .invoke_handler(invoke_handler(Backend::Fleet))
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let RunEvent::ExitRequested { api, .. } = event {
+                shutdown::on_exit_requested(app_handle, &api);
+            }
+        });
+}
+
+// Just the UI, for a daemon running the controls on another node. Images and sounds are
+// still read from this node. The daemon's token comes from ICHIBU_DAEMON_TOKEN so it stays
+// off the command line.
+pub fn run_client(url: &str) {
+    logging::init(&LoggingConfig::default(), &LoggingConfig::dir());
+    let daemon = DaemonClient::new(url, env::var("ICHIBU_DAEMON_TOKEN").ok());
+    tauri::Builder::default()
+        .plugin(tauri_plugin_opener::init())
+        .setup({
+            let daemon = daemon.clone();
+            move |app| {
+                tauri::async_runtime::spawn(daemon.relay_events(app.app_handle().clone()));
+                Ok(())
+            }
+        })
+        .invoke_handler(invoke_handler(Backend::Daemon(daemon)))
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|_, _| {});
+}
+
+//Whose state the UI's commands work on
+enum Backend {
+    Local,
+    //The fleet's selected node
+    Fleet,
+    Daemon(DaemonClient),
+}
+
+// State commands go through commands::invoke, locally or on the daemon, everything else is
+// handled by this app
+fn invoke_handler(backend: Backend) -> impl Fn(Invoke) -> bool + Send + Sync + 'static {
+    let local = tauri::generate_handler![
+        get_ingredient_data,
+        get_image,
+        get_sound,
+        set_fullscreen,
+        get_fleet,
+        select_node,
+        export::save_export
+    ];
+    move |invoke| {
+        let command = invoke.message.command().to_string();
+        if !commands::COMMANDS.contains(&command.as_str()) {
+            return local(invoke);
+        }
+        let args = match invoke.message.payload() {
+            InvokeBody::Json(args) => args.clone(),
+            InvokeBody::Raw(_) => Value::Null,
+        };
+        match &backend {
+            Backend::Daemon(daemon) => {
+                let daemon = daemon.clone();
+                tauri::async_runtime::spawn(async move {
+                    respond(invoke.resolver, daemon.invoke(&command, args).await);
+                });
+            }
+            Backend::Fleet => {
+                let webview = invoke.message.webview();
+                let state = webview.state::<Fleet>().selected().state.clone();
+                respond(invoke.resolver, commands::invoke(&state, &command, args));
+            }
+            Backend::Local => {
+                let webview = invoke.message.webview();
+                let state = webview.state::<SharedState>();
+                respond(invoke.resolver, commands::invoke(&state, &command, args));
+            }
+        }
+        true
+    }
+}
+
+fn respond(resolver: InvokeResolver, result: Result<Value, CommandError>) {
+    match result {
+        Ok(value) => resolver.resolve(value),
+        Err(e) => resolver.reject(e),
+    }
 }
 
 pub fn read_image(root_dir: &str, filename: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -226,8 +400,10 @@ fn set_fullscreen(app: AppHandle) -> Result<(), String> {
     Ok(())
 }
 
-#[tauri::command]
-fn escape() {
+fn escape(state: &Mutex<AppData>) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Admin)?;
     info!("Exiting app");
-    std::process::exit(0x0);
+    request_shutdown(&mut state_guard, ShutdownReason::EscapeKey);
+    Ok(())
 }
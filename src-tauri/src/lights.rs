@@ -1,42 +1,231 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
 use control_components::components::clear_core_io::{HBridge, HBridgeState};
 use control_components::controllers::clear_core::Controller;
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+use crate::config::{ColorEncodings, LightPatterns, LightsConfig};
+use crate::state::AppData;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ChannelDrive {
+    Pos,
+    Neg,
+    Off,
+}
+
+impl From<ChannelDrive> for HBridgeState {
+    fn from(drive: ChannelDrive) -> Self {
+        match drive {
+            ChannelDrive::Pos => HBridgeState::Pos,
+            ChannelDrive::Neg => HBridgeState::Neg,
+            ChannelDrive::Off => HBridgeState::Off,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LightColors {
+    Red,
+    Yellow,
+    Green,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "pattern")]
+pub enum LightPattern {
+    Off,
+    Solid {
+        color: LightColors,
+    },
+    //Half the period on, half off
+    Blink {
+        color: LightColors,
+        #[serde(with = "crate::config::duration_serde")]
+        period: Duration,
+    },
+    //A short flash once per period
+    Pulse {
+        color: LightColors,
+        #[serde(with = "crate::config::duration_serde")]
+        on: Duration,
+        #[serde(with = "crate::config::duration_serde")]
+        period: Duration,
+    },
+    Alternating {
+        first: LightColors,
+        second: LightColors,
+        #[serde(with = "crate::config::duration_serde")]
+        period: Duration,
+    },
+}
+
+impl LightPattern {
+    //The color to show this far into the pattern, None is off
+    pub fn color_at(&self, elapsed: Duration) -> Option<LightColors> {
+        let phase = |period: Duration| {
+            if period.is_zero() {
+                Duration::ZERO
+            } else {
+                Duration::from_nanos((elapsed.as_nanos() % period.as_nanos()) as u64)
+            }
+        };
+        match self {
+            LightPattern::Off => None,
+            LightPattern::Solid { color } => Some(*color),
+            LightPattern::Blink { color, period } => {
+                (phase(*period) < *period / 2).then_some(*color)
+            }
+            LightPattern::Pulse { color, on, period } => (phase(*period) < *on).then_some(*color),
+            LightPattern::Alternating {
+                first,
+                second,
+                period,
+            } => {
+                if phase(*period) < *period / 2 {
+                    Some(*first)
+                } else {
+                    Some(*second)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightCondition {
+    Off,
+    Idle,
+    Running,
+    Busy,
+    RunOut,
+    Fault,
+    Cleaning,
+    MaintenanceDue,
+}
+
+impl LightPatterns {
+    pub fn for_condition(&self, condition: LightCondition) -> &LightPattern {
+        match condition {
+            LightCondition::Off => &LightPattern::Off,
+            LightCondition::Idle => &self.idle,
+            LightCondition::Running => &self.running,
+            LightCondition::Busy => &self.busy,
+            LightCondition::RunOut => &self.run_out,
+            LightCondition::Fault => &self.fault,
+            LightCondition::Cleaning => &self.cleaning,
+            LightCondition::MaintenanceDue => &self.maintenance_due,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Lights {
-    red: HBridge,
-    green: HBridge,
+    channels: Vec<HBridge>,
+    colors: ColorEncodings,
 }
 impl Lights {
-    pub fn new(controller: Controller) -> Self {
+    pub fn new(controller: Controller, config: &LightsConfig) -> Self {
         Self {
-            red: controller.get_h_bridge(4),
-            green: controller.get_h_bridge(5),
+            channels: config
+                .channels
+                .iter()
+                .map(|id| controller.get_h_bridge(*id))
+                .collect(),
+            colors: config.colors.clone(),
+        }
+    }
+    async fn drive(&mut self, drives: &[ChannelDrive]) {
+        for (channel, drive) in self.channels.iter().zip(drives) {
+            channel.set_state((*drive).into()).await;
         }
     }
     pub async fn set_color(&mut self, color: LightColors) {
+        let drives = match color {
+            LightColors::Red => self.colors.red.clone(),
+            LightColors::Yellow => self.colors.yellow.clone(),
+            LightColors::Green => self.colors.green.clone(),
+        };
+        self.drive(&drives).await;
+    }
+    pub async fn turn_off(&mut self) {
+        let drives = self.colors.off.clone();
+        self.drive(&drives).await;
+    }
+    async fn show(&mut self, color: Option<LightColors>) {
         match color {
-            LightColors::Red => {
-                self.red.set_state(HBridgeState::Neg).await;
-                self.green.set_state(HBridgeState::Off).await;
-            }
-            LightColors::Green => {
-                self.red.set_state(HBridgeState::Pos).await;
-                self.green.set_state(HBridgeState::Neg).await;
-            }
-            LightColors::Yellow => {
-                self.red.set_state(HBridgeState::Neg).await;
-                self.green.set_state(HBridgeState::Neg).await;
-            }
+            Some(color) => self.set_color(color).await,
+            None => self.turn_off().await,
         }
     }
-    pub async fn turn_off(&mut self) {
-        self.red.set_state(HBridgeState::Pos).await;
-        self.green.set_state(HBridgeState::Pos).await;
+}
+
+// Runs the stack light on its own, restarting the pattern whenever the condition changes and
+// only writing to the H-bridges when the output changes
+pub async fn run_lights(state: &Mutex<AppData>, mut lights: Lights, config: LightsConfig) {
+    let mut ticker = interval(config.tick);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut condition = None;
+    let mut started = Instant::now();
+    let mut shown = None;
+    loop {
+        ticker.tick().await;
+        let current = state.lock().unwrap().light_condition();
+        if condition != Some(current) {
+            log::info!("Lights showing {:?}", current);
+            condition = Some(current);
+            started = Instant::now();
+            shown = None;
+        }
+        let color = config
+            .patterns
+            .for_condition(current)
+            .color_at(started.elapsed());
+        if shown != Some(color) {
+            lights.show(color).await;
+            shown = Some(color);
+        }
     }
 }
 
-pub enum LightColors {
-    Red,
-    Yellow,
-    Green
-}
\ No newline at end of file
+#[test]
+fn test_light_patterns() {
+    let period = Duration::from_millis(1000);
+    let blink = LightPattern::Blink {
+        color: LightColors::Red,
+        period,
+    };
+    assert_eq!(
+        blink.color_at(Duration::from_millis(100)),
+        Some(LightColors::Red)
+    );
+    assert_eq!(blink.color_at(Duration::from_millis(600)), None);
+    assert_eq!(
+        blink.color_at(Duration::from_millis(1100)),
+        Some(LightColors::Red)
+    );
+
+    let pulse = LightPattern::Pulse {
+        color: LightColors::Yellow,
+        on: Duration::from_millis(200),
+        period,
+    };
+    assert_eq!(
+        pulse.color_at(Duration::from_millis(150)),
+        Some(LightColors::Yellow)
+    );
+    assert_eq!(pulse.color_at(Duration::from_millis(300)), None);
+
+    let alternating = LightPattern::Alternating {
+        first: LightColors::Red,
+        second: LightColors::Green,
+        period,
+    };
+    assert_eq!(alternating.color_at(Duration::ZERO), Some(LightColors::Red));
+    assert_eq!(
+        alternating.color_at(Duration::from_millis(700)),
+        Some(LightColors::Green)
+    );
+}
@@ -0,0 +1,284 @@
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+use chrono::{DateTime, Duration, Utc};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+
+use crate::accounts::Role;
+use crate::config::LoggingConfig;
+use crate::session::CommandError;
+use crate::state::{AppData, IchibuState};
+
+const LOG_FILE: &str = "ichibu.log";
+
+// What the cycle is working on, added to every line it logs
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct LogContext {
+    pub node: String,
+    //Counts the portions started since the app came up
+    pub cycle: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<IchibuState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingredient: Option<String>,
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<LogContext>;
+}
+
+// Runs the node's cycle with its own log context, fleet nodes each get theirs
+pub async fn with_context<F: Future>(node: String, cycle: F) -> F::Output {
+    let context = LogContext {
+        node,
+        ..Default::default()
+    };
+    CONTEXT.scope(RefCell::new(context), cycle).await
+}
+
+//Does nothing outside with_context
+pub fn update_context(update: impl FnOnce(&mut LogContext)) {
+    let _ = CONTEXT.try_with(|context| update(&mut context.borrow_mut()));
+}
+
+#[derive(Serialize)]
+struct LogLine<'a> {
+    ts: String,
+    level: &'a str,
+    target: &'a str,
+    msg: String,
+    #[serde(flatten)]
+    context: Option<LogContext>,
+}
+
+// ichibu.log is written to, ichibu.log.1 is the newest rotated file
+struct RotatingFile {
+    dir: String,
+    file: Option<File>,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+fn file_path(dir: &str, index: usize) -> String {
+    match index {
+        0 => format!("{}/{}", dir, LOG_FILE),
+        _ => format!("{}/{}.{}", dir, LOG_FILE, index),
+    }
+}
+
+impl RotatingFile {
+    fn open(dir: &str, max_size: u64, max_files: usize) -> Self {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Couldn't create log directory {}: {}", dir, e);
+        }
+        let path = file_path(dir, 0);
+        let file = OpenOptions::new().create(true).append(true).open(&path);
+        if let Err(e) = &file {
+            eprintln!("Couldn't open log file {}: {}", path, e);
+        }
+        Self {
+            dir: dir.to_string(),
+            size: fs::metadata(&path).map_or(0, |metadata| metadata.len()),
+            file: file.ok(),
+            max_size,
+            max_files,
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.file = None;
+        for index in (1..=self.max_files).rev() {
+            let _ = fs::rename(file_path(&self.dir, index - 1), file_path(&self.dir, index));
+        }
+        *self = Self::open(&self.dir, self.max_size, self.max_files);
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.size + line.len() as u64 > self.max_size {
+            self.rotate();
+        }
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if writeln!(file, "{}", line).is_ok() {
+            self.size += line.len() as u64 + 1;
+        }
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    //Longest module path first so the most specific one wins
+    modules: Vec<(String, LevelFilter)>,
+    file: Mutex<RotatingFile>,
+}
+
+fn parse_level(level: &str) -> LevelFilter {
+    level.parse().unwrap_or_else(|_| {
+        eprintln!("Unknown log level {}, using info", level);
+        LevelFilter::Info
+    })
+}
+
+impl Logger {
+    fn new(config: &LoggingConfig, dir: &str) -> Self {
+        let mut modules: Vec<(String, LevelFilter)> = config
+            .modules
+            .iter()
+            .map(|(module, level)| (module.clone(), parse_level(level)))
+            .collect();
+        modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Self {
+            level: parse_level(&config.level),
+            modules,
+            file: Mutex::new(RotatingFile::open(
+                dir,
+                config.max_file_size_kb * 1024,
+                config.max_files,
+            )),
+        }
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| target == module || target.starts_with(&format!("{}::", module)))
+            .map_or(self.level, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = LogLine {
+            ts: Utc::now().to_rfc3339(),
+            level: record.level().as_str(),
+            target: record.target(),
+            msg: record.args().to_string(),
+            context: CONTEXT
+                .try_with(|context| context.try_borrow().ok().map(|c| c.clone()))
+                .ok()
+                .flatten(),
+        };
+        //Plain text still goes to the journal
+        eprintln!("[{} {} {}] {}", line.ts, line.level, line.target, line.msg);
+        if let Ok(json) = serde_json::to_string(&line) {
+            self.file.lock().unwrap().write_line(&json);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &mut self.file.lock().unwrap().file {
+            let _ = file.flush();
+        }
+    }
+}
+
+struct LogFiles {
+    dir: String,
+    max_files: usize,
+}
+
+static LOG_FILES: OnceLock<LogFiles> = OnceLock::new();
+
+// Call once, first thing, with the logs directory under the config directory
+pub fn init(config: &LoggingConfig, dir: &str) {
+    let logger = Logger::new(config, dir);
+    let max_level = logger.max_level();
+    if log::set_boxed_logger(Box::new(logger)).is_err() {
+        return;
+    }
+    log::set_max_level(max_level);
+    let _ = LOG_FILES.set(LogFiles {
+        dir: dir.to_string(),
+        max_files: config.max_files,
+    });
+}
+
+fn read_since(dir: &str, max_files: usize, since: DateTime<Utc>) -> String {
+    let mut recent = String::new();
+    for index in (0..=max_files).rev() {
+        let Ok(text) = fs::read_to_string(file_path(dir, index)) else {
+            continue;
+        };
+        for line in text.lines() {
+            let logged = serde_json::from_str::<serde_json::Value>(line)
+                .ok()
+                .and_then(|line| line["ts"].as_str().map(str::to_string))
+                .and_then(|ts| DateTime::parse_from_rfc3339(&ts).ok());
+            if logged.is_some_and(|logged| logged >= since) {
+                recent.push_str(line);
+                recent.push('\n');
+            }
+        }
+    }
+    recent
+}
+
+// For support, the JSON lines logged in the last few minutes
+pub fn get_recent_logs(state: &Mutex<AppData>, minutes: u32) -> Result<String, CommandError> {
+    state.lock().unwrap().authorize(Role::Manager)?;
+    let Some(files) = LOG_FILES.get() else {
+        return Err(CommandError::Failed(
+            "File logging isn't running".to_string(),
+        ));
+    };
+    let since = Utc::now() - Duration::minutes(minutes as i64);
+    Ok(read_since(&files.dir, files.max_files, since))
+}
+
+#[test]
+fn test_log_rotation() {
+    let dir = std::env::temp_dir().join("ichibu_logging_test");
+    let _ = fs::remove_dir_all(&dir);
+    let dir = dir.to_str().unwrap();
+    let mut file = RotatingFile::open(dir, 60, 2);
+    let line = |ts: &str, msg: &str| format!(r#"{{"ts":"{}","msg":"{}"}}"#, ts, msg);
+    file.write_line(&line("2024-01-01T10:00:00+00:00", "old"));
+    file.write_line(&line("2024-01-01T11:00:00+00:00", "rotated"));
+    file.write_line(&line("2024-01-01T12:00:00+00:00", "newest"));
+    file.write_line(&line("2024-01-01T12:30:00+00:00", "current"));
+    //Only two rotated files are kept, "old" fell off the end
+    assert!(!fs::read_to_string(file_path(dir, 2))
+        .unwrap()
+        .contains("old"));
+    let since = DateTime::parse_from_rfc3339("2024-01-01T11:30:00+00:00").unwrap();
+    let recent = read_since(dir, 2, since.with_timezone(&Utc));
+    assert_eq!(recent.lines().count(), 2);
+    assert!(recent.starts_with(&line("2024-01-01T12:00:00+00:00", "newest")));
+}
+
+#[test]
+fn test_module_levels() {
+    let dir = std::env::temp_dir().join("ichibu_logging_levels_test");
+    let mut config = LoggingConfig::default();
+    config
+        .modules
+        .insert("ichibu_lib::dispense".to_string(), "debug".to_string());
+    config
+        .modules
+        .insert("axum".to_string(), "warn".to_string());
+    let logger = Logger::new(&config, dir.to_str().unwrap());
+    assert_eq!(logger.level_for("ichibu_lib::dispense"), LevelFilter::Debug);
+    assert_eq!(logger.level_for("ichibu_lib::dispenser"), LevelFilter::Info);
+    assert_eq!(logger.level_for("axum::serve"), LevelFilter::Warn);
+    assert_eq!(logger.max_level(), LevelFilter::Debug);
+}
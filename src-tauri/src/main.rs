@@ -2,6 +2,19 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
-    env_logger::init();
-    ichibu_lib::run()
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        //Controls only, for nodes without a display
+        Some("--headless") => ichibu_lib::daemon::run_headless(),
+        //Plays a saved cycle trace back against simulated hardware
+        Some("--replay") => ichibu_lib::trace::replay_cli(&args[2..]),
+        //Every node listed in fleet.toml from one process
+        Some("--fleet") => ichibu_lib::run_fleet(),
+        //UI only, driving a daemon on another node
+        Some("--connect") => {
+            let url = args.get(2).expect("--connect needs the daemon's url");
+            ichibu_lib::run_client(url)
+        }
+        _ => ichibu_lib::run(),
+    }
 }
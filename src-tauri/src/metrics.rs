@@ -0,0 +1,175 @@
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::config::MetricsConfig;
+
+// Everything is labelled by node so a fleet reports each of its nodes from one endpoint
+pub struct Metrics {
+    registry: Registry,
+    pub bowls_dispensed: IntCounterVec,
+    pub dispense_duration: HistogramVec,
+    pub weight_error: HistogramVec,
+    pub hatch_move: HistogramVec,
+    pub hatch_faults: IntCounterVec,
+    pub run_outs: IntCounterVec,
+    pub motor_alerts: IntCounterVec,
+    pub scale_read: HistogramVec,
+    pub cycle_iteration: HistogramVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+fn histogram(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    labels: &[&str],
+    buckets: Vec<f64>,
+) -> HistogramVec {
+    let histogram =
+        HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), labels).unwrap();
+    registry.register(Box::new(histogram.clone())).unwrap();
+    histogram
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("ichibu".to_string()), None).unwrap();
+        Self {
+            bowls_dispensed: counter(
+                &registry,
+                "bowls_dispensed_total",
+                "Bowls served",
+                &["node", "ingredient", "size"],
+            ),
+            dispense_duration: histogram(
+                &registry,
+                "dispense_duration_seconds",
+                "Time spent dispensing a portion, without the hold before the top-up",
+                &["node", "ingredient"],
+                vec![1., 2., 5., 10., 20., 30., 60., 120., 300.],
+            ),
+            weight_error: histogram(
+                &registry,
+                "dispense_weight_error_grams",
+                "Served weight minus the portion target",
+                &["node", "ingredient"],
+                vec![-20., -10., -5., -2., -1., 0., 1., 2., 5., 10., 20.],
+            ),
+            hatch_move: histogram(
+                &registry,
+                "hatch_move_seconds",
+                "Time for the hatch to reach its sensor",
+                &["node"],
+                vec![0.25, 0.5, 1., 2., 3., 4., 6.],
+            ),
+            hatch_faults: counter(
+                &registry,
+                "hatch_faults_total",
+                "Hatch moves that timed out",
+                &["node"],
+            ),
+            run_outs: counter(
+                &registry,
+                "run_outs_total",
+                "Times the dispenser ran out",
+                &["node", "ingredient"],
+            ),
+            motor_alerts: counter(
+                &registry,
+                "motor_alerts_total",
+                "Motors found faulted",
+                &["node", "motor"],
+            ),
+            scale_read: histogram(
+                &registry,
+                "scale_read_seconds",
+                "Time to read a weight from the scale",
+                &["node"],
+                vec![0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1],
+            ),
+            cycle_iteration: histogram(
+                &registry,
+                "cycle_iteration_seconds",
+                "Time for one pass of the cycle loop",
+                &["node"],
+                vec![0.5, 1., 2., 5., 10., 30., 60., 300.],
+            ),
+            registry,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+pub fn router() -> Router {
+    Router::new().route("/metrics", get(scrape))
+}
+
+async fn scrape() -> Response {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        METRICS.render(),
+    )
+        .into_response()
+}
+
+pub async fn serve(config: MetricsConfig) {
+    let address = SocketAddr::from((config.address, config.port));
+    let listener = match tokio::net::TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Metrics couldn't bind {}: {}", address, e);
+            return;
+        }
+    };
+    log::info!("Metrics served on {}/metrics", address);
+    if let Err(e) = axum::serve(listener, router()).await {
+        log::error!("Metrics stopped: {}", e);
+    }
+}
+
+//One endpoint per process, a fleet's nodes share it
+pub fn spawn(config: &MetricsConfig) {
+    if config.enabled {
+        tauri::async_runtime::spawn(serve(config.clone()));
+    }
+}
+
+#[test]
+fn test_metrics_render() {
+    METRICS
+        .bowls_dispensed
+        .with_label_values(&["test", "Edamame", "Regular"])
+        .inc();
+    METRICS
+        .weight_error
+        .with_label_values(&["test", "Edamame"])
+        .observe(-1.5);
+    let text = METRICS.render();
+    assert!(text.contains(
+        r#"ichibu_bowls_dispensed_total{ingredient="Edamame",node="test",size="Regular"} 1"#
+    ));
+    assert!(text.contains("ichibu_dispense_weight_error_grams_bucket"));
+}
@@ -0,0 +1,221 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::OrderApiConfig;
+use crate::ingredients::{read_ingredient_config, Ingredient};
+use crate::orders::{NewOrder, OrderBook};
+use crate::HOME_DIRECTORY;
+
+#[derive(Clone)]
+pub struct OrderApi {
+    orders: OrderBook,
+    //None reads the ingredient config for every order, so edits apply without a restart
+    ingredients: Option<Arc<Vec<Ingredient>>>,
+}
+
+impl OrderApi {
+    pub fn new(orders: OrderBook, ingredients: Vec<Ingredient>) -> Self {
+        Self {
+            orders,
+            ingredients: Some(Arc::new(ingredients)),
+        }
+    }
+
+    pub fn load(orders: OrderBook) -> Self {
+        Self {
+            orders,
+            ingredients: None,
+        }
+    }
+
+    fn find_ingredient(&self, id: usize) -> Result<Option<Ingredient>, String> {
+        let find = |ingredients: &[Ingredient]| {
+            ingredients
+                .iter()
+                .find(|ingredient| ingredient.id == id)
+                .cloned()
+        };
+        match &self.ingredients {
+            Some(ingredients) => Ok(find(ingredients)),
+            None => read_ingredient_config(HOME_DIRECTORY.as_str())
+                .map(|config| find(&config.ingredients))
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+pub fn router(api: OrderApi) -> Router {
+    Router::new()
+        .route("/orders", get(list_orders).post(submit_order))
+        .route("/orders/ws", get(order_updates))
+        .route("/orders/:id", get(get_order).delete(cancel_order))
+        .with_state(api)
+}
+
+// Served next to the kiosk for a POS or kitchen display to send orders
+pub async fn serve(config: OrderApiConfig, orders: OrderBook) {
+    let address = SocketAddr::from((config.address, config.port));
+    let listener = match tokio::net::TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Order API couldn't bind {}: {}", address, e);
+            return;
+        }
+    };
+    log::info!("Order API listening on {}", address);
+    if let Err(e) = axum::serve(listener, router(OrderApi::load(orders))).await {
+        log::error!("Order API stopped: {}", e);
+    }
+}
+
+async fn list_orders(State(api): State<OrderApi>) -> Response {
+    Json(api.orders.list()).into_response()
+}
+
+async fn submit_order(State(api): State<OrderApi>, Json(new_order): Json<NewOrder>) -> Response {
+    let ingredient = match api.find_ingredient(new_order.ingredient_id) {
+        Ok(Some(ingredient)) => ingredient,
+        Ok(None) => {
+            let error = format!("Unknown ingredient {}", new_order.ingredient_id);
+            return (StatusCode::NOT_FOUND, error).into_response();
+        }
+        Err(e) => {
+            log::error!("Order API couldn't read ingredients: {}", e);
+            let error = "Ingredients unavailable".to_string();
+            return (StatusCode::SERVICE_UNAVAILABLE, error).into_response();
+        }
+    };
+    if ingredient.portion_size(&new_order.size).is_none() {
+        let error = format!("{} has no {} size", ingredient.name, new_order.size);
+        return (StatusCode::BAD_REQUEST, error).into_response();
+    }
+    if !api.orders.is_loaded(ingredient.id) {
+        let error = format!("{} isn't loaded on any node", ingredient.name);
+        return (StatusCode::CONFLICT, error).into_response();
+    }
+    let order = api.orders.submit(new_order);
+    (StatusCode::CREATED, Json(order)).into_response()
+}
+
+async fn get_order(State(api): State<OrderApi>, Path(id): Path<u64>) -> Response {
+    match api.orders.get(id) {
+        Some(order) => Json(order).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+//Only orders that haven't started can be cancelled
+async fn cancel_order(State(api): State<OrderApi>, Path(id): Path<u64>) -> Response {
+    if api.orders.get(id).is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match api.orders.cancel(id) {
+        Some(order) => Json(order).into_response(),
+        None => StatusCode::CONFLICT.into_response(),
+    }
+}
+
+async fn order_updates(State(api): State<OrderApi>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| stream_updates(socket, api.orders))
+}
+
+async fn stream_updates(mut socket: WebSocket, orders: OrderBook) {
+    let mut updates = orders.subscribe();
+    loop {
+        let order = match updates.recv().await {
+            Ok(order) => order,
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("Order websocket missed {} updates", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Ok(text) = serde_json::to_string(&order) else {
+            continue;
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_mock_pos_orders() {
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    // Stands in for a POS talking to the kiosk
+    async fn send(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(body.map_or(Body::empty(), |body| Body::from(body.to_string())))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    let orders = OrderBook::default();
+    let snack = Ingredient {
+        id: 1,
+        ..Default::default()
+    };
+    let app = router(OrderApi::new(orders.clone(), vec![snack]));
+    let order = json!({"ingredient_id": 1, "size": "Regular"});
+    let (status, _) = send(&app, "POST", "/orders", Some(order)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    orders.set_loaded("ichibu", 1);
+
+    let order = json!({"ingredient_id": 1, "size": "Regular"});
+    let (status, created) = send(&app, "POST", "/orders", Some(order)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["status"], "Queued");
+    let uri = format!("/orders/{}", created["id"]);
+
+    let order = json!({"ingredient_id": 1, "size": "Huge"});
+    let (status, _) = send(&app, "POST", "/orders", Some(order)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let order = json!({"ingredient_id": 9, "size": "Small"});
+    let (status, _) = send(&app, "POST", "/orders", Some(order)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // The cycle picks it up, serves it and the bowl is taken
+    let mut updates = orders.subscribe();
+    assert!(orders.start_next("ichibu", 1).is_some());
+    let (_, dispensing) = send(&app, "GET", &uri, None).await;
+    assert_eq!(dispensing["status"], "Dispensing");
+    orders.finish_dispensing("ichibu");
+    orders.picked_up("ichibu");
+    let (_, picked_up) = send(&app, "GET", &uri, None).await;
+    assert_eq!(picked_up["status"], "PickedUp");
+    assert_eq!(
+        updates.recv().await.unwrap().status,
+        crate::orders::OrderStatus::Dispensing
+    );
+
+    let (status, _) = send(&app, "DELETE", &uri, None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (_, listed) = send(&app, "GET", "/orders", None).await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+}
@@ -0,0 +1,305 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+// Finished orders kept around so the POS can still look them up
+const FINISHED_ORDERS_KEPT: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum OrderStatus {
+    Queued,
+    Dispensing,
+    Ready,
+    PickedUp,
+    Failed(String),
+}
+
+impl OrderStatus {
+    fn is_finished(&self) -> bool {
+        matches!(self, OrderStatus::PickedUp | OrderStatus::Failed(_))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct NewOrder {
+    pub ingredient_id: usize,
+    //Name of one of the ingredient's portion sizes
+    pub size: String,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Order {
+    pub id: u64,
+    pub ingredient_id: usize,
+    pub size: String,
+    pub status: OrderStatus,
+    //Node dispensing it, where it is picked up
+    pub node: Option<String>,
+    pub created_at: String,
+}
+
+// Orders in the order they came in, shared by every node on the station. Each node takes the
+// oldest order for the snack it has loaded, dispenses one at a time and has one sitting under
+// its chute waiting to be picked up.
+#[derive(Default)]
+pub struct OrderQueue {
+    next_id: u64,
+    orders: VecDeque<Order>,
+    loaded: HashMap<String, usize>,
+    dispensing: HashMap<String, u64>,
+    awaiting_pickup: HashMap<String, u64>,
+}
+
+impl OrderQueue {
+    pub fn submit(&mut self, new_order: NewOrder) -> Order {
+        self.next_id += 1;
+        let order = Order {
+            id: self.next_id,
+            ingredient_id: new_order.ingredient_id,
+            size: new_order.size,
+            status: OrderStatus::Queued,
+            node: None,
+            created_at: chrono::Utc::now().to_string(),
+        };
+        self.orders.push_back(order.clone());
+        self.prune();
+        order
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Order> {
+        self.orders.iter().find(|order| order.id == id)
+    }
+
+    pub fn list(&self) -> Vec<Order> {
+        self.orders.iter().cloned().collect()
+    }
+
+    pub fn set_loaded(&mut self, node: &str, ingredient: usize) {
+        self.loaded.insert(node.to_string(), ingredient);
+    }
+
+    //Whether any node has the ingredient loaded
+    pub fn is_loaded(&self, ingredient: usize) -> bool {
+        self.loaded.values().any(|loaded| *loaded == ingredient)
+    }
+
+    pub fn dispensing(&self, node: &str) -> Option<u64> {
+        self.dispensing.get(node).copied()
+    }
+
+    fn set_status(&mut self, id: u64, status: OrderStatus) -> Option<Order> {
+        let order = self.orders.iter_mut().find(|order| order.id == id)?;
+        order.status = status;
+        Some(order.clone())
+    }
+
+    //Orders for other snacks are left for the nodes that have them loaded
+    pub fn start_next(&mut self, node: &str, loaded_ingredient: usize) -> Option<Order> {
+        if self.dispensing.contains_key(node) {
+            return None;
+        }
+        let order = self.orders.iter_mut().find(|order| {
+            order.status == OrderStatus::Queued && order.ingredient_id == loaded_ingredient
+        })?;
+        order.status = OrderStatus::Dispensing;
+        order.node = Some(node.to_string());
+        self.dispensing.insert(node.to_string(), order.id);
+        Some(order.clone())
+    }
+
+    //The portion dropped into the bowl
+    pub fn finish_dispensing(&mut self, node: &str) -> Option<Order> {
+        let id = self.dispensing.remove(node)?;
+        self.awaiting_pickup.insert(node.to_string(), id);
+        self.set_status(id, OrderStatus::Ready)
+    }
+
+    pub fn fail_dispensing(&mut self, node: &str, reason: &str) -> Option<Order> {
+        let id = self.dispensing.remove(node)?;
+        self.set_status(id, OrderStatus::Failed(reason.to_string()))
+    }
+
+    //The photo eye saw the bowl leave
+    pub fn picked_up(&mut self, node: &str) -> Option<Order> {
+        let id = self.awaiting_pickup.remove(node)?;
+        self.set_status(id, OrderStatus::PickedUp)
+    }
+
+    pub fn cancel(&mut self, id: u64) -> Option<Order> {
+        match self.get(id)?.status {
+            OrderStatus::Queued => {
+                self.set_status(id, OrderStatus::Failed("Cancelled".to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    fn prune(&mut self) {
+        while self
+            .orders
+            .iter()
+            .filter(|order| order.status.is_finished())
+            .count()
+            > FINISHED_ORDERS_KEPT
+        {
+            let Some(oldest) = self
+                .orders
+                .iter()
+                .position(|order| order.status.is_finished())
+            else {
+                break;
+            };
+            self.orders.remove(oldest);
+        }
+    }
+}
+
+// Shared between the cycle and the order API, every status change is broadcast for the websocket
+#[derive(Clone)]
+pub struct OrderBook {
+    queue: Arc<Mutex<OrderQueue>>,
+    updates: broadcast::Sender<Order>,
+}
+
+impl Default for OrderBook {
+    fn default() -> Self {
+        let (updates, _) = broadcast::channel(64);
+        Self {
+            queue: Arc::default(),
+            updates,
+        }
+    }
+}
+
+impl OrderBook {
+    pub fn subscribe(&self) -> broadcast::Receiver<Order> {
+        self.updates.subscribe()
+    }
+
+    fn publish(&self, orders: impl IntoIterator<Item = Order>) {
+        for order in orders {
+            log::info!("Order {} is {:?}", order.id, order.status);
+            //No subscribers is fine
+            let _ = self.updates.send(order);
+        }
+    }
+
+    pub fn submit(&self, new_order: NewOrder) -> Order {
+        let order = self.queue.lock().unwrap().submit(new_order);
+        self.publish([order.clone()]);
+        order
+    }
+
+    pub fn get(&self, id: u64) -> Option<Order> {
+        self.queue.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Order> {
+        self.queue.lock().unwrap().list()
+    }
+
+    pub fn cancel(&self, id: u64) -> Option<Order> {
+        let order = self.queue.lock().unwrap().cancel(id);
+        self.publish(order.clone());
+        order
+    }
+
+    pub fn set_loaded(&self, node: &str, ingredient: usize) {
+        self.queue.lock().unwrap().set_loaded(node, ingredient);
+    }
+
+    pub fn is_loaded(&self, ingredient: usize) -> bool {
+        self.queue.lock().unwrap().is_loaded(ingredient)
+    }
+
+    pub fn start_next(&self, node: &str, loaded_ingredient: usize) -> Option<Order> {
+        let order = self
+            .queue
+            .lock()
+            .unwrap()
+            .start_next(node, loaded_ingredient);
+        self.publish(order.clone());
+        order
+    }
+
+    pub fn is_dispensing(&self, node: &str) -> bool {
+        self.queue.lock().unwrap().dispensing(node).is_some()
+    }
+
+    pub fn finish_dispensing(&self, node: &str) {
+        let order = self.queue.lock().unwrap().finish_dispensing(node);
+        self.publish(order);
+    }
+
+    pub fn fail_dispensing(&self, node: &str, reason: &str) {
+        let order = self.queue.lock().unwrap().fail_dispensing(node, reason);
+        self.publish(order);
+    }
+
+    pub fn picked_up(&self, node: &str) {
+        let order = self.queue.lock().unwrap().picked_up(node);
+        self.publish(order);
+    }
+}
+
+#[test]
+fn test_order_lifecycle() {
+    let mut queue = OrderQueue::default();
+    let other = queue.submit(NewOrder {
+        ingredient_id: 2,
+        size: "Small".to_string(),
+    });
+    let first = queue.submit(NewOrder {
+        ingredient_id: 1,
+        size: "Regular".to_string(),
+    });
+    let second = queue.submit(NewOrder {
+        ingredient_id: 1,
+        size: "Small".to_string(),
+    });
+
+    let started = queue.start_next("left", 1).unwrap();
+    assert_eq!(started.id, first.id);
+    assert_eq!(started.node.as_deref(), Some("left"));
+    // Left for a node with the other snack
+    assert_eq!(queue.get(other.id).unwrap().status, OrderStatus::Queued);
+    // Only one at a time per node
+    assert_eq!(queue.start_next("left", 1), None);
+
+    queue.finish_dispensing("left");
+    assert_eq!(queue.get(first.id).unwrap().status, OrderStatus::Ready);
+    queue.picked_up("left");
+    assert_eq!(queue.get(first.id).unwrap().status, OrderStatus::PickedUp);
+
+    queue.start_next("left", 1);
+    queue.fail_dispensing("left", "Ran out");
+    assert_eq!(
+        queue.get(second.id).unwrap().status,
+        OrderStatus::Failed("Ran out".to_string())
+    );
+    assert_eq!(queue.cancel(second.id), None);
+}
+
+#[test]
+fn test_orders_routed_between_nodes() {
+    let mut queue = OrderQueue::default();
+    queue.set_loaded("left", 1);
+    queue.set_loaded("right", 2);
+    assert!(queue.is_loaded(2));
+    assert!(!queue.is_loaded(3));
+    let rice = queue.submit(NewOrder {
+        ingredient_id: 1,
+        size: "Regular".to_string(),
+    });
+    let chips = queue.submit(NewOrder {
+        ingredient_id: 2,
+        size: "Regular".to_string(),
+    });
+    assert_eq!(queue.start_next("right", 2).unwrap().id, chips.id);
+    assert_eq!(queue.start_next("left", 1).unwrap().id, rice.id);
+    queue.finish_dispensing("right");
+    assert_eq!(queue.dispensing("right"), None);
+    assert_eq!(queue.dispensing("left"), Some(rice.id));
+}
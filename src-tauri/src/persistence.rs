@@ -0,0 +1,70 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::portion::DispenseType;
+use crate::state::IchibuState;
+
+const STATE_FILE: &str = "state.toml";
+
+// What the machine was doing, so it can offer to pick up where it left off after a reboot
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SavedState {
+    pub snack_id: Option<usize>,
+    pub run_state: IchibuState,
+    #[serde(default)]
+    pub dispense_type: DispenseType,
+    pub cycle_dispense_count: usize,
+    pub timed_out: bool,
+    //Still set if the power went while a portion was being built
+    pub mid_cycle: bool,
+}
+
+impl SavedState {
+    fn path(dir: &str) -> String {
+        format!("{}/{}", dir, STATE_FILE)
+    }
+
+    //dir is the node's config directory
+    pub fn load(dir: &str) -> Option<Self> {
+        let text = fs::read_to_string(Self::path(dir)).ok()?;
+        match toml::from_str(&text) {
+            Ok(saved) => Some(saved),
+            Err(e) => {
+                log::warn!("Ignoring unreadable saved state: {}", e);
+                None
+            }
+        }
+    }
+
+    // Written to a temp file and renamed over the old one so a power cut never leaves half a file
+    pub fn save(&self, dir: &str) -> Result<(), Box<dyn Error>> {
+        let path = Self::path(dir);
+        let temp_path = format!("{}.tmp", path);
+        let text = toml::to_string(self)?;
+        let mut file = File::create(&temp_path)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_saved_state_round_trip() {
+    let dir = std::env::temp_dir().join("ichibu_saved_state_test");
+    fs::create_dir_all(&dir).unwrap();
+    let dir = dir.to_str().unwrap();
+    let saved = SavedState {
+        snack_id: Some(3),
+        run_state: IchibuState::Running,
+        dispense_type: DispenseType::LargeSmall,
+        cycle_dispense_count: 12,
+        timed_out: false,
+        mid_cycle: true,
+    };
+    saved.save(dir).unwrap();
+    assert_eq!(SavedState::load(dir), Some(saved));
+}
@@ -0,0 +1,151 @@
+use node_diagnostics::dispenser::DispenseSettings;
+use serde::{Deserialize, Serialize};
+
+use crate::data_logging::DataAction;
+use crate::ingredients::Ingredient;
+use crate::UiRequest;
+
+// How a snack is portioned. The cycle pre-dispenses the base portion with the hatch closed,
+// then tops up to whatever the customer asks for.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub enum DispenseType {
+    //One portion size from the snack's dispense settings, any request serves it
+    #[default]
+    Classic,
+    //Small is pre-dispensed, regular tops it up
+    LargeSmall,
+    //The smallest of the snack's sizes is pre-dispensed and topped up to the one asked for
+    MultiSize,
+}
+
+impl DispenseType {
+    pub fn base_target(&self, snack: &Ingredient) -> f64 {
+        match self {
+            DispenseType::Classic => snack.dispense_settings.weight,
+            DispenseType::LargeSmall => snack.min_setpoint as f64,
+            DispenseType::MultiSize => snack
+                .portion_sizes()
+                .first()
+                .map_or(snack.min_setpoint as f64, |size| size.target),
+        }
+    }
+
+    //Weight the requested portion should reach, None if the snack has no such size
+    pub fn requested_target(&self, snack: &Ingredient, request: &UiRequest) -> Option<f64> {
+        let sizes = snack.portion_sizes();
+        match (self, request) {
+            (_, UiRequest::None) => None,
+            (DispenseType::Classic, _) => Some(snack.dispense_settings.weight),
+            (DispenseType::LargeSmall, UiRequest::SmallDispense) => Some(snack.min_setpoint as f64),
+            (DispenseType::LargeSmall, UiRequest::RegularDispense) => {
+                Some(snack.max_setpoint as f64)
+            }
+            (DispenseType::MultiSize, UiRequest::SmallDispense) => {
+                sizes.first().map(|size| size.target)
+            }
+            (DispenseType::MultiSize, UiRequest::RegularDispense) => {
+                sizes.last().map(|size| size.target)
+            }
+            (_, UiRequest::Size(name)) => snack.portion_size(name).map(|size| size.target),
+        }
+    }
+
+    pub fn served_action(&self, request: &UiRequest) -> DataAction {
+        match (self, request) {
+            (DispenseType::Classic, _) => DataAction::DispensedRegular,
+            (_, UiRequest::SmallDispense) => DataAction::DispensedSmall,
+            (_, UiRequest::Size(name)) => DataAction::DispensedSize(name.clone()),
+            _ => DataAction::DispensedRegular,
+        }
+    }
+}
+
+// A portion this close to its target is served as it is
+const TOP_UP_TOLERANCE: f64 = 1.;
+
+//The snack's dispense settings, aimed at what is still missing from the portion. None once
+//the portion already reaches the target.
+pub fn top_up_settings(snack: &Ingredient, target: f64, portion: f64) -> Option<DispenseSettings> {
+    if portion >= target - TOP_UP_TOLERANCE {
+        return None;
+    }
+    Some(DispenseSettings {
+        weight: target - portion,
+        ..snack.dispense_settings.clone()
+    })
+}
+
+#[test]
+fn test_portion_targets() {
+    let snack = Ingredient {
+        min_setpoint: 10,
+        max_setpoint: 25,
+        dispense_settings: DispenseSettings {
+            weight: 30.,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let classic = DispenseType::Classic;
+    assert_eq!(classic.base_target(&snack), 30.);
+    assert_eq!(classic.requested_target(&snack, &UiRequest::None), None);
+    assert_eq!(
+        classic.requested_target(&snack, &UiRequest::SmallDispense),
+        Some(30.)
+    );
+
+    let sized = DispenseType::LargeSmall;
+    assert_eq!(sized.base_target(&snack), 10.);
+    assert_eq!(
+        sized.requested_target(&snack, &UiRequest::SmallDispense),
+        Some(10.)
+    );
+    assert_eq!(
+        sized.requested_target(&snack, &UiRequest::RegularDispense),
+        Some(25.)
+    );
+}
+
+#[test]
+fn test_multi_size_targets() {
+    use crate::ingredients::PortionSize;
+
+    let size = |name: &str, target: f64| PortionSize {
+        name: name.to_string(),
+        target,
+        price: None,
+    };
+    let snack = Ingredient {
+        sizes: vec![size("Large", 40.), size("Kids", 8.), size("Medium", 20.)],
+        ..Default::default()
+    };
+    let multi = DispenseType::MultiSize;
+    assert_eq!(multi.base_target(&snack), 8.);
+    assert_eq!(
+        multi.requested_target(&snack, &UiRequest::Size("Medium".to_string())),
+        Some(20.)
+    );
+    assert_eq!(
+        multi.requested_target(&snack, &UiRequest::RegularDispense),
+        Some(40.)
+    );
+    assert_eq!(
+        multi.requested_target(&snack, &UiRequest::Size("Huge".to_string())),
+        None
+    );
+    assert_eq!(
+        multi.served_action(&UiRequest::Size("Kids".to_string())),
+        DataAction::DispensedSize("Kids".to_string())
+    );
+}
+
+#[test]
+fn test_top_up_settings() {
+    let snack = Ingredient::default();
+    assert_eq!(
+        top_up_settings(&snack, 30., 12.).map(|s| s.weight),
+        Some(18.)
+    );
+    assert!(top_up_settings(&snack, 30., 30. - TOP_UP_TOLERANCE).is_none());
+    assert!(top_up_settings(&snack, 30., 35.).is_none());
+}
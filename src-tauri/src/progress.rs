@@ -0,0 +1,196 @@
+use control_components::components::clear_core_motor::ClearCoreMotor;
+use libra::scale::ConnectedScale;
+use node_diagnostics::dispenser::{DispenseOutcome, DispenseSettings};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+use crate::config::PhidgetConfig;
+use crate::daemon::connect_scale;
+use crate::events::EventSink;
+use crate::metrics::METRICS;
+
+pub const DISPENSE_PROGRESS_EVENT: &str = "dispense-progress";
+// Each step is this share of the dispense, but never less than MIN_STEP grams
+const STEP_FRACTION: f64 = 0.25;
+const MIN_STEP: f64 = 5.;
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DispenseProgress {
+    pub target: f64,
+    pub weight: Option<f64>,
+    pub progress: Option<f64>,
+    pub elapsed_ms: u64,
+    pub complete: bool,
+}
+
+fn read_weight(scale: &ConnectedScale) -> Option<f64> {
+    match scale.get_weight() {
+        Ok(weight) => Some(weight),
+        Err(e) => {
+            log::warn!("Couldn't read scale for progress: {:?}", e);
+            None
+        }
+    }
+}
+
+// Streams the portion being built to the UI. Weights are relative to the empty closed hatch.
+pub struct ProgressEmitter {
+    events: EventSink,
+    node: String,
+    empty_weight: Option<f64>,
+    last_weight: Option<f64>,
+    started: Instant,
+    //Time spent dispensing this portion, without the hold between base and top-up
+    dispensing: Duration,
+    //Shared by all the steps of one dispense
+    timeout: Duration,
+    phidget: PhidgetConfig,
+    //Portion weights read since the cycle trace last took them
+    samples: Vec<(Instant, Option<f64>)>,
+}
+
+impl ProgressEmitter {
+    pub fn new(events: EventSink, node: String, timeout: Duration, phidget: PhidgetConfig) -> Self {
+        Self {
+            events,
+            node,
+            empty_weight: None,
+            last_weight: None,
+            started: Instant::now(),
+            dispensing: Duration::ZERO,
+            timeout,
+            phidget,
+            samples: Vec::new(),
+        }
+    }
+
+    //Call with the hatch closed and nothing on it
+    pub fn start_portion(&mut self, empty_weight: Option<f64>) {
+        self.empty_weight = empty_weight;
+        self.last_weight = Some(0.);
+        self.started = Instant::now();
+        self.dispensing = Duration::ZERO;
+        self.samples.push((self.started, self.last_weight));
+    }
+
+    fn emit(&self, target: f64, complete: bool) {
+        let progress = DispenseProgress {
+            target,
+            weight: self.last_weight,
+            progress: self
+                .last_weight
+                .filter(|_| target > 0.)
+                .map(|weight| (weight / target * 100.).clamp(0., 100.)),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            complete,
+        };
+        self.events.emit(DISPENSE_PROGRESS_EVENT, progress);
+    }
+
+    // The dispense routine owns the scale while it runs, so the weight is dispensed in steps
+    // with a scale read between them to report the portion as it builds. Returns the scale and
+    // whether the dispense timed out, a failed dispense counts as a timeout.
+    pub async fn dispense(
+        &mut self,
+        conveyor: &ClearCoreMotor,
+        mut scale: ConnectedScale,
+        settings: DispenseSettings,
+        target: f64,
+    ) -> (ConnectedScale, bool) {
+        if settings.weight <= 0. {
+            return (scale, false);
+        }
+        let deadline = Instant::now() + self.timeout;
+        let start = self.last_weight;
+        let step = (settings.weight * STEP_FRACTION).max(MIN_STEP);
+        let mut dispensed = 0.;
+        loop {
+            let step_settings = DispenseSettings {
+                weight: (settings.weight - dispensed).min(step),
+                ..settings.clone()
+            };
+            let step_started = Instant::now();
+            let outcome = DispenseOutcome::dispense(conveyor, scale, step_settings.clone()).await;
+            self.dispensing += step_started.elapsed();
+            let timed_out;
+            (scale, timed_out) = match outcome {
+                Ok(DispenseOutcome::Success(_, scale)) => (scale, false),
+                Ok(DispenseOutcome::Timeout(_, scale)) => (scale, true),
+                Err(e) => {
+                    log::error!("Dispense failed: {:?}", e);
+                    return (self.reconnect_scale().await, true);
+                }
+            };
+            let weight = self.read_weight(&scale);
+            self.update(target, weight);
+            // A missed read counts the step as delivered so the loop still ends
+            dispensed = match (self.last_weight, start) {
+                (Some(weight), Some(start)) => weight - start,
+                _ => dispensed + step_settings.weight,
+            };
+            if timed_out || dispensed >= settings.weight {
+                return (scale, timed_out);
+            }
+            if Instant::now() >= deadline {
+                log::warn!("Dispense timed out after {:?}", self.timeout);
+                return (scale, true);
+            }
+        }
+    }
+
+    // The failed dispense dropped the scale, keep trying until it is back
+    async fn reconnect_scale(&self) -> ConnectedScale {
+        loop {
+            match connect_scale(&self.phidget) {
+                Ok(scale) => return scale,
+                Err(e) => {
+                    log::error!("Couldn't reconnect scale: {:?}", e);
+                    sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    pub fn read_weight(&self, scale: &ConnectedScale) -> Option<f64> {
+        let started = Instant::now();
+        let weight = read_weight(scale);
+        METRICS
+            .scale_read
+            .with_label_values(&[&self.node])
+            .observe(started.elapsed().as_secs_f64());
+        weight
+    }
+
+    pub fn dispense_time(&self) -> Duration {
+        self.dispensing
+    }
+
+    pub fn portion_weight(&self) -> Option<f64> {
+        self.last_weight
+    }
+
+    fn set_weight(&mut self, weight: Option<f64>) {
+        self.last_weight = match (weight, self.empty_weight) {
+            (Some(weight), Some(empty)) => Some(weight - empty),
+            _ => None,
+        };
+        self.samples.push((Instant::now(), self.last_weight));
+    }
+
+    pub fn take_samples(&mut self) -> Vec<(Instant, Option<f64>)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn update(&mut self, target: f64, weight: Option<f64>) {
+        self.set_weight(weight);
+        self.emit(target, false);
+    }
+
+    pub fn finish(&mut self, target: f64, weight: Option<f64>) {
+        self.set_weight(weight);
+        log::info!("Portion complete: {:?} of {}", self.last_weight, target);
+        self.emit(target, true);
+    }
+}
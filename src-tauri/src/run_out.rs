@@ -0,0 +1,109 @@
+use crate::config::DispensePolicy;
+
+#[derive(Debug, PartialEq)]
+pub enum DispenseDecision {
+    Accept,
+    Retry { prime: bool },
+    RunOut,
+}
+
+// Decides after every dispense whether the hopper has run out, based on consecutive timeouts
+#[derive(Debug, Default)]
+pub struct RunOutTracker {
+    policy: DispensePolicy,
+    consecutive_timeouts: usize,
+}
+
+impl RunOutTracker {
+    pub fn new(policy: DispensePolicy) -> Self {
+        Self {
+            policy,
+            consecutive_timeouts: 0,
+        }
+    }
+
+    pub fn policy(&self) -> &DispensePolicy {
+        &self.policy
+    }
+
+    pub fn consecutive_timeouts(&self) -> usize {
+        self.consecutive_timeouts
+    }
+
+    //Called after a refill or cleaning
+    pub fn reset(&mut self) {
+        self.consecutive_timeouts = 0;
+    }
+
+    // weight is the portion on the hatch so far, target is what this portion should reach
+    pub fn record(
+        &mut self,
+        timed_out: bool,
+        weight: Option<f64>,
+        target: f64,
+    ) -> DispenseDecision {
+        let acceptable =
+            weight.is_some_and(|weight| weight >= target * self.policy.acceptable_partial_weight);
+        if !timed_out || acceptable {
+            self.consecutive_timeouts = 0;
+            return DispenseDecision::Accept;
+        }
+        self.consecutive_timeouts += 1;
+        if self.consecutive_timeouts >= self.policy.max_consecutive_timeouts {
+            DispenseDecision::RunOut
+        } else {
+            DispenseDecision::Retry {
+                prime: self.policy.prime_on_retry,
+            }
+        }
+    }
+}
+
+#[test]
+fn test_run_out_after_consecutive_timeouts() {
+    let mut tracker = RunOutTracker::new(DispensePolicy {
+        max_consecutive_timeouts: 3,
+        prime_on_retry: true,
+        acceptable_partial_weight: 0.8,
+    });
+    assert_eq!(
+        tracker.record(false, Some(20.), 20.),
+        DispenseDecision::Accept
+    );
+    assert_eq!(
+        tracker.record(true, Some(2.), 20.),
+        DispenseDecision::Retry { prime: true }
+    );
+    assert_eq!(
+        tracker.record(true, None, 20.),
+        DispenseDecision::Retry { prime: true }
+    );
+    assert_eq!(
+        tracker.record(true, Some(5.), 20.),
+        DispenseDecision::RunOut
+    );
+    tracker.reset();
+    assert_eq!(tracker.consecutive_timeouts(), 0);
+}
+
+#[test]
+fn test_partial_weight_resets_timeouts() {
+    let mut tracker = RunOutTracker::new(DispensePolicy {
+        max_consecutive_timeouts: 2,
+        prime_on_retry: false,
+        acceptable_partial_weight: 0.8,
+    });
+    assert_eq!(
+        tracker.record(true, Some(1.), 20.),
+        DispenseDecision::Retry { prime: false }
+    );
+    assert_eq!(
+        tracker.record(true, Some(16.), 20.),
+        DispenseDecision::Accept
+    );
+    assert_eq!(tracker.consecutive_timeouts(), 0);
+    assert_eq!(
+        tracker.record(true, Some(1.), 20.),
+        DispenseDecision::Retry { prime: false }
+    );
+}
@@ -0,0 +1,221 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::Role;
+use crate::config::SanitationConfig;
+use crate::session::CommandError;
+use crate::state::AppData;
+
+// One completed guided cleaning, kept for health inspections
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CleaningEvent {
+    pub timestamp: String,
+    pub user: String,
+    pub duration_s: u64,
+    //The node's bowl count when it was cleaned
+    pub bowl_count: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SanitationStatus {
+    #[default]
+    Ok,
+    Due,
+    //Due for longer than the grace period
+    Overdue,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SanitationReport {
+    pub status: SanitationStatus,
+    pub blocking: bool,
+    pub last_cleaned: Option<String>,
+    pub bowls_since_cleaned: i64,
+    //Newest first
+    pub history: Vec<CleaningEvent>,
+}
+
+// Tracks when the node was last cleaned against the configured intervals. When cleaning
+// became due is worked out from the records, so a restart doesn't restart the grace period.
+pub struct SanitationTracker {
+    config: SanitationConfig,
+    last_cleaned: Option<DateTime<Utc>>,
+    bowls_at_clean: i64,
+    //When the bowl count reached max_bowls since the last cleaning, from the dispense log
+    bowls_due_at: Option<DateTime<Utc>>,
+    //A node with no cleaning on record is due from its first logged action
+    first_logged: Option<DateTime<Utc>>,
+    status: SanitationStatus,
+}
+
+impl SanitationTracker {
+    pub fn new(config: SanitationConfig, last: Option<&CleaningEvent>) -> Self {
+        Self {
+            config,
+            last_cleaned: last
+                .and_then(|last| DateTime::parse_from_rfc3339(&last.timestamp).ok())
+                .map(|last| last.with_timezone(&Utc)),
+            bowls_at_clean: last.map_or(0, |last| last.bowl_count),
+            bowls_due_at: None,
+            first_logged: None,
+            status: SanitationStatus::Ok,
+        }
+    }
+
+    pub fn cleaned(&mut self, at: DateTime<Utc>, bowl_count: i64) {
+        self.last_cleaned = Some(at);
+        self.bowls_at_clean = bowl_count;
+        self.bowls_due_at = None;
+        self.status = SanitationStatus::Ok;
+    }
+
+    //The node's bowl number that made cleaning due, while its time hasn't been looked up yet
+    pub fn bowl_limit_reached(&self, bowl_count: i64) -> Option<i64> {
+        let limit = self.bowls_at_clean + self.config.max_bowls?;
+        (self.last_cleaned.is_some() && self.bowls_due_at.is_none() && bowl_count >= limit)
+            .then_some(limit)
+    }
+
+    pub fn set_bowls_due_at(&mut self, at: DateTime<Utc>) {
+        self.bowls_due_at = Some(at);
+    }
+
+    //True while the node has no cleaning on record and its first log entry hasn't been read
+    pub fn needs_first_logged(&self) -> bool {
+        self.last_cleaned.is_none() && self.first_logged.is_none()
+    }
+
+    pub fn set_first_logged(&mut self, at: DateTime<Utc>) {
+        self.first_logged = Some(at);
+    }
+
+    //When cleaning became due, None if it isn't
+    fn due_at(&mut self, now: DateTime<Utc>, bowl_count: i64) -> Option<DateTime<Utc>> {
+        let Some(last_cleaned) = self.last_cleaned else {
+            //Nothing logged yet means the node has never run
+            return Some(self.first_logged.unwrap_or(now));
+        };
+        let by_time = chrono::Duration::from_std(self.config.interval)
+            .ok()
+            .map(|interval| last_cleaned + interval)
+            .filter(|due| *due <= now);
+        if self.bowl_limit_reached(bowl_count).is_some() {
+            //Nothing in the log to date it by
+            self.bowls_due_at = Some(now);
+        }
+        by_time.into_iter().chain(self.bowls_due_at).min()
+    }
+
+    //Called periodically, the grace period runs from when cleaning became due
+    pub fn update(&mut self, now: DateTime<Utc>, bowl_count: i64) -> SanitationStatus {
+        let Some(due_at) = self.due_at(now, bowl_count) else {
+            self.status = SanitationStatus::Ok;
+            return self.status;
+        };
+        let overdue = (now - due_at)
+            .to_std()
+            .is_ok_and(|due_for| due_for >= self.config.grace_period);
+        let status = if overdue {
+            SanitationStatus::Overdue
+        } else {
+            SanitationStatus::Due
+        };
+        if status != self.status {
+            log::warn!("Cleaning is {:?}", status);
+        }
+        self.status = status;
+        status
+    }
+
+    pub fn status(&self) -> SanitationStatus {
+        self.status
+    }
+
+    pub fn blocks_dispensing(&self) -> bool {
+        self.config.block_when_overdue && self.status == SanitationStatus::Overdue
+    }
+
+    pub fn report(&self, bowl_count: i64, history: Vec<CleaningEvent>) -> SanitationReport {
+        SanitationReport {
+            status: self.status,
+            blocking: self.blocks_dispensing(),
+            last_cleaned: self.last_cleaned.map(|last| last.to_rfc3339()),
+            bowls_since_cleaned: bowl_count - self.bowls_at_clean,
+            history,
+        }
+    }
+}
+
+pub fn get_sanitation_status(state: &Mutex<AppData>) -> SanitationStatus {
+    state.lock().unwrap().sanitation.status()
+}
+
+pub fn get_sanitation_report(
+    state: &Mutex<AppData>,
+    limit: u32,
+) -> Result<SanitationReport, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    Ok(state_guard.sanitation_report(limit))
+}
+
+#[test]
+fn test_sanitation_schedule() {
+    use std::time::Duration;
+
+    let config = SanitationConfig {
+        interval: Duration::from_secs(4 * 60 * 60),
+        max_bowls: Some(100),
+        grace_period: Duration::from_secs(30 * 60),
+        block_when_overdue: true,
+    };
+    let cleaned = DateTime::parse_from_rfc3339("2024-01-01T08:00:00+00:00")
+        .unwrap()
+        .with_timezone(&Utc);
+    let hours = |h: i64| cleaned + chrono::Duration::minutes(h * 60);
+    let event = CleaningEvent {
+        timestamp: cleaned.to_rfc3339(),
+        user: "sam".to_string(),
+        duration_s: 600,
+        bowl_count: 500,
+    };
+    let mut tracker = SanitationTracker::new(config.clone(), Some(&event));
+    assert_eq!(tracker.update(hours(1), 550), SanitationStatus::Ok);
+    //Due by bowls well before the interval
+    assert_eq!(tracker.update(hours(2), 600), SanitationStatus::Due);
+    assert!(!tracker.blocks_dispensing());
+    assert_eq!(
+        tracker.update(hours(2) + chrono::Duration::minutes(30), 600),
+        SanitationStatus::Overdue
+    );
+    assert!(tracker.blocks_dispensing());
+    tracker.cleaned(hours(3), 620);
+    assert_eq!(tracker.update(hours(6), 650), SanitationStatus::Ok);
+    assert_eq!(tracker.update(hours(7), 650), SanitationStatus::Due);
+
+    //After a restart the same record still says overdue, by time and by bowls
+    let mut restarted = SanitationTracker::new(config.clone(), Some(&event));
+    assert_eq!(
+        restarted.update(hours(4) + chrono::Duration::minutes(31), 520),
+        SanitationStatus::Overdue
+    );
+    let mut restarted = SanitationTracker::new(config.clone(), Some(&event));
+    assert_eq!(restarted.bowl_limit_reached(600), Some(600));
+    restarted.set_bowls_due_at(hours(2));
+    assert_eq!(restarted.bowl_limit_reached(600), None);
+    assert_eq!(
+        restarted.update(hours(2) + chrono::Duration::minutes(30), 600),
+        SanitationStatus::Overdue
+    );
+
+    let mut never_cleaned = SanitationTracker::new(config, None);
+    assert_eq!(never_cleaned.update(hours(0), 0), SanitationStatus::Due);
+    //Restarted long after its first use, it is overdue straight away
+    let mut never_cleaned = SanitationTracker::new(never_cleaned.config.clone(), None);
+    assert!(never_cleaned.needs_first_logged());
+    never_cleaned.set_first_logged(hours(0));
+    assert!(!never_cleaned.needs_first_logged());
+    assert_eq!(never_cleaned.update(hours(1), 0), SanitationStatus::Overdue);
+}
@@ -0,0 +1,204 @@
+use std::sync::Mutex;
+
+use libra::scale::ConnectedScale;
+use serde::Serialize;
+use tokio::time::{sleep, Instant};
+
+use crate::accounts::Role;
+use crate::config::ScaleHealthConfig;
+use crate::data_logging::DataAction;
+use crate::session::CommandError;
+use crate::state::{AppData, IchibuState};
+use crate::telemetry::TelemetryEvent;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum ScaleFault {
+    Disconnected,
+    Stalled,
+    OutOfRange(f64),
+    Noisy(f64),
+    ZeroDrift(f64),
+}
+
+// Checks a batch of idle readings against the zero captured on the first healthy check
+pub fn evaluate(
+    samples: &[f64],
+    zero: Option<f64>,
+    config: &ScaleHealthConfig,
+) -> Result<f64, ScaleFault> {
+    if samples.is_empty() {
+        return Err(ScaleFault::Disconnected);
+    }
+    if let Some(weight) = samples
+        .iter()
+        .find(|w| **w < config.min_weight || **w > config.max_weight)
+    {
+        return Err(ScaleFault::OutOfRange(*weight));
+    }
+    let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+    let variance = samples.iter().map(|w| (w - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+    let std_dev = variance.sqrt();
+    if std_dev > config.max_noise {
+        return Err(ScaleFault::Noisy(std_dev));
+    }
+    if let Some(zero) = zero {
+        let drift = mean - zero;
+        if drift.abs() > config.max_zero_drift {
+            return Err(ScaleFault::ZeroDrift(drift));
+        }
+    }
+    Ok(mean)
+}
+
+pub struct ScaleMonitor {
+    config: ScaleHealthConfig,
+    zero: Option<f64>,
+    last_check: Option<Instant>,
+    // The last reading that differed from the one before it, and when it was taken
+    last_change: Option<(f64, Instant)>,
+    faulted: bool,
+}
+
+impl ScaleMonitor {
+    pub fn new(config: ScaleHealthConfig) -> Self {
+        Self {
+            config,
+            zero: None,
+            last_check: None,
+            last_change: None,
+            faulted: false,
+        }
+    }
+
+    async fn sample(&self, scale: &mut ConnectedScale) -> Vec<f64> {
+        let mut samples = Vec::with_capacity(self.config.sample_number);
+        for _ in 0..self.config.sample_number {
+            match scale.get_weight() {
+                Ok(weight) => samples.push(weight),
+                Err(e) => {
+                    log::error!("Scale read failed: {:?}", e);
+                    return Vec::new();
+                }
+            }
+            sleep(self.config.sample_period).await;
+        }
+        samples
+    }
+
+    // Stalled once the readings have not moved at all for the whole stall timeout
+    fn stalled(&mut self, samples: &[f64], now: Instant) -> bool {
+        for weight in samples {
+            if self.last_change.is_none_or(|(last, _)| last != *weight) {
+                self.last_change = Some((*weight, now));
+            }
+        }
+        self.last_change
+            .is_some_and(|(_, since)| now.duration_since(since) >= self.config.stall_timeout)
+    }
+
+    //Checks the empty platform against the zero reference now, without raising a fault
+    pub async fn zero_check(&mut self, scale: &mut ConnectedScale) -> Result<f64, ScaleFault> {
+        let samples = self.sample(scale).await;
+        evaluate(&samples, self.zero, &self.config)
+    }
+
+    //Only call this while the hatch is open and nothing is being dispensed
+    pub async fn check_if_due(
+        &mut self,
+        state: &Mutex<AppData>,
+        scale: &mut ConnectedScale,
+    ) {
+        let due = self
+            .last_check
+            .is_none_or(|last| last.elapsed() >= self.config.check_interval);
+        let faulted = { state.lock().unwrap().scale_fault.is_some() };
+        if faulted {
+            return;
+        }
+        if self.faulted {
+            // Staff cleared the fault, take a fresh zero reference
+            self.faulted = false;
+            self.zero = None;
+            self.last_change = None;
+        } else if !due {
+            return;
+        }
+        self.last_check = Some(Instant::now());
+        let samples = self.sample(scale).await;
+        let result = evaluate(&samples, self.zero, &self.config).and_then(|mean| {
+            if self.stalled(&samples, Instant::now()) {
+                Err(ScaleFault::Stalled)
+            } else {
+                Ok(mean)
+            }
+        });
+        match result {
+            Ok(mean) => {
+                if self.zero.is_none() {
+                    log::info!("Scale zero reference: {}", mean);
+                    self.zero = Some(mean);
+                }
+            }
+            Err(fault) => {
+                log::error!("Scale fault: {:?}", fault);
+                self.faulted = true;
+                let mut state_guard = state.lock().unwrap();
+                state_guard.telemetry.publish(TelemetryEvent::Fault {
+                    fault: format!("Scale: {:?}", fault),
+                });
+                state_guard.scale_fault = Some(fault);
+                state_guard.log_action(&DataAction::ScaleFault);
+                if matches!(state_guard.get_state(), IchibuState::Running) {
+                    state_guard.update_state(IchibuState::Ready);
+                }
+            }
+        }
+    }
+}
+
+pub fn get_scale_fault(state: &Mutex<AppData>) -> Option<ScaleFault> {
+    state.lock().unwrap().scale_fault.clone()
+}
+
+pub fn clear_scale_fault(state: &Mutex<AppData>) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    log::info!("Scale fault cleared");
+    state_guard.scale_fault = None;
+    Ok(())
+}
+
+#[test]
+fn test_scale_health_evaluate() {
+    let config = ScaleHealthConfig::default();
+    let healthy = [0.1, -0.2, 0.15, 0.05, -0.1];
+    assert!(evaluate(&healthy, Some(0.), &config).is_ok());
+    assert_eq!(evaluate(&[], None, &config), Err(ScaleFault::Disconnected));
+    assert!(evaluate(&[1.; 5], None, &config).is_ok());
+    assert!(matches!(
+        evaluate(&[0., 0.2, 1e6], None, &config),
+        Err(ScaleFault::OutOfRange(_))
+    ));
+    assert!(matches!(
+        evaluate(&[-20., 20., -20., 20.], None, &config),
+        Err(ScaleFault::Noisy(_))
+    ));
+    let drifted = healthy.map(|w| w + 3. * config.max_zero_drift);
+    assert!(matches!(
+        evaluate(&drifted, Some(0.), &config),
+        Err(ScaleFault::ZeroDrift(_))
+    ));
+}
+
+#[test]
+fn test_scale_monitor_stall_needs_the_full_timeout() {
+    let mut monitor = ScaleMonitor::new(ScaleHealthConfig::default());
+    let start = Instant::now();
+    let timeout = monitor.config.stall_timeout;
+    assert!(!monitor.stalled(&[1.; 5], start));
+    assert!(!monitor.stalled(&[1.; 5], start + timeout / 2));
+    assert!(monitor.stalled(&[1.; 5], start + timeout));
+    // Any movement restarts the window
+    assert!(!monitor.stalled(&[1., 1.1], start + timeout * 2));
+    assert!(!monitor.stalled(&[1.1; 5], start + timeout * 2 + timeout / 2));
+}
@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{Account, AuthError, Role};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum CommandError {
+    NotLoggedIn,
+    Forbidden { required: Role },
+    Auth(AuthError),
+    Failed(String),
+}
+
+impl From<AuthError> for CommandError {
+    fn from(e: AuthError) -> Self {
+        CommandError::Auth(e)
+    }
+}
+
+// Who is logged in at the kiosk, expires after the configured idle time
+pub struct Session {
+    pub account: Account,
+    last_activity: Instant,
+}
+
+impl Session {
+    pub fn new(account: Account) -> Self {
+        Self {
+            account,
+            last_activity: Instant::now(),
+        }
+    }
+
+    pub fn is_expired(&self, idle_timeout: Duration) -> bool {
+        self.last_activity.elapsed() >= idle_timeout
+    }
+
+    //Checks the role and counts the command as activity
+    pub fn authorize(&mut self, required: Role) -> Result<(), CommandError> {
+        if self.account.role < required {
+            return Err(CommandError::Forbidden { required });
+        }
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_session_roles() {
+    let mut session = Session::new(Account {
+        name: "jo".to_string(),
+        role: Role::Manager,
+    });
+    assert!(session.authorize(Role::Operator).is_ok());
+    assert!(session.authorize(Role::Manager).is_ok());
+    assert_eq!(
+        session.authorize(Role::Admin),
+        Err(CommandError::Forbidden {
+            required: Role::Admin
+        })
+    );
+    assert!(!session.is_expired(Duration::from_secs(60)));
+    assert!(session.is_expired(Duration::ZERO));
+}
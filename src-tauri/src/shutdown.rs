@@ -0,0 +1,107 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, ExitRequestApi, Manager};
+
+use crate::accounts::Role;
+use crate::data_logging::DataAction;
+use crate::fleet::Fleet;
+use crate::session::CommandError;
+use crate::state::{AppData, SharedState};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ShutdownReason {
+    AdminExit,
+    EscapeKey,
+    AppClosed,
+    //Ctrl-C to the headless daemon
+    Signal,
+}
+
+// The cycle loop sees the request, parks the hardware and returns, then finish() wraps up
+pub fn request_shutdown(state: &mut AppData, reason: ShutdownReason) {
+    log::info!("Shutdown requested: {:?}", reason);
+    if state.shutdown_request.is_none() {
+        state.shutdown_request = Some(reason);
+        state.shutdown_requested_at = Some(Instant::now());
+    }
+}
+
+// How long an exit waits on the hardware being parked before giving up
+const EXIT_DEADLINE: Duration = Duration::from_secs(30);
+
+//Whether the exit still has to wait on this node parking its hardware
+pub fn holds_exit(state: &AppData) -> bool {
+    if state.hardware_parked {
+        return false;
+    }
+    if state.controls_stopped {
+        log::error!("Controls stopped without parking the hardware, exiting anyway");
+        return false;
+    }
+    if state
+        .shutdown_requested_at
+        .is_some_and(|requested| requested.elapsed() >= EXIT_DEADLINE)
+    {
+        log::error!("Hardware not parked within {:?}, exiting anyway", EXIT_DEADLINE);
+        return false;
+    }
+    true
+}
+
+//Asks to exit again once the deadline has passed, in case parking hangs
+fn exit_after_deadline(app_handle: &AppHandle) {
+    let app_handle = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(EXIT_DEADLINE).await;
+        app_handle.exit(1);
+    });
+}
+
+//Runs once the hardware is safe, the caller exits after
+pub fn finish(state: &Mutex<AppData>) {
+    let mut state_guard = state.lock().unwrap();
+    let reason = state_guard
+        .shutdown_request
+        .clone()
+        .unwrap_or(ShutdownReason::AdminExit);
+    state_guard.log_action(&DataAction::Shutdown(reason));
+    state_guard.hardware_parked = true;
+    state_guard.persist();
+    state_guard.end_session();
+    if let Err(e) = state_guard.flush_database() {
+        log::error!("Failed to flush database on shutdown: {}", e);
+    }
+    log::info!("Hardware parked, exiting");
+}
+
+// Hooked to the tauri exit event. Holds the exit back until the cycle loop has parked the
+// hardware and finish() has run, or the controls died, or EXIT_DEADLINE has passed.
+pub fn on_exit_requested(app_handle: &AppHandle, api: &ExitRequestApi) {
+    if let Some(fleet) = app_handle.try_state::<Fleet>() {
+        if fleet.holds_exit() {
+            api.prevent_exit();
+            fleet.request_shutdown(ShutdownReason::AppClosed);
+            exit_after_deadline(app_handle);
+        }
+        return;
+    }
+    let Some(state) = app_handle.try_state::<SharedState>() else {
+        return;
+    };
+    let mut state_guard = state.lock().unwrap_or_else(PoisonError::into_inner);
+    if !holds_exit(&state_guard) {
+        return;
+    }
+    api.prevent_exit();
+    request_shutdown(&mut state_guard, ShutdownReason::AppClosed);
+    exit_after_deadline(app_handle);
+}
+
+pub fn exit_kiosk(state: &Mutex<AppData>) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Admin)?;
+    request_shutdown(&mut state_guard, ShutdownReason::AdminExit);
+    Ok(())
+}
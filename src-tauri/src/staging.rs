@@ -0,0 +1,130 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::StagingConfig;
+use crate::state::AppData;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct StagingReport {
+    pub staged_for_ms: Option<u64>,
+    pub expired: bool,
+    pub average_wait_ms: Option<u64>,
+    pub served: usize,
+    pub discarded: usize,
+}
+
+// Tracks the portion waiting on the hatch and how long customers wait for theirs
+pub struct StagingTracker {
+    config: StagingConfig,
+    staged_at: Option<Instant>,
+    flagged: bool,
+    total_wait: Duration,
+    timed_waits: u32,
+    served: usize,
+    discarded: usize,
+}
+
+impl StagingTracker {
+    pub fn new(config: StagingConfig) -> Self {
+        Self {
+            config,
+            staged_at: None,
+            flagged: false,
+            total_wait: Duration::ZERO,
+            timed_waits: 0,
+            served: 0,
+            discarded: 0,
+        }
+    }
+
+    pub fn config(&self) -> &StagingConfig {
+        &self.config
+    }
+
+    pub fn stage(&mut self, now: Instant) {
+        self.staged_at = Some(now);
+        self.flagged = false;
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.staged_at
+            .is_some_and(|staged_at| now.duration_since(staged_at) >= self.config.hold_time)
+    }
+
+    //True the first time an expired portion is seen, so it is only logged once
+    pub fn flag(&mut self) -> bool {
+        let first = !self.flagged;
+        self.flagged = true;
+        first
+    }
+
+    //wait is from the bowl going under the chute to the portion dropping into it
+    pub fn serve(&mut self, wait: Option<Duration>) {
+        self.staged_at = None;
+        self.flagged = false;
+        self.served += 1;
+        if let Some(wait) = wait {
+            self.total_wait += wait;
+            self.timed_waits += 1;
+        }
+    }
+
+    pub fn discard(&mut self) {
+        self.staged_at = None;
+        self.flagged = false;
+        self.discarded += 1;
+    }
+
+    //The portion left the hatch some other way, e.g. cleaning
+    pub fn clear(&mut self) {
+        self.staged_at = None;
+        self.flagged = false;
+    }
+
+    pub fn report(&self, now: Instant) -> StagingReport {
+        StagingReport {
+            staged_for_ms: self
+                .staged_at
+                .map(|staged_at| now.duration_since(staged_at).as_millis() as u64),
+            expired: self.is_expired(now),
+            average_wait_ms: (self.timed_waits > 0)
+                .then(|| (self.total_wait / self.timed_waits).as_millis() as u64),
+            served: self.served,
+            discarded: self.discarded,
+        }
+    }
+}
+
+pub fn get_staging_report(state: &Mutex<AppData>) -> StagingReport {
+    state.lock().unwrap().staging.report(Instant::now())
+}
+
+#[test]
+fn test_staging_hold_time_and_waits() {
+    let mut tracker = StagingTracker::new(StagingConfig {
+        hold_time: Duration::from_secs(60),
+        ..Default::default()
+    });
+    let start = Instant::now();
+    tracker.stage(start);
+    assert!(!tracker.is_expired(start + Duration::from_secs(30)));
+    assert!(tracker.is_expired(start + Duration::from_secs(60)));
+    assert!(tracker.flag());
+    assert!(!tracker.flag());
+
+    tracker.discard();
+    assert!(!tracker.is_expired(start + Duration::from_secs(90)));
+
+    tracker.stage(start);
+    tracker.serve(Some(Duration::from_secs(4)));
+    tracker.stage(start);
+    tracker.serve(Some(Duration::from_secs(8)));
+    tracker.serve(None);
+    let report = tracker.report(start);
+    assert_eq!(report.average_wait_ms, Some(6000));
+    assert_eq!(report.served, 3);
+    assert_eq!(report.discarded, 1);
+    assert_eq!(report.staged_for_ms, None);
+}
@@ -1,27 +1,45 @@
-use std::sync::Mutex;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use log::info;
 use tokio::sync::{mpsc::Sender, oneshot};
 
-use control_components::components::{clear_core_io::DigitalInput, scale::ScaleCmd};
+use control_components::components::scale::ScaleCmd;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    accounts::{Account, Accounts, AuthEvent, Role},
+    alerts::{AlertKind, AlertTracker},
+    calibration::Calibration,
+    cleaning::CleaningSession,
+    config::{Config, NodeConfig},
     data_logging::{Data, DataAction},
-    ingredients::{read_ingredient_config, Ingredient},
+    scale_health::ScaleFault,
+    ingredients::{read_ingredient_config, Ingredient, PortionSize},
     io::{self, PhotoEyeState},
+    orders::OrderBook,
+    persistence::SavedState,
+    portion::DispenseType,
+    run_out::RunOutTracker,
+    sanitation::{CleaningEvent, SanitationReport, SanitationStatus, SanitationTracker},
+    session::{CommandError, Session},
+    staging::StagingTracker,
+    telemetry::{Telemetry, TelemetryEvent},
+    trace::{CycleTrace, TraceEvent, TraceRecorder, TraceSummary},
+    shutdown::ShutdownReason,
     UiRequest, HOME_DIRECTORY,
 };
-use crate::lights::{LightColors, Lights};
+use crate::lights::LightCondition;
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum IchibuState {
     #[default]
     Ready,
-    RunningClassic,
-    RunningSized,
+    //Older saved state and UIs still send the mode as part of the state
+    #[serde(alias = "RunningClassic", alias = "RunningSized")]
+    Running,
     Cleaning,
     Emptying,
+    Calibrating,
 }
 
 #[derive(Default, Debug, Serialize)]
@@ -31,8 +49,14 @@ pub enum NodeLevel {
     Empty,
 }
 
+//Owned by the Tauri app or the headless daemon, the controls and commands borrow it
+pub type SharedState = Arc<Mutex<AppData>>;
+
 //App data is what should be shared between the UI and the controls
 pub struct AppData {
+    pub node: String,
+    //Where this node's controls config, saved state and data live
+    pub config_dir: String,
     state: IchibuState,
     ui_request: UiRequest,
     node_level: NodeLevel,
@@ -43,13 +67,51 @@ pub struct AppData {
     bowl_count: i64,
     pub cycle_dispense_count: usize,
     current_snack: Option<Ingredient>,
+    dispense_type: DispenseType,
+    pub calibration: Calibration,
+    pub cleaning: Option<CleaningSession>,
+    pub scale_fault: Option<ScaleFault>,
+    pub run_out: RunOutTracker,
+    pub needs_warm_up: bool,
+    //Configured conveyor speed, restored after a warm-up changes it
+    pub conveyor_velocity: f64,
+    pub accounts: Accounts,
+    session: Option<Session>,
+    pub shutdown_request: Option<ShutdownReason>,
+    pub shutdown_requested_at: Option<Instant>,
+    pub hardware_parked: bool,
+    //The controls task died, so the hardware will never be parked
+    pub controls_stopped: bool,
+    pub maintenance_due: bool,
+    pub hatch_fault: bool,
+    //When the last portion dropped into a bowl that hasn't been picked up yet
+    pub bowl_served: Option<Instant>,
+    //When the bowl currently under the chute was put there
+    pub bowl_placed: Option<Instant>,
+    pub staging: StagingTracker,
+    pub sanitation: SanitationTracker,
+    pub alerts: AlertTracker,
+    pub orders: OrderBook,
+    pub telemetry: Telemetry,
+    pub trace: TraceRecorder,
+    resume_offer: Option<SavedState>,
+    last_saved: Option<SavedState>,
 }
 
 impl AppData {
-    pub fn new() -> Self {
-        let (database, bowl_count) = io::initialize_database();
+    pub fn new(config: &Config, node: &NodeConfig) -> Self {
+        Self::open(config, &node.name, node.path())
+    }
+
+    pub(crate) fn open(config: &Config, node: &str, config_dir: String) -> Self {
+        let (database, bowl_count) = io::initialize_database(&config_dir);
+        let accounts = io::initialize_accounts(config, &config_dir);
+        let resume_offer = SavedState::load(&config_dir);
+        let last_cleaning = database.list_cleanings(1).unwrap_or_default();
         // let pe_state = io::photo_eye_state(&photo_eye).await;
-        Self {
+        let mut app_data = Self {
+            node: node.to_string(),
+            config_dir,
             state: IchibuState::Ready,
             ui_request: UiRequest::None,
             node_level: NodeLevel::Empty,
@@ -60,7 +122,37 @@ impl AppData {
             bowl_count,
             cycle_dispense_count: 0,
             current_snack: None,
+            dispense_type: config.dispense.default_type,
+            calibration: Calibration::default(),
+            cleaning: None,
+            scale_fault: None,
+            run_out: RunOutTracker::default(),
+            needs_warm_up: true,
+            conveyor_velocity: config.motor.velocity,
+            accounts,
+            session: None,
+            shutdown_request: None,
+            shutdown_requested_at: None,
+            hardware_parked: false,
+            controls_stopped: false,
+            maintenance_due: false,
+            hatch_fault: false,
+            bowl_served: None,
+            bowl_placed: None,
+            staging: StagingTracker::new(config.staging.clone()),
+            sanitation: SanitationTracker::new(config.sanitation.clone(), last_cleaning.first()),
+            alerts: AlertTracker::new(config.alerts.clone()),
+            orders: OrderBook::default(),
+            telemetry: Telemetry::default(),
+            trace: TraceRecorder::default(),
+            resume_offer,
+            last_saved: None,
+        };
+        if app_data.resume_offer.as_ref().is_some_and(|saved| saved.mid_cycle) {
+            log::warn!("Power was lost mid cycle");
+            app_data.log_action(&DataAction::Interrupted);
         }
+        app_data
     }
 
     pub fn log_action(&mut self, action: &DataAction) {
@@ -69,6 +161,173 @@ impl AppData {
         self.bowl_count = self.database.get_bowl_count().unwrap();
     }
 
+    pub fn bowl_count(&self) -> i64 {
+        self.bowl_count
+    }
+
+    pub fn start_trace(&mut self) {
+        self.trace
+            .start(self.current_snack.as_ref().map(|snack| snack.name.clone()));
+        let blocked = matches!(self.pe_state, PhotoEyeState::Blocked);
+        self.trace.record(TraceEvent::PhotoEye { blocked });
+    }
+
+    pub fn finish_trace(&mut self) {
+        let Some(trace) = self.trace.finish() else {
+            return;
+        };
+        match self.database.save_trace(&trace) {
+            Ok(id) => log::info!("Cycle trace {} saved", id),
+            Err(e) => log::error!("Couldn't save cycle trace: {}", e),
+        }
+    }
+
+    pub fn load_trace(&self, id: i64) -> Option<CycleTrace> {
+        self.database.load_trace(id)
+    }
+
+    pub fn list_traces(&self, limit: u32) -> Vec<TraceSummary> {
+        self.database.list_traces(limit).unwrap_or_default()
+    }
+
+    pub fn flush_database(&self) -> rusqlite::Result<()> {
+        self.database.flush()
+    }
+
+    fn snapshot(&self) -> SavedState {
+        SavedState {
+            snack_id: self.current_snack.as_ref().map(|snack| snack.id),
+            run_state: self.state.clone(),
+            dispense_type: self.dispense_type,
+            cycle_dispense_count: self.cycle_dispense_count,
+            timed_out: self.dispenser_has_timed_out,
+            mid_cycle: self.dispenser_busy && !self.hardware_parked,
+        }
+    }
+
+    //Writes the state to disk whenever it changes
+    // The resume offer was read at start up and stays in memory until answered, so saving the
+    // current run over it is fine
+    pub fn persist(&mut self) {
+        let snapshot = self.snapshot();
+        if self.last_saved.as_ref() == Some(&snapshot) {
+            return;
+        }
+        match snapshot.save(&self.config_dir) {
+            Ok(()) => self.last_saved = Some(snapshot),
+            Err(e) => log::error!("Failed to save state: {}", e),
+        }
+    }
+
+    fn resume(&mut self, saved: SavedState, snack: Option<Ingredient>) {
+        info!("Resuming {:?} with {:?}", saved.run_state, snack.as_ref().map(|s| &s.name));
+        if let Some(snack) = snack {
+            self.update_current_snack(snack);
+        }
+        self.cycle_dispense_count = saved.cycle_dispense_count;
+        self.dispenser_has_timed_out = saved.timed_out;
+        self.dispense_type = saved.dispense_type;
+        // Only dispensing picks back up, anything that moves hardware on its own waits for the operator
+        let can_run = self.current_snack.is_some() && self.scale_fault.is_none();
+        self.state = match saved.run_state {
+            IchibuState::Running if can_run => saved.run_state,
+            _ => IchibuState::Ready,
+        };
+    }
+
+    //Most urgent first, the lights task maps this to a pattern
+    pub fn light_condition(&self) -> LightCondition {
+        let running = matches!(self.state, IchibuState::Running);
+        if self.is_shutting_down() {
+            LightCondition::Off
+        } else if self.scale_fault.is_some() || self.hatch_fault {
+            LightCondition::Fault
+        } else if self.dispenser_has_timed_out {
+            LightCondition::RunOut
+        } else if matches!(self.state, IchibuState::Cleaning | IchibuState::Emptying) {
+            LightCondition::Cleaning
+        } else if self.maintenance_due {
+            LightCondition::MaintenanceDue
+        } else if running && self.dispenser_busy {
+            LightCondition::Busy
+        } else if running {
+            LightCondition::Running
+        } else {
+            LightCondition::Idle
+        }
+    }
+
+    pub fn alert_conditions(&self) -> Vec<AlertKind> {
+        let mut conditions = Vec::new();
+        if self.dispenser_has_timed_out {
+            conditions.push(AlertKind::RunOut);
+        }
+        if self.hatch_fault {
+            conditions.push(AlertKind::HatchFault);
+        }
+        if self.scale_fault.is_some() {
+            conditions.push(AlertKind::ScaleFault);
+        }
+        if self.staging.is_expired(Instant::now()) {
+            conditions.push(AlertKind::StaleHold);
+        }
+        if self.sanitation.status() == SanitationStatus::Overdue {
+            conditions.push(AlertKind::SanitationOverdue);
+        }
+        let bowl_left_after = self.alerts.config().bowl_left_after;
+        if self
+            .bowl_served
+            .is_some_and(|served| served.elapsed() >= bowl_left_after)
+        {
+            conditions.push(AlertKind::BowlLeft);
+        }
+        conditions
+    }
+
+    //Keeps the maintenance light and the overdue block in step with the cleaning schedule
+    pub fn update_sanitation(&mut self) {
+        if self.sanitation.needs_first_logged() {
+            if let Some(at) = self.database.first_log_time() {
+                self.sanitation.set_first_logged(at);
+            }
+        }
+        if let Some(bowl) = self.sanitation.bowl_limit_reached(self.bowl_count) {
+            if let Some(at) = self.database.bowl_time(bowl) {
+                self.sanitation.set_bowls_due_at(at);
+            }
+        }
+        let status = self.sanitation.update(chrono::Utc::now(), self.bowl_count);
+        self.maintenance_due = status != SanitationStatus::Ok;
+        if self.sanitation.blocks_dispensing() && matches!(self.state, IchibuState::Running) {
+            log::warn!("Cleaning is overdue, stopping the dispenser");
+            self.update_state(IchibuState::Ready);
+        }
+    }
+
+    pub fn record_cleaning(&mut self, user: &str, duration_s: u64) {
+        let now = chrono::Utc::now();
+        let cleaning = CleaningEvent {
+            timestamp: now.to_rfc3339(),
+            user: user.to_string(),
+            duration_s,
+            bowl_count: self.bowl_count,
+        };
+        if let Err(e) = self.database.save_cleaning(&cleaning) {
+            log::error!("Couldn't save the cleaning record: {}", e);
+        }
+        self.sanitation.cleaned(now, self.bowl_count);
+        self.maintenance_due = false;
+    }
+
+    pub fn sanitation_report(&self, limit: u32) -> SanitationReport {
+        let history = self.database.list_cleanings(limit).unwrap_or_default();
+        self.sanitation.report(self.bowl_count, history)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown_request.is_some()
+    }
+
     pub fn reset_ui_request(&mut self) {
         self.ui_request = UiRequest::None;
     }
@@ -81,6 +340,14 @@ impl AppData {
         self.pe_state.clone()
     }
 
+    //The current snack's own dispense type if it has one, otherwise the kiosk's
+    pub fn dispense_type(&self) -> DispenseType {
+        self.current_snack
+            .as_ref()
+            .and_then(|snack| snack.dispense_type)
+            .unwrap_or(self.dispense_type)
+    }
+
     pub fn get_snack(&self) -> Option<&Ingredient> {
         self.current_snack.as_ref()
     }
@@ -94,57 +361,146 @@ impl AppData {
     }
 
     pub fn update_state(&mut self, new_state: IchibuState) {
+        if new_state != self.state {
+            self.telemetry.publish(TelemetryEvent::StateChanged {
+                from: self.state.clone(),
+                to: new_state.clone(),
+            });
+        }
         self.state = new_state;
     }
-    //These are private so that they can only be called from the UI via the tauri commands below
-    fn update_current_snack(&mut self, snack: Ingredient) {
+
+    pub fn start_session(&mut self, account: Account) {
+        self.end_session();
+        self.session = Some(Session::new(account));
+    }
+
+    pub fn user_name(&self) -> Option<String> {
+        self.session
+            .as_ref()
+            .map(|session| session.account.name.clone())
+    }
+
+    pub fn end_session(&mut self) {
+        if let Some(session) = self.session.take() {
+            info!("{} logged out", session.account.name);
+            self.accounts
+                .audit(Some(&session.account.name), &AuthEvent::LogOut);
+        }
+    }
+
+    pub fn current_account(&mut self) -> Option<Account> {
+        self.expire_session();
+        self.session.as_ref().map(|session| session.account.clone())
+    }
+
+    fn expire_session(&mut self) {
+        let idle_timeout = self.accounts.config().session_timeout;
+        if self
+            .session
+            .as_ref()
+            .is_some_and(|session| session.is_expired(idle_timeout))
+        {
+            info!("Session timed out");
+            self.end_session();
+        }
+    }
+
+    //Every command that changes the machine goes through here first
+    pub fn authorize(&mut self, required: Role) -> Result<(), CommandError> {
+        self.expire_session();
+        match self.session.as_mut() {
+            Some(session) => session.authorize(required),
+            None => Err(CommandError::NotLoggedIn),
+        }
+    }
+    //Loading a snack is left to the tauri commands below, the fleet tests load theirs directly
+    pub(crate) fn update_current_snack(&mut self, snack: Ingredient) {
+        self.orders.set_loaded(&self.node, snack.id);
         self.current_snack = Some(snack);
+        self.needs_warm_up = true;
     }
 
+    //These are private so that they can only be called from the UI via the tauri commands below
     fn update_ui_request(&mut self, ui_request: UiRequest) {
         self.ui_request = ui_request;
     }
 
-    fn dispenser_is_busy(&self) -> bool {
+    pub fn dispenser_is_busy(&self) -> bool {
         self.dispenser_busy
     }
-}
 
-//These are so that we can have a task updating these
-pub async fn update_pe_state(state: tauri::State<'_, Mutex<AppData>>, photo_eye: DigitalInput) {
-    let pe_state = io::photo_eye_state(&photo_eye).await;
-    state.lock().unwrap().pe_state = pe_state;
-}
-pub async fn update_lights_state(state: tauri::State<'_, Mutex<AppData>>, mut lights: Lights, interval: Duration) {
-    let (run_state, dispenser_busy, is_timed_out) = {
-        let state = state.lock().unwrap();
-        let run_state = state.get_state();
-        let dispenser_busy = state.dispenser_is_busy();
-        let is_timed_out = state.dispenser_has_timed_out;
-        (run_state, dispenser_busy, is_timed_out)
-    };
-    match (run_state, dispenser_busy, is_timed_out) {
-        (_, _, true) => {
-            lights.set_color(LightColors::Red).await;
-            tokio::time::sleep(interval/2).await;
-            lights.turn_off().await;
-        }
-        (IchibuState::Ready, _, _) => {
-            lights.turn_off().await;
-        }
-        (IchibuState::RunningClassic | IchibuState::RunningSized, true, _) => {
-            lights.set_color(LightColors::Yellow).await;
+    //Starts the next POS order as if the customer had asked for its size. Orders wait for a
+    //bowl under the chute so the photo eye can tell when they're picked up.
+    pub fn take_next_order(&mut self) -> bool {
+        if !matches!(self.pe_state, PhotoEyeState::Blocked) || self.bowl_served.is_some() {
+            return false;
         }
-        (IchibuState::RunningClassic | IchibuState::RunningSized, false, _) => {
-            lights.set_color(LightColors::Green).await;
+        let Some(snack_id) = self.current_snack.as_ref().map(|snack| snack.id) else {
+            return false;
+        };
+        let Some(order) = self.orders.start_next(&self.node, snack_id) else {
+            return false;
+        };
+        self.ui_request = UiRequest::Size(order.size);
+        true
+    }
+
+    pub fn finish_order(&self) {
+        self.orders.finish_dispensing(&self.node);
+    }
+
+    pub fn fail_order(&self, reason: &str) {
+        self.orders.fail_dispensing(&self.node, reason);
+    }
+
+    //Called with the debounced photo eye whenever a bowl is placed or removed
+    pub fn update_pe_state(&mut self, pe_state: PhotoEyeState) {
+        let blocked = matches!(pe_state, PhotoEyeState::Blocked);
+        self.trace.record(TraceEvent::PhotoEye { blocked });
+        match pe_state {
+            PhotoEyeState::Unblocked => {
+                self.orders.picked_up(&self.node);
+                match self.bowl_served {
+                    Some(served) => {
+                        let waited = served.elapsed();
+                        log::info!(
+                            "Bowl picked up {:.1}s after it was ready",
+                            waited.as_secs_f64()
+                        );
+                        self.log_action(&DataAction::BowlPickedUp {
+                            waited_ms: waited.as_millis() as u64,
+                        });
+                    }
+                    None => self.log_action(&DataAction::BowlRemoved),
+                }
+                self.bowl_served = None;
+                self.bowl_placed = None;
+            }
+            PhotoEyeState::Blocked => {
+                log::info!("Bowl placed");
+                self.log_action(&DataAction::BowlPlaced);
+                self.bowl_placed = Some(Instant::now());
+            }
         }
-        _ => {
-            lights.turn_off().await;
+        self.pe_state = pe_state;
+    }
+
+    //Fleet nodes all take from the station's queue
+    pub fn share_orders(&mut self, orders: OrderBook) {
+        self.orders = orders;
+        if let Some(snack) = &self.current_snack {
+            self.orders.set_loaded(&self.node, snack.id);
         }
     }
 }
+
+//These are so that we can have a task updating these
+pub fn persist_state(state: &Mutex<AppData>) {
+    state.lock().unwrap().persist();
+}
 pub async fn update_node_level(
-    state: tauri::State<'_, Mutex<AppData>>,
+    state: &Mutex<AppData>,
     empty_weight: f64,
     scale_tx: Sender<ScaleCmd>,
 ) {
@@ -161,8 +517,11 @@ pub async fn update_node_level(
     }
 }
 
-#[tauri::command]
-pub fn update_current_ingredient(state: tauri::State<'_, Mutex<AppData>>, snack: usize) {
+pub fn update_current_ingredient(
+    state: &Mutex<AppData>,
+    snack: usize,
+) -> Result<(), CommandError> {
+    state.lock().unwrap().authorize(Role::Operator)?;
     if let Ok(res) = read_ingredient_config(HOME_DIRECTORY.as_str()) {
         if let Some(ingredient) = res.ingredients.iter().find(|ing| ing.id == snack) {
             state
@@ -171,11 +530,25 @@ pub fn update_current_ingredient(state: tauri::State<'_, Mutex<AppData>>, snack:
                 .update_current_snack(ingredient.clone());
         }
     }
+    Ok(())
 }
 
-#[tauri::command]
-pub fn update_run_state(state: tauri::State<'_, Mutex<AppData>>, new_state: IchibuState) {
+pub fn update_run_state(
+    state: &Mutex<AppData>,
+    new_state: IchibuState,
+) -> Result<(), CommandError> {
     let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    if let (Some(fault), IchibuState::Running) = (&state_guard.scale_fault, &new_state) {
+        log::warn!("Refusing to run with a scale fault");
+        return Err(CommandError::Failed(format!("Scale fault: {:?}", fault)));
+    }
+    if state_guard.sanitation.blocks_dispensing() && matches!(new_state, IchibuState::Running) {
+        log::warn!("Refusing to run with cleaning overdue");
+        return Err(CommandError::Failed(
+            "Cleaning is overdue, clean the node before dispensing".to_string(),
+        ));
+    }
     if matches!(state_guard.get_state(), IchibuState::Ready) {
         match new_state {
             IchibuState::Cleaning => {
@@ -189,43 +562,98 @@ pub fn update_run_state(state: tauri::State<'_, Mutex<AppData>>, new_state: Ichi
             _ => (),
         }
     }
+    if new_state != IchibuState::Cleaning && state_guard.cleaning.take().is_some() {
+        log::warn!("Cleaning left unfinished");
+    }
     state_guard.update_state(new_state);
+    Ok(())
+}
+
+pub fn get_portion_sizes(state: &Mutex<AppData>) -> Vec<PortionSize> {
+    state
+        .lock()
+        .unwrap()
+        .get_snack()
+        .map(|snack| snack.portion_sizes())
+        .unwrap_or_default()
+}
+
+pub fn get_dispense_type(state: &Mutex<AppData>) -> DispenseType {
+    state.lock().unwrap().dispense_type()
 }
 
-#[tauri::command]
-pub fn update_ui_request(state: tauri::State<'_, Mutex<AppData>>, ui_request: UiRequest) {
+pub fn set_dispense_type(
+    state: &Mutex<AppData>,
+    dispense_type: DispenseType,
+) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    info!("Dispense type set to {:?}", dispense_type);
+    state_guard.dispense_type = dispense_type;
+    Ok(())
+}
+
+pub fn update_ui_request(state: &Mutex<AppData>, ui_request: UiRequest) {
     state.lock().unwrap().update_ui_request(ui_request);
 }
 
-#[tauri::command]
-pub fn get_dispense_count(state: tauri::State<'_, Mutex<AppData>>) -> usize {
+pub fn get_dispense_count(state: &Mutex<AppData>) -> usize {
     state.lock().unwrap().bowl_count as usize
 }
 
-#[tauri::command]
-pub fn get_pe_blocked(state: tauri::State<'_, Mutex<AppData>>) -> bool {
+pub fn get_pe_blocked(state: &Mutex<AppData>) -> bool {
     match state.lock().unwrap().pe_state {
         PhotoEyeState::Blocked => true,
         PhotoEyeState::Unblocked => false,
     }
 }
 
-#[tauri::command]
-pub fn dispenser_is_busy(state: tauri::State<'_, Mutex<AppData>>) -> bool {
+pub fn dispenser_is_busy(state: &Mutex<AppData>) -> bool {
     state.lock().unwrap().dispenser_is_busy()
 }
 
-#[tauri::command]
-pub fn dispenser_has_timed_out(state: tauri::State<'_, Mutex<AppData>>) -> bool {
+pub fn dispenser_has_timed_out(state: &Mutex<AppData>) -> bool {
     state.lock().unwrap().dispenser_has_timed_out
 }
 
-#[tauri::command]
-pub fn clear_dispenser_time_out(state: tauri::State<'_, Mutex<AppData>>) {
-    info!("Dispenser state cleared");
+pub fn clear_dispenser_time_out(state: &Mutex<AppData>) -> Result<(), CommandError> {
     let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    info!("Dispenser state cleared");
     state_guard.dispenser_has_timed_out = false;
     state_guard.cycle_dispense_count = 0;
+    state_guard.run_out.reset();
+    state_guard.needs_warm_up = true;
     let action = DataAction::Refilled;
     state_guard.log_action(&action);
+    Ok(())
+}
+
+pub fn get_resume_offer(state: &Mutex<AppData>) -> Option<SavedState> {
+    state.lock().unwrap().resume_offer.clone()
+}
+
+pub fn resume_previous_state(state: &Mutex<AppData>) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    let Some(saved) = state_guard.resume_offer.take() else {
+        return Ok(());
+    };
+    let snack = saved.snack_id.and_then(|id| {
+        read_ingredient_config(HOME_DIRECTORY.as_str())
+            .ok()?
+            .ingredients
+            .into_iter()
+            .find(|ing| ing.id == id)
+    });
+    state_guard.resume(saved, snack);
+    Ok(())
+}
+
+pub fn discard_resume_offer(state: &Mutex<AppData>) -> Result<(), CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Operator)?;
+    info!("Starting fresh instead of resuming");
+    state_guard.resume_offer = None;
+    Ok(())
 }
@@ -0,0 +1,367 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+
+use crate::config::TelemetryConfig;
+use crate::state::{AppData, IchibuState, SharedState};
+
+const OUTBOX_FILE: &str = "telemetry_outbox.jsonl";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event")]
+pub enum TelemetryEvent {
+    StateChanged {
+        from: IchibuState,
+        to: IchibuState,
+    },
+    Dispensed {
+        ingredient: String,
+        portion: String,
+        target: f64,
+        weight: Option<f64>,
+        //Time the conveyor spent dispensing, base and top-up together
+        duration_ms: u64,
+    },
+    RanOut {
+        ingredient: String,
+    },
+    Fault {
+        fault: String,
+    },
+    Heartbeat {
+        run_state: IchibuState,
+        ingredient: Option<String>,
+        bowl_count: i64,
+        alerts: usize,
+        uptime_s: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Stamped {
+    node: String,
+    time: String,
+    #[serde(flatten)]
+    event: TelemetryEvent,
+}
+
+// What goes to the broker, and what waits on disk while it can't be reached
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Outgoing {
+    topic: String,
+    payload: String,
+}
+
+// The controls publish through this, it does nothing unless telemetry is enabled
+#[derive(Clone, Default)]
+pub struct Telemetry {
+    sender: Option<mpsc::UnboundedSender<Stamped>>,
+    node: String,
+}
+
+impl Telemetry {
+    pub fn publish(&self, event: TelemetryEvent) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let _ = sender.send(Stamped {
+            node: self.node.clone(),
+            time: chrono::Utc::now().to_rfc3339(),
+            event,
+        });
+    }
+}
+
+fn topic(config: &TelemetryConfig, node: &str, event: &TelemetryEvent) -> String {
+    let topics = &config.topics;
+    let topic = match event {
+        TelemetryEvent::StateChanged { .. } => &topics.state,
+        TelemetryEvent::Dispensed { .. } => &topics.dispense,
+        TelemetryEvent::RanOut { .. } => &topics.run_out,
+        TelemetryEvent::Fault { .. } => &topics.fault,
+        TelemetryEvent::Heartbeat { .. } => &topics.heartbeat,
+    };
+    format!("{}/{}/{}", config.topic_prefix, node, topic)
+}
+
+fn qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+// Messages published while the broker is down, one JSON line each, replayed in order
+struct Outbox {
+    path: String,
+    count: usize,
+    max: usize,
+}
+
+impl Outbox {
+    fn open(dir: &str, max: usize) -> Self {
+        let path = format!("{}/{}", dir, OUTBOX_FILE);
+        let count = fs::read_to_string(&path).map_or(0, |text| text.lines().count());
+        if count > 0 {
+            log::info!("{} telemetry messages buffered from before", count);
+        }
+        Self { path, count, max }
+    }
+
+    fn push(&mut self, message: &Outgoing) {
+        if self.count >= self.max {
+            log::warn!(
+                "Telemetry buffer full, dropping message to {}",
+                message.topic
+            );
+            return;
+        }
+        let written = serde_json::to_string(message)
+            .map_err(std::io::Error::from)
+            .and_then(|line| {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                writeln!(file, "{}", line)
+            });
+        match written {
+            Ok(()) => self.count += 1,
+            Err(e) => log::error!("Couldn't buffer telemetry: {}", e),
+        }
+    }
+
+    fn take(&mut self) -> Vec<Outgoing> {
+        if self.count == 0 {
+            return Vec::new();
+        }
+        let Ok(text) = fs::read_to_string(&self.path) else {
+            return Vec::new();
+        };
+        let _ = fs::remove_file(&self.path);
+        self.count = 0;
+        text.lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    }
+}
+
+// Starts publishing the node's telemetry if it is enabled, with a heartbeat on the side
+pub fn spawn(state: &SharedState, config: &TelemetryConfig) {
+    if !config.enabled {
+        return;
+    }
+    let (node, dir) = {
+        let state_guard = state.lock().unwrap();
+        (state_guard.node.clone(), state_guard.config_dir.clone())
+    };
+    let mut options = MqttOptions::new(format!("ichibu-{}", node), &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    let (client, eventloop) = AsyncClient::new(options, 64);
+    let (connected_sender, connected) = watch::channel(false);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    state.lock().unwrap().telemetry = Telemetry {
+        sender: Some(sender),
+        node,
+    };
+
+    tauri::async_runtime::spawn(run_connection(eventloop, connected_sender));
+    tauri::async_runtime::spawn(run_publisher(
+        config.clone(),
+        client,
+        receiver,
+        connected,
+        Outbox::open(&dir, config.max_buffered),
+    ));
+    tauri::async_runtime::spawn({
+        let state = state.clone();
+        let period = config.heartbeat;
+        async move { run_heartbeat(&state, period).await }
+    });
+}
+
+async fn run_connection(mut eventloop: EventLoop, connected: watch::Sender<bool>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("Telemetry connected to broker");
+                connected.send_replace(true);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                if connected.send_replace(false) {
+                    log::warn!("Telemetry lost the broker: {}", e);
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn run_publisher(
+    config: TelemetryConfig,
+    client: AsyncClient,
+    mut receiver: mpsc::UnboundedReceiver<Stamped>,
+    mut connected: watch::Receiver<bool>,
+    mut outbox: Outbox,
+) {
+    let qos = qos(config.qos);
+    //Anything the client can't take right now waits in the outbox for the next replay
+    let send = |outbox: &mut Outbox, message: Outgoing| {
+        let sent = client.try_publish(
+            message.topic.as_str(),
+            qos,
+            false,
+            message.payload.as_bytes(),
+        );
+        if sent.is_err() {
+            outbox.push(&message);
+        }
+    };
+    let replay = |outbox: &mut Outbox| {
+        let buffered = outbox.take();
+        if !buffered.is_empty() {
+            log::info!("Replaying {} buffered telemetry messages", buffered.len());
+        }
+        for message in buffered {
+            //Once one is buffered again the rest follow it, to keep the order
+            if outbox.count > 0 {
+                outbox.push(&message);
+            } else {
+                send(outbox, message);
+            }
+        }
+    };
+    loop {
+        tokio::select! {
+            stamped = receiver.recv() => {
+                let Some(stamped) = stamped else { return };
+                let message = Outgoing {
+                    topic: topic(&config, &stamped.node, &stamped.event),
+                    payload: serde_json::to_string(&stamped).unwrap(),
+                };
+                if *connected.borrow() {
+                    //Buffered messages go first so the broker sees them in order
+                    replay(&mut outbox);
+                    send(&mut outbox, message);
+                } else {
+                    outbox.push(&message);
+                }
+            }
+            changed = connected.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                if *connected.borrow_and_update() {
+                    replay(&mut outbox);
+                }
+            }
+        }
+    }
+}
+
+async fn run_heartbeat(state: &Mutex<AppData>, period: Duration) {
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let state_guard = state.lock().unwrap();
+        state_guard.telemetry.publish(TelemetryEvent::Heartbeat {
+            run_state: state_guard.get_state(),
+            ingredient: state_guard.get_snack().map(|snack| snack.name.clone()),
+            bowl_count: state_guard.bowl_count(),
+            alerts: state_guard.alerts.active().len(),
+            uptime_s: started.elapsed().as_secs(),
+        });
+    }
+}
+
+#[test]
+fn test_outbox_replays_in_order() {
+    let dir = std::env::temp_dir().join("ichibu_telemetry_test");
+    fs::create_dir_all(&dir).unwrap();
+    let dir = dir.to_str().unwrap();
+    let mut outbox = Outbox::open(dir, 2);
+    outbox.take();
+    let message = |payload: &str| Outgoing {
+        topic: "ichibu/test/state".to_string(),
+        payload: payload.to_string(),
+    };
+    outbox.push(&message("first"));
+    outbox.push(&message("second"));
+    outbox.push(&message("dropped"));
+    //Still there after a restart
+    let mut outbox = Outbox::open(dir, 2);
+    assert_eq!(outbox.count, 2);
+    assert_eq!(outbox.take(), vec![message("first"), message("second")]);
+    assert!(outbox.take().is_empty());
+}
+
+#[test]
+fn test_telemetry_payload() {
+    let config = TelemetryConfig::default();
+    let event = TelemetryEvent::RanOut {
+        ingredient: "Edamame".to_string(),
+    };
+    assert_eq!(topic(&config, "line-1", &event), "ichibu/line-1/run_out");
+    let stamped = Stamped {
+        node: "line-1".to_string(),
+        time: "2024-01-01T00:00:00+00:00".to_string(),
+        event,
+    };
+    let payload: serde_json::Value = serde_json::to_value(&stamped).unwrap();
+    assert_eq!(payload["event"], "RanOut");
+    assert_eq!(payload["ingredient"], "Edamame");
+}
+
+// Needs a broker on localhost:1883, e.g. `mosquitto -v`
+#[tokio::test]
+#[ignore]
+async fn test_publish_to_local_broker() {
+    let options = MqttOptions::new("ichibu-telemetry-test", "localhost", 1883);
+    let (subscriber, mut eventloop) = AsyncClient::new(options, 10);
+    subscriber
+        .subscribe("ichibu/test/#", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+
+    let options = MqttOptions::new("ichibu-test", "localhost", 1883);
+    let (client, publisher_loop) = AsyncClient::new(options, 10);
+    let (connected_sender, connected) = watch::channel(false);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let dir = std::env::temp_dir().join("ichibu_telemetry_broker_test");
+    fs::create_dir_all(&dir).unwrap();
+    tokio::spawn(run_connection(publisher_loop, connected_sender));
+    tokio::spawn(run_publisher(
+        TelemetryConfig::default(),
+        client,
+        receiver,
+        connected,
+        Outbox::open(dir.to_str().unwrap(), 10),
+    ));
+    let telemetry = Telemetry {
+        sender: Some(sender),
+        node: "test".to_string(),
+    };
+    telemetry.publish(TelemetryEvent::Fault {
+        fault: "HatchFault".to_string(),
+    });
+
+    let received = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await.unwrap() {
+                break publish;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(received.topic, "ichibu/test/fault");
+}
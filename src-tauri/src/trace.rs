@@ -0,0 +1,335 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::Role;
+use crate::config::NodeConfig;
+use crate::io;
+use crate::session::CommandError;
+use crate::state::AppData;
+
+// What happened during a cycle, in order. Kept short since every cycle stores one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TraceEvent {
+    HatchCloseStart,
+    HatchCloseEnd { ok: bool },
+    HatchOpenStart,
+    HatchOpenEnd { ok: bool },
+    ConveyorEnabled,
+    DispenseStart { target: f64 },
+    //Portion weight, relative to the empty closed hatch
+    Weight(Option<f64>),
+    EndCondition { timed_out: bool },
+    UserRequest(String),
+    PhotoEye { blocked: bool },
+    Served,
+    Discarded,
+    RanOut,
+}
+
+// Milliseconds into the cycle and what happened
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TracePoint(pub u32, pub TraceEvent);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CycleTrace {
+    //Assigned when the trace is saved
+    pub id: i64,
+    pub started: String,
+    pub ingredient: Option<String>,
+    pub points: Vec<TracePoint>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TraceSummary {
+    pub id: i64,
+    pub started: String,
+    pub ingredient: Option<String>,
+}
+
+// Collects the running cycle's timeline, events outside a cycle are dropped
+#[derive(Default)]
+pub struct TraceRecorder {
+    current: Option<(Instant, CycleTrace)>,
+}
+
+impl TraceRecorder {
+    pub fn start(&mut self, ingredient: Option<String>) {
+        let trace = CycleTrace {
+            id: 0,
+            started: chrono::Utc::now().to_rfc3339(),
+            ingredient,
+            points: Vec::new(),
+        };
+        self.current = Some((Instant::now(), trace));
+    }
+
+    pub fn record(&mut self, event: TraceEvent) {
+        self.record_at(Instant::now(), event);
+    }
+
+    //For events noted earlier and recorded later, e.g. the weights read during a dispense
+    pub fn record_at(&mut self, at: Instant, event: TraceEvent) {
+        if let Some((started, trace)) = &mut self.current {
+            let at = at.saturating_duration_since(*started);
+            let at = at.as_millis().min(u32::MAX as u128) as u32;
+            trace.points.push(TracePoint(at, event));
+        }
+    }
+
+    pub fn finish(&mut self) -> Option<CycleTrace> {
+        self.current.take().map(|(_, trace)| trace)
+    }
+}
+
+pub fn get_cycle_trace(
+    state: &Mutex<AppData>,
+    id: i64,
+) -> Result<Option<CycleTrace>, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    Ok(state_guard.load_trace(id))
+}
+
+pub fn list_cycle_traces(
+    state: &Mutex<AppData>,
+    limit: u32,
+) -> Result<Vec<TraceSummary>, CommandError> {
+    let mut state_guard = state.lock().unwrap();
+    state_guard.authorize(Role::Manager)?;
+    Ok(state_guard.list_traces(limit))
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SimHatch {
+    Open,
+    #[default]
+    Closed,
+    Moving,
+    Stuck,
+}
+
+// A hand-written model of what each event does to the node's hardware, not a simulated
+// controller. A replay only checks the trace against it for anything the real hardware
+// shouldn't have done.
+#[derive(Debug, Default)]
+pub struct SimHardware {
+    pub hatch: SimHatch,
+    pub conveyor_enabled: bool,
+    pub dispensing: bool,
+    pub weight: Option<f64>,
+    pub target: f64,
+    pub bowl_present: bool,
+    //The next hatch opening throws the portion out, so there should be no bowl under it
+    pub discarding: bool,
+}
+
+impl SimHardware {
+    //Returns what looked wrong about the event, if anything
+    pub fn apply(&mut self, event: &TraceEvent) -> Option<String> {
+        match event {
+            TraceEvent::HatchCloseStart | TraceEvent::HatchOpenStart => {
+                if self.dispensing {
+                    return Some("Hatch moved while dispensing".to_string());
+                }
+                self.hatch = SimHatch::Moving;
+            }
+            TraceEvent::HatchCloseEnd { ok } => {
+                self.hatch = if *ok {
+                    SimHatch::Closed
+                } else {
+                    SimHatch::Stuck
+                };
+                if !ok {
+                    return Some("Hatch didn't close".to_string());
+                }
+            }
+            TraceEvent::HatchOpenEnd { ok } => {
+                self.hatch = if *ok { SimHatch::Open } else { SimHatch::Stuck };
+                if !ok {
+                    return Some("Hatch didn't open".to_string());
+                }
+                let discarding = std::mem::take(&mut self.discarding);
+                if discarding && self.bowl_present {
+                    return Some("Portion discarded into a bowl".to_string());
+                }
+                if !discarding && !self.bowl_present {
+                    return Some("Portion dropped without a bowl under the chute".to_string());
+                }
+            }
+            TraceEvent::ConveyorEnabled => self.conveyor_enabled = true,
+            TraceEvent::DispenseStart { target } => {
+                self.dispensing = true;
+                self.target = *target;
+                if self.hatch != SimHatch::Closed {
+                    return Some(format!("Dispensed onto a {:?} hatch", self.hatch));
+                }
+                if !self.conveyor_enabled {
+                    return Some("Dispensed with the conveyor disabled".to_string());
+                }
+            }
+            TraceEvent::Weight(weight) => {
+                let previous = self.weight;
+                self.weight = *weight;
+                match (previous, weight) {
+                    (_, None) => return Some("Scale couldn't be read".to_string()),
+                    (Some(previous), Some(weight))
+                        if self.dispensing && *weight < previous - 1. =>
+                    {
+                        return Some(format!("Weight dropped from {} to {}", previous, weight));
+                    }
+                    _ => (),
+                }
+            }
+            TraceEvent::EndCondition { timed_out } => {
+                self.dispensing = false;
+                if *timed_out {
+                    return Some(format!("Timed out at {:?} of {}", self.weight, self.target));
+                }
+            }
+            TraceEvent::PhotoEye { blocked } => self.bowl_present = *blocked,
+            TraceEvent::Discarded => self.discarding = true,
+            TraceEvent::UserRequest(_) | TraceEvent::Served | TraceEvent::RanOut => (),
+        }
+        None
+    }
+}
+
+// What a replay saw, one line per trace point with the time it happened
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub lines: Vec<(u32, String)>,
+    pub anomalies: Vec<String>,
+}
+
+//Steps through a trace and lists what looked wrong
+pub fn replay(trace: &CycleTrace) -> Replay {
+    let mut hardware = SimHardware::default();
+    let mut replay = Replay::default();
+    for TracePoint(at, event) in &trace.points {
+        let anomaly = hardware.apply(event);
+        replay.lines.push((
+            *at,
+            format!(
+                "{:>7}ms {:<40} hatch {:?}, weight {:?}, bowl {}",
+                at,
+                format!("{:?}", event),
+                hardware.hatch,
+                hardware.weight,
+                hardware.bowl_present
+            ),
+        ));
+        if let Some(anomaly) = anomaly {
+            replay
+                .lines
+                .push((*at, format!("          !! {}", anomaly)));
+            replay.anomalies.push(format!("{}ms: {}", at, anomaly));
+        }
+    }
+    replay
+}
+
+// `ichibu --replay <id> [node dir] [--fast]`, reads the trace from the node's database and
+// prints it at the pace it was recorded unless fast
+pub fn replay_cli(args: &[String]) {
+    let id: i64 = args
+        .first()
+        .and_then(|id| id.parse().ok())
+        .expect("--replay needs a trace id");
+    let fast = args.iter().any(|arg| arg == "--fast");
+    let dir = match args.get(1).filter(|arg| *arg != "--fast") {
+        Some(dir) => NodeConfig {
+            name: String::new(),
+            dir: dir.clone(),
+        }
+        .path(),
+        None => NodeConfig::local().path(),
+    };
+    let (database, _) = io::initialize_database(&dir);
+    let Some(trace) = database.load_trace(id) else {
+        println!("No cycle trace {} in {}", id, dir);
+        return;
+    };
+    println!(
+        "Cycle {} started {} with {:?}",
+        trace.id, trace.started, trace.ingredient
+    );
+    let replay = replay(&trace);
+    let mut last = 0;
+    for (at, line) in &replay.lines {
+        if !fast {
+            std::thread::sleep(Duration::from_millis(at.saturating_sub(last) as u64));
+        }
+        last = *at;
+        println!("{}", line);
+    }
+    if replay.anomalies.is_empty() {
+        println!("Nothing unusual");
+    } else {
+        println!("{} anomalies:", replay.anomalies.len());
+        for anomaly in replay.anomalies {
+            println!("  {}", anomaly);
+        }
+    }
+}
+
+#[test]
+fn test_replay_flags_anomalies() {
+    use TraceEvent::*;
+    let points = vec![
+        TracePoint(0, HatchCloseStart),
+        TracePoint(900, HatchCloseEnd { ok: true }),
+        TracePoint(1900, ConveyorEnabled),
+        TracePoint(1900, Weight(Some(0.))),
+        TracePoint(1910, DispenseStart { target: 25. }),
+        TracePoint(5000, Weight(Some(12.))),
+        TracePoint(5001, EndCondition { timed_out: true }),
+        TracePoint(5002, DispenseStart { target: 25. }),
+        TracePoint(7000, Weight(Some(25.))),
+        TracePoint(7001, EndCondition { timed_out: false }),
+        TracePoint(9000, UserRequest("Regular".to_string())),
+        TracePoint(9001, Served),
+        TracePoint(9002, HatchOpenStart),
+        TracePoint(9800, HatchOpenEnd { ok: true }),
+    ];
+    let trace = CycleTrace {
+        id: 1,
+        started: "2024-01-01T12:00:00+00:00".to_string(),
+        ingredient: Some("Edamame".to_string()),
+        points,
+    };
+    let replayed = replay(&trace);
+    assert_eq!(replayed.lines.len(), trace.points.len() + 2);
+    let anomalies = replayed.anomalies;
+    assert_eq!(anomalies.len(), 2);
+    assert!(anomalies[0].starts_with("5001ms: Timed out"));
+    assert!(anomalies[1].contains("without a bowl"));
+
+    let discard = vec![
+        TracePoint(0, Discarded),
+        TracePoint(1, HatchOpenStart),
+        TracePoint(800, HatchOpenEnd { ok: true }),
+        TracePoint(900, PhotoEye { blocked: true }),
+        TracePoint(1000, Discarded),
+        TracePoint(1001, HatchOpenStart),
+        TracePoint(1800, HatchOpenEnd { ok: true }),
+    ];
+    let anomalies = replay(&CycleTrace {
+        points: discard,
+        ..trace
+    })
+    .anomalies;
+    assert_eq!(
+        anomalies,
+        vec!["1800ms: Portion discarded into a bowl".to_string()]
+    );
+
+    let mut recorder = TraceRecorder::default();
+    recorder.record(Served);
+    recorder.start(None);
+    recorder.record(PhotoEye { blocked: true });
+    let recorded = recorder.finish().unwrap();
+    assert_eq!(recorded.points.len(), 1);
+    assert!(recorder.finish().is_none());
+}
@@ -6,9 +6,10 @@ import Header from './Header'
 import SetupScreen from './SetupScreen';
 import Home from './home'
 import DispenseScreen from './dispense-screen';
-import { DispenseType, Ingredient, UiData, User } from './types';
+import { AlertSound, DispenseType, Ingredient, UiData, User } from './types';
 import { useEffect, useState } from 'react';
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 
 
 const ArrayBufferToBase64 = (buffer: ArrayBuffer): string => {
@@ -96,6 +97,24 @@ function App() {
 },[]);
 
 
+  useEffect(() => {
+    const playAlert = async (alert: AlertSound) => {
+      if (!alert.sound) return;
+      try {
+        const data: ArrayBuffer = await invoke('get_sound', {filename: alert.sound});
+        const url = URL.createObjectURL(new Blob([data]));
+        const audio = new Audio(url);
+        audio.onended = () => URL.revokeObjectURL(url);
+        await audio.play();
+      } catch (error) {
+        console.error("Failed to play alert:", error);
+      }
+    };
+    const unlisten = listen<AlertSound>('alert', (event) => playAlert(event.payload));
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
 
   return (
     <main className=" cursor-none h-full w-full bg-slate-950">
@@ -103,8 +122,8 @@ function App() {
       <Header currentDispenseType ={dispenseType}  setDispenseType={setDispenseType} user={user}/>
         <Routes>
           <Route path="/" element={<Home setUser={setUser}/>}/>
-          <Route path="/setup-screen" element={<SetupScreen dispenseType={dispenseType} snacks={snacks} setIngredient={setSelectedIngredient} setUser={setUser}/>}/>
-          <Route path="/dispense-screen" element={<DispenseScreen snack={selectedIngredient} mode={dispenseType}/>}/>
+          <Route path="/setup-screen" element={<SetupScreen snacks={snacks} setIngredient={setSelectedIngredient} setUser={setUser}/>}/>
+          <Route path="/dispense-screen" element={<DispenseScreen snack={selectedIngredient}/>}/>
         </Routes>
       </Router>
     </main>
@@ -1,12 +1,14 @@
-import React from 'react';
+import React, { useEffect } from 'react';
 import logo from './assets/caldo-logo-full-horizontal-white.svg';
 import foodLogo from './assets/FOOD_Logo_rev (1).png';
 import home from './assets/home.svg';
 
 import {DispenseType, User} from './types.ts'
 import SettingsMenu from './settings-menu.tsx';
+import FleetBar from './components/fleet-bar.tsx';
 import { Button } from './components/ui/button.tsx';
 import { useNavigate, useLocation } from 'react-router-dom';
+import { SESSION_ENDED } from './lib/session';
 
 interface SettingsMenuProps{
     user: User
@@ -19,6 +21,12 @@ const Header: React.FC<SettingsMenuProps> = ({user, currentDispenseType, setDisp
     const navigate = useNavigate();
     const location = useLocation();
     const isHome = location.pathname === '/';
+
+    useEffect(() => {
+        const backToPin = () => navigate('/');
+        window.addEventListener(SESSION_ENDED, backToPin);
+        return () => window.removeEventListener(SESSION_ENDED, backToPin);
+    }, [navigate]);
     return (
         <header className='bg-slate-950 absolute top-0 left-0 w-full'>
             <div className='flex justify-center items-center gap-2'>
@@ -26,6 +34,7 @@ const Header: React.FC<SettingsMenuProps> = ({user, currentDispenseType, setDisp
                 <div className="pl-10 text-white text-4xl font-bold">x</div> 
                 <img src={foodLogo} alt="Caldo logo" className='top-2 h-35 w-60'/>
             </div>
+            <FleetBar/>
             
                 <div className='absolute top-4 right-0 h-12'>
                     {sudoLoggedIn && <SettingsMenu currentDispenseType={currentDispenseType} setDispenseType={setDispenseType} currentUser={user}/>}
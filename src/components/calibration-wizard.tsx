@@ -0,0 +1,136 @@
+import React, { useEffect, useState } from 'react';
+import Keyboard from 'react-simple-keyboard';
+import 'react-simple-keyboard/build/css/index.css';
+import { invoke } from '@/lib/session';
+
+import { CalibrationFit, CalibrationSession } from '@/types';
+import { Button } from './ui/button';
+
+interface CalibrationWizardProps {
+    onDone: () => void;
+}
+
+// Tare, then reference weights, then the fit and its residuals before the coefficients are saved
+const CalibrationWizard: React.FC<CalibrationWizardProps> = ({ onDone }) => {
+    const [calibration, setCalibration] = useState<CalibrationSession | null>(null);
+    const [fit, setFit] = useState<CalibrationFit | null>(null);
+    const [weight, setWeight] = useState('');
+    const [saved, setSaved] = useState<CalibrationFit | null>(null);
+    const [error, setError] = useState<string | null>(null);
+
+    useEffect(() => {
+        if (saved) return;
+        const fetchCalibration = async () => {
+            try {
+                setCalibration(await invoke<CalibrationSession>('get_calibration'));
+                setFit(await invoke<CalibrationFit | null>('get_calibration_fit'));
+            } catch (error) {
+                console.error("Failed to get calibration:", error);
+            }
+        };
+        fetchCalibration();
+        const interval = setInterval(fetchCalibration, 500);
+        return () => clearInterval(interval);
+    }, [saved]);
+
+    const command = async <T,>(name: string, args?: Record<string, unknown>) => {
+        try {
+            setError(null);
+            return await invoke<T>(name, args);
+        } catch (error) {
+            setError(String(error));
+            return null;
+        }
+    };
+
+    const handleAddWeight = async () => {
+        const grams = parseFloat(weight);
+        if (!(grams > 0)) return;
+        if (await command<boolean>('request_calibration_reading', { request: { AddWeight: grams } })) {
+            setWeight('');
+        }
+    };
+
+    const handleSave = async () => {
+        const result = await command<CalibrationFit>('save_calibration');
+        if (result) setSaved(result);
+    };
+
+    const handleCancel = async () => {
+        await command('cancel_calibration');
+        onDone();
+    };
+
+    if (saved) {
+        return (
+            <div className='space-y-2 text-white'>
+                <div className='text-3xl'>Saved, residual {saved.residual.toFixed(2)} g</div>
+                <div className='text-3xl text-yellow-300'>Restart the kiosk to use the new calibration</div>
+                <Button className='w-full text-4xl h-32 bg-blue-500' onClick={onDone}>
+                    Done
+                </Button>
+            </div>
+        );
+    }
+
+    if (!calibration) return null;
+    const busy = calibration.pending !== null;
+
+    return (
+        <div className='space-y-2 text-white'>
+            <div className={`text-3xl ${calibration.tare ? 'text-gray-400' : 'font-bold'}`}>
+                {calibration.tare ? '✓' : '1.'} Empty the scale and tare
+            </div>
+            <Button
+                className='w-full text-4xl h-32 bg-blue-500'
+                disabled={busy}
+                onClick={() => command('request_calibration_reading', { request: 'Tare' })}
+            >
+                {calibration.pending === 'Tare' ? 'Reading Scale...' : calibration.tare ? 'Tare Again' : 'Tare'}
+            </Button>
+            {calibration.tare && (
+                <>
+                    <div className='text-3xl font-bold'>2. Place a reference weight and enter grams</div>
+                    <input className='w-full text-4xl text-center text-black' value={weight} readOnly placeholder='grams' />
+                    <div style={{ width: '400px', margin: 'auto' }}>
+                        <Keyboard
+                            onChange={setWeight}
+                            layout={{ default: ['1 2 3', '4 5 6', '7 8 9', '. 0 {bksp}'] }}
+                            display={{ '{bksp}': 'del' }}
+                            buttonTheme={[{ class: 'text-3xl font-bold cursor-none text-black', buttons: '1 2 3 4 5 6 7 8 9 0 . {bksp}' }]}
+                            theme='hg-theme-default hg-layout-numeric cursor-none'
+                        />
+                    </div>
+                    <Button
+                        className='w-full text-4xl h-32 bg-blue-500'
+                        disabled={busy || !(parseFloat(weight) > 0)}
+                        onClick={handleAddWeight}
+                    >
+                        {calibration.pending && calibration.pending !== 'Tare' ? 'Reading Scale...' : 'Add Weight'}
+                    </Button>
+                </>
+            )}
+            {calibration.samples.map((sample, index) => (
+                <div key={index} className='text-2xl'>
+                    {sample.weight.toFixed(1)} g
+                    {fit && fit.errors[index] !== undefined && ` (off by ${fit.errors[index].toFixed(2)} g)`}
+                </div>
+            ))}
+            {fit && (
+                <>
+                    <div className='text-3xl font-bold'>3. Residual {fit.residual.toFixed(2)} g</div>
+                    <Button className='w-full text-4xl h-32 bg-green-600' disabled={busy} onClick={handleSave}>
+                        Save Calibration
+                    </Button>
+                </>
+            )}
+            <Button className='w-full text-4xl h-32 bg-destructive' onClick={handleCancel}>
+                Cancel Calibration
+            </Button>
+            {calibration.error && <div className='text-2xl text-red-400'>{calibration.error}</div>}
+            {error && <div className='text-2xl text-red-400'>{error}</div>}
+        </div>
+    );
+}
+
+export default CalibrationWizard;
@@ -0,0 +1,91 @@
+import React, { useEffect, useState } from 'react';
+import { invoke } from '@/lib/session';
+
+import { CleaningSession } from '@/types';
+import { Button } from './ui/button';
+
+interface CleaningPanelProps {
+    onDone: () => void;
+}
+
+// Walks the operator through the configured cleaning steps, then checks the scale zero
+const CleaningPanel: React.FC<CleaningPanelProps> = ({ onDone }) => {
+    const [cleaning, setCleaning] = useState<CleaningSession | null>(null);
+    const [error, setError] = useState<string | null>(null);
+
+    useEffect(() => {
+        const fetchCleaning = async () => {
+            try {
+                const session = await invoke<CleaningSession | null>('get_cleaning');
+                setCleaning(session);
+                if (!session) onDone();
+            } catch (error) {
+                console.error("Failed to get cleaning:", error);
+            }
+        };
+        fetchCleaning();
+        const interval = setInterval(fetchCleaning, 500);
+        return () => clearInterval(interval);
+    }, []);
+
+    if (!cleaning) return null;
+
+    const command = async (name: string, args?: Record<string, unknown>) => {
+        try {
+            setError(null);
+            await invoke(name, args);
+        } catch (error) {
+            setError(String(error));
+        }
+    };
+
+    const current = cleaning.steps.findIndex((step) => !step.done);
+    const zeroCheck = cleaning.zero_check;
+
+    return (
+        <div className='space-y-2 text-white'>
+            {cleaning.steps.map((step, index) => (
+                <div key={step.name} className={`text-3xl ${index === current ? 'font-bold' : step.done ? 'text-gray-400' : ''}`}>
+                    {step.done ? '✓' : `${index + 1}.`} {step.name}
+                </div>
+            ))}
+            {current >= 0 && (
+                <Button className='w-full text-4xl h-32 bg-green-600' onClick={() => command('confirm_cleaning_step')}>
+                    Done: {cleaning.steps[current].name}
+                </Button>
+            )}
+            <Button
+                className='w-full text-4xl h-32 bg-blue-500'
+                disabled={cleaning.pending !== null}
+                onClick={() => command('request_cleaning_action', { request: 'Jog' })}
+            >
+                Jog Conveyor
+            </Button>
+            {current < 0 && (
+                <Button
+                    className='w-full text-4xl h-32 bg-blue-500'
+                    disabled={cleaning.pending !== null}
+                    onClick={() => command('request_cleaning_action', { request: 'ZeroCheck' })}
+                >
+                    {cleaning.pending === 'ZeroCheck' ? 'Checking Scale...' : 'Check Scale Zero'}
+                </Button>
+            )}
+            {zeroCheck && (
+                <div className={`text-3xl ${'Ok' in zeroCheck ? '' : 'text-red-400'}`}>
+                    {'Ok' in zeroCheck ? `Scale reads ${zeroCheck.Ok.toFixed(1)}` : 'Scale is not at zero, check the hopper is seated'}
+                </div>
+            )}
+            {current < 0 && zeroCheck && 'Ok' in zeroCheck && (
+                <Button className='w-full text-4xl h-32 bg-green-600' onClick={() => command('finish_cleaning')}>
+                    Finish Cleaning
+                </Button>
+            )}
+            <Button className='w-full text-4xl h-32 bg-destructive' onClick={() => command('cancel_cleaning')}>
+                Cancel Cleaning
+            </Button>
+            {error && <div className='text-2xl text-red-400'>{error}</div>}
+        </div>
+    );
+}
+
+export default CleaningPanel;
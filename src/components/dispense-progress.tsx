@@ -0,0 +1,38 @@
+import React, { useEffect, useState } from 'react';
+import { listen } from '@tauri-apps/api/event';
+
+import { DispenseProgress } from '@/types';
+
+// Fills up as the portion builds on the closed hatch, hidden between portions
+const DispenseProgressBar: React.FC = () => {
+    const [progress, setProgress] = useState<DispenseProgress | null>(null);
+
+    useEffect(() => {
+        const unlisten = listen<DispenseProgress>('dispense-progress', (event) => {
+            setProgress(event.payload.complete ? null : event.payload);
+        });
+        return () => {
+            unlisten.then((stop) => stop());
+        };
+    }, []);
+
+    if (!progress) return null;
+
+    return (
+        <div className='w-full space-y-2'>
+            <div className='w-full h-6 rounded bg-gray-700 overflow-hidden'>
+                <div
+                    className='h-full bg-green-600 transition-all duration-200'
+                    style={{ width: `${progress.progress ?? 0}%` }}
+                />
+            </div>
+            <div className='text-white text-2xl text-center'>
+                {progress.weight !== null
+                    ? `${progress.weight.toFixed(0)} / ${progress.target.toFixed(0)} g`
+                    : `${progress.target.toFixed(0)} g`}
+            </div>
+        </div>
+    );
+}
+
+export default DispenseProgressBar;
@@ -0,0 +1,57 @@
+import React, { useEffect, useState } from 'react';
+import { invoke } from '@/lib/session';
+import { useNavigate } from 'react-router-dom';
+
+import { FleetView } from '@/types';
+import { Button } from './ui/button';
+
+// Only shows when the app runs a fleet, one button per node
+const FleetBar: React.FC = () => {
+    const [fleet, setFleet] = useState<FleetView | null>(null);
+    const navigate = useNavigate();
+
+    useEffect(() => {
+        const fetchFleet = async () => {
+            try {
+                setFleet(await invoke<FleetView | null>('get_fleet'));
+            } catch (error) {
+                console.error("Failed to get fleet:", error);
+            }
+        };
+        fetchFleet();
+        const interval = setInterval(fetchFleet, 1000);
+        return () => clearInterval(interval);
+    }, []);
+
+    if (!fleet || fleet.nodes.length < 2) return null;
+
+    //Needs someone logged in at the node on screen
+    const selectNode = async (name: string) => {
+        try {
+            if (await invoke<boolean>('select_node', { name })) {
+                //Screens reload the newly selected node's state from home
+                navigate('/');
+                setFleet({ ...fleet, selected: name });
+            }
+        } catch (error) {
+            console.error("Failed to select node:", error);
+        }
+    };
+
+    return (
+        <div className='flex justify-center gap-2 pb-2'>
+            {fleet.nodes.map((node) => (
+                <Button
+                    key={node.name}
+                    className={`h-14 flex flex-col ${node.name === fleet.selected ? 'bg-slate-600' : 'bg-slate-800'}`}
+                    onClick={() => selectNode(node.name)}
+                >
+                    <span className='text-lg'>{node.name}{node.alerts > 0 && ` (${node.alerts})`}</span>
+                    <span className='text-xs'>{node.snack ?? 'No snack'} · {node.timed_out ? 'Timed out' : node.run_state}</span>
+                </Button>
+            ))}
+        </div>
+    );
+}
+
+export default FleetBar;
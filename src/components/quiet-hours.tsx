@@ -0,0 +1,75 @@
+import React, { useEffect, useState } from 'react';
+import { invoke } from '@/lib/session';
+
+import { QuietHours } from '@/types';
+import { Button } from './ui/button';
+
+const DEFAULT_QUIET_HOURS: QuietHours = { start: '22:00', end: '06:00' };
+
+// Moves an "HH:MM" time by whole hours, keeping the minutes
+const shiftHour = (time: string, hours: number) => {
+    const [hour, minute] = time.split(':');
+    const shifted = (parseInt(hour, 10) + hours + 24) % 24;
+    return `${shifted.toString().padStart(2, '0')}:${minute}`;
+};
+
+// The buzzer stays silent between start and end, alerts still show on screen
+const QuietHoursControl: React.FC = () => {
+    const [saved, setSaved] = useState<QuietHours | null>(null);
+    const [draft, setDraft] = useState<QuietHours>(DEFAULT_QUIET_HOURS);
+    const [error, setError] = useState<string | null>(null);
+
+    useEffect(() => {
+        invoke<QuietHours | null>('get_quiet_hours')
+            .then((quietHours) => {
+                setSaved(quietHours);
+                if (quietHours) setDraft(quietHours);
+            })
+            .catch((error) => console.error("failed to get quiet hours: ", error));
+    }, []);
+
+    const save = async (quietHours: QuietHours | null) => {
+        try {
+            setError(null);
+            await invoke('set_quiet_hours', { quietHours });
+            setSaved(quietHours);
+        } catch (error) {
+            setError(String(error));
+        }
+    };
+
+    const stepper = (label: string, key: keyof QuietHours) => (
+        <div className='flex items-center space-x-2'>
+            <Button className='text-4xl h-24 w-24 bg-gray-500' onClick={() => setDraft({ ...draft, [key]: shiftHour(draft[key], -1) })}>
+                -
+            </Button>
+            <div className='flex-1 text-center text-3xl'>{label} {draft[key]}</div>
+            <Button className='text-4xl h-24 w-24 bg-gray-500' onClick={() => setDraft({ ...draft, [key]: shiftHour(draft[key], 1) })}>
+                +
+            </Button>
+        </div>
+    );
+
+    const unchanged = saved !== null && saved.start === draft.start && saved.end === draft.end;
+
+    return (
+        <div className='space-y-2 text-white'>
+            <div className='text-3xl'>
+                Quiet Hours: {saved ? `${saved.start} - ${saved.end}` : 'Off'}
+            </div>
+            {stepper('From', 'start')}
+            {stepper('Until', 'end')}
+            <Button className='w-full text-4xl h-32 bg-green-600' disabled={unchanged} onClick={() => save(draft)}>
+                Save Quiet Hours
+            </Button>
+            {saved && (
+                <Button className='w-full text-4xl h-32 bg-gray-500' onClick={() => save(null)}>
+                    Turn Off Quiet Hours
+                </Button>
+            )}
+            {error && <div className='text-2xl text-red-400'>{error}</div>}
+        </div>
+    );
+}
+
+export default QuietHoursControl;
@@ -1,4 +1,4 @@
-import React from 'react';
+import React, { useState } from 'react';
 import { Card, CardContent } from "./ui/card";
 import { 
     Carousel, 
@@ -9,34 +9,34 @@ import {
 } from "./ui/carousel";
 
 
-import { DispenseType, Ingredient, IchibuState, User } from '@/types';
+import { Ingredient, IchibuState, User } from '@/types';
 import { useNavigate } from 'react-router-dom';
-import { invoke } from '@tauri-apps/api/core';
+import { invoke } from "@/lib/session";
 
 interface SnackCarouselProps {
-    dispenseType: DispenseType
     snacks: Ingredient[]
     setSnack: (snack: Ingredient) => void,
     setUser: (user: User) => void
 
 }
 
-const SnackCarousel: React.FC<SnackCarouselProps> = ({dispenseType, snacks, setSnack, setUser}) => {
+const SnackCarousel: React.FC<SnackCarouselProps> = ({snacks, setSnack, setUser}) => {
     const navigate = useNavigate()
+    const [error, setError] = useState<string | null>(null)
     const handleClick = async (snack: Ingredient) => {
-        let state: IchibuState = IchibuState.Ready;
+        const state: IchibuState = IchibuState.Running;
         try {
+            setError(null)
 
             await invoke("update_current_ingredient", {snack: snack.id})
-            if (dispenseType === DispenseType.Classic) {
-                state = IchibuState.RunningClassic;
-            } else if (dispenseType === DispenseType.LargeSmall) {
-                state = IchibuState.RunningSized;
-            }
             console.log("Snack Selected updating state with: ", state, snack);
             await invoke("update_run_state", {newState: state})
+            // The dispense screen is for customers, so whoever set it up is logged out
+            await invoke("log_out")
         } catch(error){
             console.error("Failed to send state: ", error)
+            setError(String(error))
+            return
         }
         setSnack(snack);
         setUser(User.None)
@@ -45,6 +45,7 @@ const SnackCarousel: React.FC<SnackCarouselProps> = ({dispenseType, snacks, setS
     return (
         <div className="w-full">
         <div className="mx-auto max-w-5xl">
+            {error && <div className="text-4xl text-center text-red-400">{error}</div>}
             <Carousel 
                 opts={{
                     align: "start", 
@@ -1,26 +1,30 @@
 import React, { useEffect, useState } from "react";
-import { DispenseType, Ingredient, UiRequest} from "./types";
+import { DispenseType, Ingredient, PortionRequest, PortionSize, UiRequest} from "./types";
 import recycle from "./assets/recycle.svg"
 import { invoke } from "@tauri-apps/api/core";
 import SvgViewer from "./components/svg-viewer";
 import { Button } from "./components/ui/button";
 import SoundWave from "./components/soundwave";
+import DispenseProgressBar from "./components/dispense-progress";
 
 
 interface DispenseScreenProps{
     snack: Ingredient | undefined,
-    mode: DispenseType
 }
 
-const DispenseScreen: React.FC<DispenseScreenProps> = ({snack, mode}) => {
+const DispenseScreen: React.FC<DispenseScreenProps> = ({snack}) => {
     
+    // The snack can override the kiosk wide mode, so ask the backend which one applies
+    const [mode, setMode] = useState<DispenseType>(DispenseType.Classic);
     const classicModeOn = mode == DispenseType.Classic;
     const smallLargeModeOn = mode === DispenseType.LargeSmall;
+    const multiSizeModeOn = mode === DispenseType.MultiSize;
+    const [sizes, setSizes] = useState<PortionSize[]>([]);
 
     const [bowlCount, setBowlCount] = useState<Number>(0);
     const [peBlocked, setPeBlocked] = useState<Boolean>(false);
     const [dispenserBusy, setDispenserBusy] = useState<Boolean>(false);
-    const [size, setSize] = useState<UiRequest>(UiRequest.None);
+    const [size, setSize] = useState<PortionRequest>(UiRequest.None);
     const [timedOut, setTimedOut] = useState<Boolean>(false);
 
     const fetchIchibuState = async () => {
@@ -79,11 +83,12 @@ const DispenseScreen: React.FC<DispenseScreenProps> = ({snack, mode}) => {
         return size !== UiRequest.None ? `${baseClasses} ${readyClass}` : `${baseClasses} ${notReadyClass}`
     };
 
-    const getSizeButtonClass = (buttonType: UiRequest) => {
-        const baseClass = "w-1/2 h-[150px] text-6xl font-bold focus:outline-none focus:ring-0 border-0";
+    const getSizeButtonClass = (buttonType: PortionRequest, width: string = "w-1/2") => {
+        const baseClass = `${width} h-[150px] text-6xl font-bold focus:outline-none focus:ring-0 border-0`;
         const selectedColor = "bg-blue-600 hover:bg-blue-600";
         const gray = "bg-gray-500 hover:bg-gray-500 active:bg-gray-500";
-        return size === buttonType ? `${baseClass} ${selectedColor}` : `${baseClass} ${gray}`;
+        const selected = JSON.stringify(size) === JSON.stringify(buttonType);
+        return selected ? `${baseClass} ${selectedColor}` : `${baseClass} ${gray}`;
         
     }
 
@@ -100,7 +105,7 @@ const DispenseScreen: React.FC<DispenseScreenProps> = ({snack, mode}) => {
         return size !== UiRequest.None ? false : true;
     }
 
-    const handleClick = async (size: UiRequest) => {
+    const handleClick = async (size: PortionRequest) => {
         try {
             if (classicModeOn) {
                 size = UiRequest.RegularDispense
@@ -112,6 +117,15 @@ const DispenseScreen: React.FC<DispenseScreenProps> = ({snack, mode}) => {
         }
     }
 
+        useEffect(() => {
+            invoke<DispenseType>("get_dispense_type")
+                .then(setMode)
+                .catch((error) => console.error("Failed to get dispense type: ", error));
+            invoke<PortionSize[]>("get_portion_sizes")
+                .then(setSizes)
+                .catch((error) => console.error("Failed to get portion sizes: ", error));
+        }, [snack]);
+
         useEffect(() => {
             if (!peBlocked) {
                 setSize(UiRequest.None);
@@ -156,6 +170,20 @@ const DispenseScreen: React.FC<DispenseScreenProps> = ({snack, mode}) => {
                         </Button>
                     </div>
                 }
+                {
+                    multiSizeModeOn &&
+                    <div className="grid grid-cols-2 gap-4 w-full">
+                        {sizes.map((portion) => (
+                            <Button
+                                key={portion.name}
+                                className={getSizeButtonClass({Size: portion.name}, "w-full")}
+                                onClick={() => setSize({Size: portion.name})}
+                            >
+                                {portion.price !== null ? `${portion.name} $${portion.price.toFixed(2)}` : portion.name}
+                            </Button>
+                        ))}
+                    </div>
+                }
 
                     <Button  
                         disabled={disableButton()}
@@ -171,6 +199,7 @@ const DispenseScreen: React.FC<DispenseScreenProps> = ({snack, mode}) => {
                         <div className="flex items-center justify-center py-7">
                             {!timedOut && dispenserBusy && <SoundWave/>}
                         </div>
+                        {!timedOut && <DispenseProgressBar/>}
                     </div>
                 </div>
                 <div id="bag counter" className="absolute bottom-0 left-0 w-full flex items-center justify-center space-x-2 py-10">
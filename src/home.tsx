@@ -1,4 +1,6 @@
 
+import { useEffect } from 'react';
+import { invoke } from '@tauri-apps/api/core';
 import LogIn from './login';
 import { User } from './types';
 
@@ -7,7 +9,11 @@ interface HomeProps{
 }
 
 const Home: React.FC<HomeProps> = ({setUser}) => {
-   
+    // Coming back to the PIN screen always ends the session
+    useEffect(() => {
+        setUser(User.None);
+        invoke('log_out').catch((error) => console.error("Failed to log out:", error));
+    }, []);
 
 
     return (
@@ -0,0 +1,21 @@
+import { invoke as tauriInvoke, InvokeArgs } from "@tauri-apps/api/core"
+
+export const SESSION_ENDED = "session-ended"
+
+// NotLoggedIn once the session has idled out, Forbidden when the role isn't enough
+function isSessionError(error: unknown) {
+  return error === "NotLoggedIn" ||
+    (typeof error === "object" && error !== null && "Forbidden" in error)
+}
+
+// Same as tauri's invoke, but a session error sends the UI back to the PIN screen
+export async function invoke<T>(command: string, args?: InvokeArgs): Promise<T> {
+  try {
+    return await tauriInvoke<T>(command, args)
+  } catch (error) {
+    if (isSessionError(error)) {
+      window.dispatchEvent(new Event(SESSION_ENDED))
+    }
+    throw error
+  }
+}
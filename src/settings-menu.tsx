@@ -1,4 +1,4 @@
-import React, { useState} from 'react';
+import React, { useEffect, useState} from 'react';
 import { Button } from "@/components/ui/button";
 import { Switch } from "@/components/ui/switch";
 import {
@@ -9,8 +9,11 @@ import {
 import gear from '@/assets/gear-white.svg';
 import { Label } from '@radix-ui/react-dropdown-menu';
 
-import { DispenseType, IchibuState, User } from '@/types';
-import { invoke } from '@tauri-apps/api/core';
+import { Alert, DispenseType, IchibuState, SanitationReport, SanitationStatus, SavedState, ScaleFault, StagingReport, User } from '@/types';
+import { invoke } from '@/lib/session';
+import CleaningPanel from '@/components/cleaning-panel';
+import CalibrationWizard from '@/components/calibration-wizard';
+import QuietHoursControl from '@/components/quiet-hours';
 
 
 interface SettingsMenuProps {
@@ -21,14 +24,109 @@ interface SettingsMenuProps {
 
 const SettingsMenu: React.FC<SettingsMenuProps> = ({ currentUser, currentDispenseType, setDispenseType }) => {
   const superVisibility = currentUser === User.Admin || currentUser === User.Manager;
+  const adminVisibility = currentUser === User.Admin;
   
   // State to track whether the dropdown is open or closed
   const [open, setOpen] = useState(false);
+  const [resumeOffer, setResumeOffer] = useState<SavedState | null>(null);
+  const [alerts, setAlerts] = useState<Alert[]>([]);
+  const [staging, setStaging] = useState<StagingReport | null>(null);
+  const [cleaning, setCleaning] = useState(false);
+  const [calibrating, setCalibrating] = useState(false);
+  const [sanitation, setSanitation] = useState<SanitationStatus>(SanitationStatus.Ok);
+  const [scaleFault, setScaleFault] = useState<ScaleFault | null>(null);
+  const [exportMessage, setExportMessage] = useState<string | null>(null);
+
+  useEffect(() => {
+    if (!open) return;
+    invoke<SavedState | null>("get_resume_offer")
+      .then(setResumeOffer)
+      .catch((error) => console.error("failed to get resume offer: ", error));
+    invoke<Alert[]>("get_alerts")
+      .then(setAlerts)
+      .catch((error) => console.error("failed to get alerts: ", error));
+    invoke<StagingReport>("get_staging_report")
+      .then(setStaging)
+      .catch((error) => console.error("failed to get staging report: ", error));
+    invoke<SanitationStatus>("get_sanitation_status")
+      .then(setSanitation)
+      .catch((error) => console.error("failed to get sanitation status: ", error));
+    invoke<ScaleFault | null>("get_scale_fault")
+      .then(setScaleFault)
+      .catch((error) => console.error("failed to get scale fault: ", error));
+    invoke<unknown>("get_cleaning")
+      .then((session) => setCleaning(session !== null))
+      .catch((error) => console.error("failed to get cleaning: ", error));
+  }, [open]);
+
+  const handleAcknowledge = async () => {
+    try {
+      await invoke("acknowledge_alerts");
+      setAlerts([]);
+    } catch (error) {
+      console.error("failed to acknowledge alerts: ", error);
+    }
+  }
+
+  const handleClearScaleFault = async () => {
+    try {
+      await invoke("clear_scale_fault");
+      setScaleFault(null);
+    } catch (error) {
+      console.error("failed to clear scale fault: ", error);
+    }
+  }
+
+  const handleResume = async (resume: boolean) => {
+    try {
+      await invoke(resume ? "resume_previous_state" : "discard_resume_offer");
+      setResumeOffer(null);
+    } catch (error) {
+      console.error("failed to answer resume offer: ", error);
+    }
+  }
   
-  const handleToggle = (checked: Boolean) => {
-    setDispenseType(checked ? DispenseType.LargeSmall : DispenseType.Classic);
+  const handleToggle = async (checked: Boolean) => {
+    const dispenseType = checked ? DispenseType.LargeSmall : DispenseType.Classic;
+    try {
+      await invoke("set_dispense_type", { dispenseType });
+      setDispenseType(dispenseType);
+    } catch (error) {
+      console.error("failed to set dispense type: ", error);
+    }
   };
 
+  // Written to a USB drive when one is plugged in, the webview can't download files itself.
+  // USB drives are usually FAT, which doesn't allow colons in names.
+  const exportStamp = () => new Date().toISOString().replace(/[:.]/g, "-");
+  const saveExport = async (fileName: string, contents: string) => {
+    const path = await invoke<string>("save_export", { fileName, contents });
+    setExportMessage(`Saved to ${path}`);
+  }
+
+  const handleDownloadLogs = async () => {
+    try {
+      const logs = await invoke<string>("get_recent_logs", { minutes: 60 });
+      await saveExport(`ichibu-logs-${exportStamp()}.jsonl`, logs);
+    } catch (error) {
+      console.error("failed to download logs: ", error);
+      setExportMessage(`Couldn't save logs: ${error}`);
+    }
+  }
+
+  const handleDownloadCleanings = async () => {
+    try {
+      const report = await invoke<SanitationReport>("get_sanitation_report", { limit: 10000 });
+      const rows = report.history.map((event) =>
+        [event.timestamp, event.user, event.duration_s, event.bowl_count].join(","));
+      const csv = ["timestamp,user,duration_s,bowl_count", ...rows].join("\n");
+      await saveExport(`ichibu-cleanings-${exportStamp()}.csv`, csv);
+    } catch (error) {
+      console.error("failed to download cleaning history: ", error);
+      setExportMessage(`Couldn't save cleaning history: ${error}`);
+    }
+  }
+
   const handleTimeoutReset = async () => {
     try {
       await invoke("clear_dispenser_time_out");
@@ -36,6 +134,27 @@ const SettingsMenu: React.FC<SettingsMenuProps> = ({ currentUser, currentDispens
       console.error("failed to clear dispenser timeout: ", error);
     }
   }
+  const handleExit = async () => {
+    try {
+      await invoke("exit_kiosk");
+    } catch (error) {
+      console.error("failed to exit kiosk: ", error);
+    }
+  }
+  const handleCleaning = async () => {
+    try {
+      setCleaning(await invoke<boolean>("start_cleaning"));
+    } catch (error) {
+      console.error("failed to start cleaning: ", error);
+    }
+  }
+  const handleCalibration = async () => {
+    try {
+      setCalibrating(await invoke<boolean>("start_calibration"));
+    } catch (error) {
+      console.error("failed to start calibration: ", error);
+    }
+  }
   const handleButton = async (state: IchibuState) => {
     try {
       await invoke("update_run_state", { newState: state });
@@ -68,6 +187,58 @@ const SettingsMenu: React.FC<SettingsMenuProps> = ({ currentUser, currentDispens
             if (open) setOpen(true);
           }}
         >
+          {alerts.some((alert) => !alert.acknowledged) && (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <Button
+                className="w-full text-4xl h-32 bg-yellow-500"
+                onClick={() => handleAcknowledge()}
+              >
+                Silence Alerts
+              </Button>
+            </div>
+          )}
+          {scaleFault && (
+            <div onClick={handleItemClick} className="px-2 py-1.5 space-y-2">
+              <div className="text-3xl text-red-400">
+                Scale Fault: {typeof scaleFault === "string" ? scaleFault : Object.keys(scaleFault)[0]}
+              </div>
+              {superVisibility && (
+                <Button
+                  className="w-full text-4xl h-32 bg-yellow-500"
+                  onClick={() => handleClearScaleFault()}
+                >
+                  Clear Scale Fault
+                </Button>
+              )}
+            </div>
+          )}
+          {resumeOffer && (
+            <div onClick={handleItemClick} className="px-2 py-1.5 space-y-2">
+              <Button
+                className="w-full text-4xl h-32 bg-green-600"
+                onClick={() => handleResume(true)}
+              >
+                {resumeOffer.mid_cycle ? "Resume After Power Loss" : "Resume Last Run"}
+              </Button>
+              <Button
+                className="w-full text-4xl h-32 bg-gray-500"
+                onClick={() => handleResume(false)}
+              >
+                Start Fresh
+              </Button>
+            </div>
+          )}
+          {superVisibility && staging && (
+            <div onClick={handleItemClick} className="px-2 py-1.5 text-3xl text-white">
+              <div>Average wait: {staging.average_wait_ms !== null ? `${(staging.average_wait_ms / 1000).toFixed(1)}s` : "-"}</div>
+              <div>Served {staging.served}, discarded {staging.discarded}</div>
+              {staging.staged_for_ms !== null && (
+                <div className={staging.expired ? "text-red-400" : ""}>
+                  Staged for {Math.floor(staging.staged_for_ms / 60000)} min
+                </div>
+              )}
+            </div>
+          )}
           {superVisibility && (
             <div onClick={handleItemClick} className="px-2 py-1.5">
               <div className="flex items-center space-x-2 h-32">
@@ -81,6 +252,36 @@ const SettingsMenu: React.FC<SettingsMenuProps> = ({ currentUser, currentDispens
               </div>
             </div>
           )}
+          {sanitation !== SanitationStatus.Ok && (
+            <div onClick={handleItemClick} className={`px-2 py-1.5 text-3xl ${sanitation === SanitationStatus.Overdue ? "text-red-400" : "text-yellow-300"}`}>
+              {sanitation === SanitationStatus.Overdue ? "Cleaning Overdue" : "Cleaning Due"}
+            </div>
+          )}
+          {superVisibility && (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <Button
+                className="w-full text-4xl h-32 bg-gray-500"
+                onClick={() => handleDownloadLogs()}
+              >
+                Download Last Hour Of Logs
+              </Button>
+            </div>
+          )}
+          {superVisibility && (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <Button
+                className="w-full text-4xl h-32 bg-gray-500"
+                onClick={() => handleDownloadCleanings()}
+              >
+                Download Cleaning History
+              </Button>
+            </div>
+          )}
+          {superVisibility && exportMessage && (
+            <div onClick={handleItemClick} className="px-2 py-1.5 text-2xl text-white break-all">
+              {exportMessage}
+            </div>
+          )}
           <div onClick={handleItemClick} className="px-2 py-1.5">
             <Button 
               className="w-full text-4xl h-32 bg-blue-500"
@@ -89,14 +290,39 @@ const SettingsMenu: React.FC<SettingsMenuProps> = ({ currentUser, currentDispens
               Refill Hopper
             </Button>
           </div>
-          <div onClick={handleItemClick} className="px-2 py-1.5">
-            <Button 
-              className="w-full text-4xl h-32 bg-blue-500"
-              onClick={() => handleButton(IchibuState.Cleaning)}
-            >
-              Clean Mode
-            </Button>
-          </div>
+          {cleaning ? (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <CleaningPanel onDone={() => setCleaning(false)} />
+            </div>
+          ) : (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <Button 
+                className="w-full text-4xl h-32 bg-blue-500"
+                onClick={() => handleCleaning()}
+              >
+                Clean Mode
+              </Button>
+            </div>
+          )}
+          {superVisibility && (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <QuietHoursControl />
+            </div>
+          )}
+          {superVisibility && (calibrating ? (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <CalibrationWizard onDone={() => setCalibrating(false)} />
+            </div>
+          ) : (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <Button
+                className="w-full text-4xl h-32 bg-gray-500"
+                onClick={() => handleCalibration()}
+              >
+                Calibrate Scale
+              </Button>
+            </div>
+          ))}
           <div onClick={handleItemClick} className="px-2 py-1.5">
             <Button 
               className="w-full h-32 text-4xl bg-green-600"
@@ -113,6 +339,16 @@ const SettingsMenu: React.FC<SettingsMenuProps> = ({ currentUser, currentDispens
               Empty Hopper Stop
             </Button>
           </div>
+          {adminVisibility && (
+            <div onClick={handleItemClick} className="px-2 py-1.5">
+              <Button
+                className="w-full h-32 text-4xl bg-destructive"
+                onClick={() => handleExit()}
+              >
+                Exit Kiosk
+              </Button>
+            </div>
+          )}
         </DropdownMenuContent>
       </DropdownMenu>
     </div>
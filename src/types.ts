@@ -22,21 +22,151 @@ export enum User {
 }
 
 export enum DispenseType {
-    Classic,
-    LargeSmall
+    Classic = "Classic",
+    LargeSmall = "LargeSmall",
+    MultiSize = "MultiSize"
 }
 
 
 export enum IchibuState {
     Ready = "Ready",
-    RunningClassic = "RunningClassic",
-    RunningSized = "RunningSized",
+    Running = "Running",
     Cleaning = "Cleaning",
     Emptying = "Emptying",
+    Calibrating = "Calibrating",
 }
 
 export enum UiRequest {
     None = "None",
     SmallDispense = "SmallDispense",
     RegularDispense = "RegularDispense"
-}
\ No newline at end of file
+}
+
+export interface DispenseProgress {
+    target: number
+    weight: number | null
+    progress: number | null
+    elapsed_ms: number
+    complete: boolean
+}
+
+export interface SavedState {
+    snack_id: number | null
+    run_state: IchibuState
+    cycle_dispense_count: number
+    timed_out: boolean
+    mid_cycle: boolean
+}
+
+export enum AlertKind {
+    RunOut = "RunOut",
+    HatchFault = "HatchFault",
+    ScaleFault = "ScaleFault",
+    BowlLeft = "BowlLeft",
+    SanitationOverdue = "SanitationOverdue",
+}
+
+export interface Alert {
+    kind: AlertKind
+    acknowledged: boolean
+}
+
+export interface QuietHours {
+    start: string
+    end: string
+}
+
+export interface AlertSound {
+    kind: AlertKind
+    sound: string | null
+}
+
+export interface PortionSize {
+    name: string
+    target: number
+    price: number | null
+}
+
+// Named sizes are sent as { Size: name }
+export type PortionRequest = UiRequest | { Size: string }
+
+export interface StagingReport {
+    staged_for_ms: number | null
+    expired: boolean
+    average_wait_ms: number | null
+    served: number
+    discarded: number
+}
+
+export interface CleaningStep {
+    name: string
+    done: boolean
+}
+
+export interface CleaningSession {
+    user: string
+    started: string
+    steps: CleaningStep[]
+    pending: "Jog" | "ZeroCheck" | null
+    zero_check: { Ok: number } | { Err: unknown } | null
+}
+
+export enum SanitationStatus {
+    Ok = "Ok",
+    Due = "Due",
+    Overdue = "Overdue",
+}
+
+export interface CleaningEvent {
+    timestamp: string
+    user: string
+    duration_s: number
+    bowl_count: number
+}
+
+export interface SanitationReport {
+    status: SanitationStatus
+    blocking: boolean
+    last_cleaned: string | null
+    bowls_since_cleaned: number
+    history: CleaningEvent[]
+}
+
+export type ScaleFault =
+    | "Disconnected"
+    | "Stalled"
+    | { OutOfRange: number }
+    | { Noisy: number }
+    | { ZeroDrift: number }
+
+export interface CalibrationSample {
+    weight: number
+    readings: number[]
+}
+
+export interface CalibrationSession {
+    tare: number[] | null
+    samples: CalibrationSample[]
+    pending: "Tare" | { AddWeight: number } | null
+    error: string | null
+}
+
+export interface CalibrationFit {
+    coefficients: number[]
+    residual: number
+    errors: number[]
+}
+
+export interface NodeStatus {
+    name: string
+    snack: string | null
+    run_state: IchibuState
+    busy: boolean
+    timed_out: boolean
+    alerts: number
+}
+
+export interface FleetView {
+    nodes: NodeStatus[]
+    selected: string
+}